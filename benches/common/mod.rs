@@ -0,0 +1,61 @@
+// Torrents over generated data and a seeder for them on localhost, shared by the benchmarks.
+// Every benchmark compiles its own copy and not all of them use everything.
+#![allow(dead_code)]
+
+use std::{
+    fs,
+    net::{SocketAddr, TcpListener},
+    path::{Path, PathBuf},
+    thread,
+};
+
+use bit_torrust::{
+    peer::{PeerConnection, PieceData},
+    storage::MemoryStore,
+    torrent::{CreateOptions, Info, Torrent},
+};
+
+// `len` bytes that don't repeat within a piece, in a file under the temporary directory.
+pub fn data_file(name: &str, len: usize) -> (PathBuf, Vec<u8>) {
+    let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+    let path =
+        std::env::temp_dir().join(format!("bit-torrust-bench-{}-{name}", std::process::id()));
+    fs::write(&path, &data).unwrap();
+    (path, data)
+}
+
+pub fn create(path: &Path, piece_length: usize, hash_threads: usize) -> Torrent {
+    let options = CreateOptions {
+        hash_threads,
+        ..CreateOptions::default()
+    };
+    Torrent::create(path, Some(piece_length), None, &options).unwrap()
+}
+
+// Serves `data` to every connection made to the returned address, for as long as the benchmark
+// runs. Everything is leaked, the seeder threads never finish.
+pub fn seed(info: Info, data: &[u8]) -> SocketAddr {
+    let info: &'static Info = Box::leak(Box::new(info));
+    let mut store = MemoryStore::new(info.pieces().len());
+    for (index, piece) in data.chunks(info.piece_length()).enumerate() {
+        store.insert(PieceData {
+            index: index as u32,
+            piece: piece.to_vec(),
+        });
+    }
+    let store: &'static MemoryStore = Box::leak(Box::new(store));
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            thread::spawn(move || {
+                let mut seeder = PeerConnection::accept(stream, &|_| Some(info)).unwrap();
+                seeder.set_store(store);
+                seeder.announce_pieces().unwrap();
+                // Ends once the download hangs up.
+                let _ = seeder.seed();
+            });
+        }
+    });
+    addr
+}
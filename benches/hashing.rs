@@ -0,0 +1,33 @@
+// Hashing the pieces when creating a torrent, on one thread and on one per core. A multi-GB file
+// shows the same ratio, this one is kept small enough to sample repeatedly.
+
+use std::thread;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+mod common;
+
+const PIECE_LENGTH: usize = 1 << 18;
+
+fn hashing(c: &mut Criterion) {
+    let (path, _) = common::data_file("hashing", 256 << 20);
+    let cores = thread::available_parallelism().map_or(1, |threads| threads.get());
+    // Same pieces whatever the thread count, or the comparison means nothing.
+    assert_eq!(
+        common::create(&path, PIECE_LENGTH, 1).info.pieces(),
+        common::create(&path, PIECE_LENGTH, cores).info.pieces()
+    );
+    let mut group = c.benchmark_group("create a torrent of 256 MiB");
+    group.sample_size(10);
+    let counts = if cores > 1 { vec![1, cores] } else { vec![1] };
+    for threads in counts {
+        group.bench_function(format!("{threads} threads"), |b| {
+            b.iter(|| common::create(&path, PIECE_LENGTH, threads))
+        });
+    }
+    group.finish();
+    let _ = std::fs::remove_file(path);
+}
+
+criterion_group!(benches, hashing);
+criterion_main!(benches);
@@ -0,0 +1,32 @@
+// Downloads from a local seeder with different numbers of block requests in flight. One at a time
+// waits a round trip per block, enough requests for a whole piece is the old burst behaviour.
+
+use bit_torrust::{download::Download, peer::Peer};
+use criterion::{criterion_group, criterion_main, Criterion};
+
+mod common;
+
+const PIECE_LENGTH: usize = 1 << 18;
+
+fn pipeline(c: &mut Criterion) {
+    let (path, data) = common::data_file("pipeline", 4 << 20);
+    let info = common::create(&path, PIECE_LENGTH, 0).info;
+    let peer = Peer::from(common::seed(info.clone(), &data));
+    let mut group = c.benchmark_group("download 4 MiB from localhost");
+    group.sample_size(10);
+    // A piece is 16 blocks, so 16 in flight asks for all of it at once.
+    for (name, depth) in [("1 request", 1), ("pipelined, 5", 5), ("burst, 16", 16)] {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let mut download = Download::new(&info, vec![peer.clone()], 1, 0);
+                download.set_pipeline_depth(depth);
+                download.run().unwrap()
+            })
+        });
+    }
+    group.finish();
+    let _ = std::fs::remove_file(path);
+}
+
+criterion_group!(benches, pipeline);
+criterion_main!(benches);
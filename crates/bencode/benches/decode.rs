@@ -0,0 +1,85 @@
+// Decoding the metainfo of a large torrent into owned values and into borrowed ones. The allocation
+// counts are printed once up front, criterion only measures time.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use bencode::{Bencode, BencodeRef};
+use criterion::{criterion_group, criterion_main, Criterion};
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+// Around what a 40 GiB torrent of 2000 files in 1 MiB pieces looks like.
+fn metainfo() -> Vec<u8> {
+    let mut info = b"d5:filesl".to_vec();
+    for i in 0..2000 {
+        info.extend(format!("d6:lengthi{}e4:pathl5:disc{}8:file.binee", 20 << 20, i % 10).bytes());
+    }
+    info.extend(b"e4:name5:large12:piece lengthi1048576e6:pieces");
+    let pieces = 40 * 1024;
+    info.extend(format!("{}:", pieces * 20).bytes());
+    info.extend((0..pieces * 20).map(|i| (i % 251) as u8));
+    info.push(b'e');
+    [
+        b"d8:announce20:http://tracker/annce4:info".to_vec(),
+        info,
+        b"e".to_vec(),
+    ]
+    .concat()
+}
+
+fn owned(encoded: &[u8]) -> Bencode {
+    Bencode::from_bytes(encoded, |path| match path {
+        ["info", "pieces"] => Some(20),
+        _ => None,
+    })
+    .unwrap()
+    .0
+}
+
+fn borrowed(encoded: &[u8]) -> BencodeRef<'_> {
+    BencodeRef::from_bytes(encoded).unwrap().0
+}
+
+fn allocations<T>(decode: impl FnOnce() -> T) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let value = decode();
+    let after = ALLOCATIONS.load(Ordering::Relaxed);
+    drop(value);
+    after - before
+}
+
+fn decode(c: &mut Criterion) {
+    let encoded = metainfo();
+    println!(
+        "Allocations decoding {} bytes: owned {}, borrowed {}",
+        encoded.len(),
+        allocations(|| owned(&encoded)),
+        allocations(|| borrowed(&encoded))
+    );
+    let mut group = c.benchmark_group("decode a large metainfo");
+    group.bench_function("owned", |b| b.iter(|| owned(&encoded)));
+    group.bench_function("borrowed", |b| b.iter(|| borrowed(&encoded)));
+    group.finish();
+}
+
+criterion_group!(benches, decode);
+criterion_main!(benches);
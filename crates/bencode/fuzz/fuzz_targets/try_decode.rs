@@ -0,0 +1,9 @@
+#![no_main]
+
+use bencode::Bencode;
+use libfuzzer_sys::fuzz_target;
+
+// Any input is fine as long as it comes back as Ok or Err, a panic is a bug.
+fuzz_target!(|data: &[u8]| {
+    let _ = Bencode::try_decode(data);
+});
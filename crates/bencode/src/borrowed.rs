@@ -0,0 +1,158 @@
+use indexmap::IndexMap;
+
+use crate::{check_depth, BenError, BenResult, Bencode, BencodeDictValues};
+
+// Borrowed counterpart of Bencode, strings are slices of the input instead of copies. Nothing is
+// split up in byte mode either, `pieces` and the like are one slice to chunk as needed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BencodeRef<'a> {
+    // UTF-8 or not, see `as_str`.
+    Bytes(&'a [u8]),
+    Number(i64),
+    List(Vec<BencodeRef<'a>>),
+    Dict(IndexMap<&'a str, BencodeRef<'a>>),
+}
+
+impl<'a> BencodeRef<'a> {
+    pub fn from_bytes(encoded_value: &'a [u8]) -> BenResult<(Self, &'a [u8])> {
+        Self::decode(encoded_value, false, 0, 0)
+    }
+
+    // Same as from_bytes, but only accepts dictionaries with sorted keys.
+    pub fn from_bytes_strict(encoded_value: &'a [u8]) -> BenResult<(Self, &'a [u8])> {
+        Self::decode(encoded_value, true, 0, 0)
+    }
+
+    pub fn as_bytes(&self) -> Option<&'a [u8]> {
+        match self {
+            BencodeRef::Bytes(bytez) => Some(bytez),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&'a str> {
+        self.as_bytes()
+            .and_then(|bytez| std::str::from_utf8(bytez).ok())
+    }
+
+    pub fn as_number(&self) -> Option<i64> {
+        match self {
+            BencodeRef::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&BencodeRef<'a>> {
+        match self {
+            BencodeRef::Dict(dict) => dict.get(key),
+            _ => None,
+        }
+    }
+
+    // Copies everything into an owned Bencode, strings the same way Bencode::from_bytes would.
+    pub fn to_owned_bencode(&self) -> Bencode {
+        match self {
+            BencodeRef::Bytes(bytez) => match std::str::from_utf8(bytez) {
+                Ok(string) => Bencode::String(string.to_string()),
+                Err(_) => Bencode::ByteString(bytez.to_vec()),
+            },
+            BencodeRef::Number(n) => Bencode::Number(*n),
+            BencodeRef::List(list) => {
+                Bencode::List(list.iter().map(Self::to_owned_bencode).collect())
+            }
+            BencodeRef::Dict(dict) => Bencode::Dict(
+                dict.iter()
+                    .map(|(key, value)| {
+                        (
+                            key.to_string(),
+                            BencodeDictValues::Bencode(value.to_owned_bencode()),
+                        )
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    // `offset` is where `encoded_value` starts in the whole input, it is only used for errors.
+    // `depth` counts the lists and dictionaries we are in.
+    fn decode(
+        encoded_value: &'a [u8],
+        strict: bool,
+        offset: usize,
+        depth: usize,
+    ) -> BenResult<(Self, &'a [u8])> {
+        if encoded_value.is_empty() {
+            return Err(Box::new(BenError::UnexpectedTruncationError));
+        }
+        check_depth(encoded_value[0], depth, offset)?;
+        match encoded_value[0] as char {
+            x if x.is_ascii_digit() => {
+                let (bytez, rem) = Bencode::bendecode_raw(encoded_value, offset)?;
+                Ok((BencodeRef::Bytes(bytez), rem))
+            }
+            'i' => {
+                let (number, rem) = Bencode::bendecode_number(&encoded_value[1..], offset + 1)?;
+                Ok((BencodeRef::Number(number), rem))
+            }
+            'l' => Self::decode_l(&encoded_value[1..], strict, offset + 1, depth + 1),
+            'd' => Self::decode_d(&encoded_value[1..], strict, offset + 1, depth + 1),
+            'e' => Err(Box::new(BenError::MisplacedClosingError { offset })),
+            x => Err(Box::new(BenError::UnexpectedToken {
+                token: x as u8,
+                offset,
+            })),
+        }
+    }
+
+    fn decode_l(
+        encoded_value: &'a [u8],
+        strict: bool,
+        offset: usize,
+        depth: usize,
+    ) -> BenResult<(Self, &'a [u8])> {
+        let mut list = Vec::new();
+        let mut rem = encoded_value;
+        while !rem.is_empty() && rem[0] != b'e' {
+            let position = offset + encoded_value.len() - rem.len();
+            let (val, returned) = Self::decode(rem, strict, position, depth)?;
+            list.push(val);
+            rem = returned;
+        }
+        // Ran out of bytes before the closing 'e'
+        if rem.is_empty() {
+            return Err(Box::new(BenError::UnexpectedTruncationError));
+        }
+        Ok((BencodeRef::List(list), &rem[1..]))
+    }
+
+    fn decode_d(
+        encoded_value: &'a [u8],
+        strict: bool,
+        offset: usize,
+        depth: usize,
+    ) -> BenResult<(Self, &'a [u8])> {
+        let mut dict = IndexMap::new();
+        let mut rem = encoded_value;
+        while !rem.is_empty() && rem[0] != b'e' {
+            let key_offset = offset + encoded_value.len() - rem.len();
+            let (key, returned) = Bencode::bendecode_raw(rem, key_offset)?;
+            // Keys are looked up by name, so they still have to be valid UTF-8.
+            let key = std::str::from_utf8(key)?;
+            if strict && dict.last().is_some_and(|(last, _)| *last >= key) {
+                return Err(Box::new(BenError::UnsortedKeys {
+                    key: key.to_string(),
+                    offset: key_offset,
+                }));
+            }
+            let value_offset = offset + encoded_value.len() - returned.len();
+            let (val, returned) = Self::decode(returned, strict, value_offset, depth)?;
+            dict.insert(key, val);
+            rem = returned;
+        }
+        // Ran out of bytes before the closing 'e'
+        if rem.is_empty() {
+            return Err(Box::new(BenError::UnexpectedTruncationError));
+        }
+        Ok((BencodeRef::Dict(dict), &rem[1..]))
+    }
+}
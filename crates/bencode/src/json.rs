@@ -0,0 +1,160 @@
+use serde_json::{Map, Value};
+
+use crate::{BenError, BenResult, Bencode, BencodeDictValues};
+
+// Byte strings that aren't UTF-8 become `{"$hex": "..."}`. A real dictionary that would read as
+// one of these tags gets wrapped in `{"$dict": {...}}`, so the way back is never ambiguous.
+const HEX_TAG: &str = "$hex";
+const DICT_TAG: &str = "$dict";
+
+impl Bencode {
+    // Dictionary order is kept as-is, so converting back gives the exact same bytes, and the same
+    // info hash for torrents.
+    pub fn to_json(&self) -> Value {
+        match self {
+            Bencode::String(s) => Value::String(s.clone()),
+            Bencode::ByteString(bytez) => hex_value(bytez),
+            Bencode::Number(i) => Value::from(*i),
+            Bencode::List(list) => Value::Array(list.iter().map(Self::to_json).collect()),
+            Bencode::Dict(dict) => {
+                let object: Map<String, Value> = dict
+                    .iter()
+                    .map(|(key, value)| {
+                        let value = match value {
+                            BencodeDictValues::Bencode(bencode) => bencode.to_json(),
+                            BencodeDictValues::Bytes(chunks) => hex_value(&chunks.concat()),
+                        };
+                        (key.clone(), value)
+                    })
+                    .collect();
+                if tagged(&object).is_some() {
+                    let mut wrapper = Map::new();
+                    wrapper.insert(DICT_TAG.to_string(), Value::Object(object));
+                    Value::Object(wrapper)
+                } else {
+                    Value::Object(object)
+                }
+            }
+        }
+    }
+
+    pub fn from_json(value: &Value) -> BenResult<Self> {
+        match value {
+            Value::String(s) => Ok(Bencode::String(s.clone())),
+            Value::Number(n) => n
+                .as_i64()
+                .map(Bencode::Number)
+                .ok_or_else(|| Box::new(BenError::UnsupportedJson(n.to_string())) as _),
+            Value::Array(array) => Ok(Bencode::List(
+                array
+                    .iter()
+                    .map(Self::from_json)
+                    .collect::<BenResult<_>>()?,
+            )),
+            Value::Object(object) => match tagged(object) {
+                Some((HEX_TAG, hex)) => Ok(Bencode::ByteString(decode_hex(hex)?)),
+                Some((_, Value::Object(inner))) => Self::from_json_object(inner),
+                _ => Self::from_json_object(object),
+            },
+            other => Err(Box::new(BenError::UnsupportedJson(other.to_string()))),
+        }
+    }
+
+    fn from_json_object(object: &Map<String, Value>) -> BenResult<Self> {
+        let dict = object
+            .iter()
+            .map(|(key, value)| {
+                Ok((
+                    key.clone(),
+                    BencodeDictValues::Bencode(Self::from_json(value)?),
+                ))
+            })
+            .collect::<BenResult<_>>()?;
+        Ok(Bencode::Dict(dict))
+    }
+}
+
+// The tag and its value, if the object is one of our single key tags.
+fn tagged(object: &Map<String, Value>) -> Option<(&'static str, &Value)> {
+    if object.len() != 1 {
+        return None;
+    }
+    match object.iter().next()? {
+        (key, value @ Value::String(_)) if key == HEX_TAG => Some((HEX_TAG, value)),
+        (key, value @ Value::Object(_)) if key == DICT_TAG => Some((DICT_TAG, value)),
+        _ => None,
+    }
+}
+
+fn hex_value(bytez: &[u8]) -> Value {
+    let hex: String = bytez.iter().map(|byte| format!("{:02x}", byte)).collect();
+    let mut object = Map::new();
+    object.insert(HEX_TAG.to_string(), Value::String(hex));
+    Value::Object(object)
+}
+
+fn decode_hex(hex: &Value) -> BenResult<Vec<u8>> {
+    let invalid = || Box::new(BenError::UnsupportedJson(hex.to_string()));
+    let hex = hex.as_str().ok_or_else(invalid)?;
+    if !hex.len().is_multiple_of(2) {
+        return Err(invalid());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| invalid() as _)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn torrents_round_trip_byte_for_byte() {
+        let encoded = b"d8:announce9:127.0.0.14:infod6:lengthi3e4:name1:a12:piece lengthi16e\
+6:pieces20:\x00\x01\x02\x03\x04\x05\x06\x07\x08\x09\x0a\x0b\x0c\x0d\x0e\x0f\xff\xfe\xfd\xfc\
+1:z4:\x00\xff\x00\xffee";
+        let info_bytes = |path: &[&str]| match path {
+            ["info", "pieces"] => Some(20),
+            _ => None,
+        };
+        let (value, _) = Bencode::from_bytes(encoded, info_bytes).unwrap();
+        let json = value.to_json();
+        assert_eq!(json["info"]["z"]["$hex"], "00ff00ff");
+        let back = Bencode::from_json(&json).unwrap();
+        assert_eq!(back.to_bytes().unwrap(), encoded);
+    }
+
+    #[test]
+    fn dictionaries_that_look_like_tags_are_kept() {
+        let (value, _) = Bencode::from_bytes(b"d4:$hex2:abe", |_| None).unwrap();
+        let json = value.to_json();
+        assert!(json.get(DICT_TAG).is_some());
+        assert_eq!(Bencode::from_json(&json).unwrap(), value);
+    }
+
+    #[test]
+    fn json_without_a_bencode_equivalent_is_refused() {
+        for json in [
+            "1.5",
+            "true",
+            "null",
+            r#"{"$hex": "abc"}"#,
+            r#"{"$hex": "zz"}"#,
+        ] {
+            let error = Bencode::from_json(&serde_json::from_str(json).unwrap()).unwrap_err();
+            assert!(
+                matches!(
+                    error.downcast_ref::<BenError>(),
+                    Some(BenError::UnsupportedJson(_))
+                ),
+                "{}",
+                json
+            );
+        }
+    }
+}
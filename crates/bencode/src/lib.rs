@@ -1,11 +1,27 @@
-use std::error::Error;
+use std::{error::Error, io::Read};
 
-use indexmap::IndexMap;
+pub use borrowed::BencodeRef;
+pub use indexmap::IndexMap;
+
+mod borrowed;
+#[cfg(feature = "json")]
+mod json;
 
 pub type BenResult<T> = Result<T, Box<dyn Error>>;
+
+// Lists and dictionaries nested deeper than this are refused, every level is a recursive call
+// and hostile input could otherwise run us out of stack.
+pub const MAX_DEPTH: usize = 100;
+
+// Longest integer or string length the reader gathers, enough for any i64 with its sign and any
+// u64 length. Past it the stream can't be valid, so we stop instead of reading digits forever.
+const MAX_DIGITS: usize = 20;
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum Bencode {
     String(String),
+    // Byte strings that are not valid UTF-8, kept as-is so they survive a round trip.
+    ByteString(Vec<u8>),
     Number(i64),
     List(Vec<Bencode>),
     Dict(IndexMap<String, BencodeDictValues>),
@@ -15,6 +31,11 @@ impl Bencode {
     pub fn to_bytes(&self) -> BenResult<Vec<u8>> {
         match self {
             Bencode::String(s) => Ok(format!("{}:{}", s.len(), s).as_bytes().to_owned()),
+            Bencode::ByteString(bytez) => {
+                let mut res = format!("{}:", bytez.len()).as_bytes().to_owned();
+                res.extend(bytez);
+                Ok(res)
+            }
             Bencode::Number(i) => Ok(format!("i{}e", i).as_bytes().to_owned()),
             Bencode::List(ben_vec) => {
                 let mut res: Vec<_> = vec![b'l'];
@@ -51,69 +72,274 @@ impl Bencode {
         }
     }
 
+    // `byte_mode_key` is given the keys leading to each dictionary value, from the outermost
+    // dictionary in (lists don't count), and picks which string values are split into chunks of
+    // raw bytes, e.g. `["info", "pieces"] => Some(20)`. Closures can capture whatever decides it.
+    // `Some(0)` is the same as `None`, there is no splitting into empty chunks.
     pub fn from_bytes(
         encoded_value: &[u8],
-        byte_mode_key: fn(&str) -> Option<usize>,
+        byte_mode_key: impl Fn(&[&str]) -> Option<usize>,
     ) -> BenResult<(Self, &[u8])> {
+        Self::decode(encoded_value, &byte_mode_key, false, 0, 0, &mut Vec::new())
+    }
+
+    // Same as from_bytes, but only accepts dictionaries with sorted keys, as canonical
+    // bencode requires.
+    pub fn from_bytes_strict(
+        encoded_value: &[u8],
+        byte_mode_key: impl Fn(&[&str]) -> Option<usize>,
+    ) -> BenResult<(Self, &[u8])> {
+        Self::decode(encoded_value, &byte_mode_key, true, 0, 0, &mut Vec::new())
+    }
+
+    // The safe way in for untrusted input: fails instead of panicking whatever the bytes are,
+    // and the value has to take up all of them. Strings come back whole, there is no byte mode.
+    pub fn try_decode(encoded_value: &[u8]) -> BenResult<Self> {
+        let (value, rest) = Self::from_bytes(encoded_value, |_| None)?;
+        if !rest.is_empty() {
+            return Err(Box::new(BenError::TrailingData {
+                offset: encoded_value.len() - rest.len(),
+            }));
+        }
+        Ok(value)
+    }
+
+    // Decodes one value straight off a reader, without buffering the rest of the input. Nothing
+    // past the end of the value is consumed, so the reader can carry on with whatever follows.
+    // Reading a byte at a time, wrap unbuffered sources like sockets in a BufReader.
+    pub fn from_reader<R: Read>(
+        reader: &mut R,
+        byte_mode_key: impl Fn(&[&str]) -> Option<usize>,
+    ) -> BenResult<Self> {
+        let mut reader = OffsetReader { reader, offset: 0 };
+        let first = reader.read_byte()?;
+        Self::decode_reader(&mut reader, first, &byte_mode_key, 0, &mut Vec::new())
+    }
+
+    // `depth` counts the lists and dictionaries we are in and `path` holds the keys of the
+    // dictionaries, see from_bytes.
+    fn decode_reader<R: Read>(
+        reader: &mut OffsetReader<R>,
+        first: u8,
+        byte_mode_key: &dyn Fn(&[&str]) -> Option<usize>,
+        depth: usize,
+        path: &mut Vec<String>,
+    ) -> BenResult<Self> {
+        // Where `first` was read from.
+        let offset = reader.offset - 1;
+        check_depth(first, depth, offset)?;
+        match first as char {
+            x if x.is_ascii_digit() => {
+                let bytez = reader.read_raw(first)?;
+                match String::from_utf8(bytez) {
+                    Ok(string) => Ok(Bencode::String(string)),
+                    Err(e) => Ok(Bencode::ByteString(e.into_bytes())),
+                }
+            }
+            'i' => {
+                // Integers are short, gather them up and let the slice decoder validate them.
+                let mut i_bytes = Vec::new();
+                loop {
+                    let byte = reader.read_byte()?;
+                    i_bytes.push(byte);
+                    if byte == b'e' {
+                        break;
+                    }
+                    if i_bytes.len() > MAX_DIGITS {
+                        return Err(Box::new(BenError::InvalidInteger {
+                            value: i_bytes,
+                            offset: offset + 1,
+                        }));
+                    }
+                }
+                Ok(Self::bendecode_i(&i_bytes, offset + 1)?.0)
+            }
+            'l' => {
+                let mut list = Vec::new();
+                loop {
+                    let next = reader.read_byte()?;
+                    if next == b'e' {
+                        break;
+                    }
+                    list.push(Self::decode_reader(
+                        reader,
+                        next,
+                        byte_mode_key,
+                        depth + 1,
+                        path,
+                    )?);
+                }
+                Ok(Bencode::List(list))
+            }
+            'd' => {
+                let mut dict = IndexMap::new();
+                loop {
+                    let next = reader.read_byte()?;
+                    if next == b'e' {
+                        break;
+                    }
+                    let key = String::from_utf8(reader.read_raw(next)?)?;
+                    let next = reader.read_byte()?;
+                    path.push(key);
+                    let keys: Vec<&str> = path.iter().map(String::as_str).collect();
+                    let chunk_size = byte_mode_key(&keys).filter(|&size| size > 0);
+                    let value = match chunk_size.filter(|_| next.is_ascii_digit()) {
+                        None => {
+                            let val =
+                                Self::decode_reader(reader, next, byte_mode_key, depth + 1, path)?;
+                            BencodeDictValues::Bencode(val)
+                        }
+                        Some(chunk_size) => {
+                            let bytes = reader.read_raw(next)?;
+                            // Length should be a multiple of chunk_size!
+                            if bytes.len() % chunk_size != 0 {
+                                return Err(Box::new(BenError::UnexpectedTruncationError));
+                            }
+                            let chunks = bytes.chunks(chunk_size).map(|x| x.to_vec()).collect();
+                            BencodeDictValues::Bytes(chunks)
+                        }
+                    };
+                    let key = path.pop().expect("Pushed above");
+                    dict.insert(key, value);
+                }
+                Ok(Bencode::Dict(dict))
+            }
+            'e' => Err(Box::new(BenError::MisplacedClosingError { offset })),
+            x => Err(Box::new(BenError::UnexpectedToken {
+                token: x as u8,
+                offset,
+            })),
+        }
+    }
+
+    // `offset` is where `encoded_value` starts in the whole input, it is only used for errors.
+    // `depth` counts the lists and dictionaries we are in and `path` holds the keys of the
+    // dictionaries, see from_bytes.
+    fn decode<'a>(
+        encoded_value: &'a [u8],
+        byte_mode_key: &dyn Fn(&[&str]) -> Option<usize>,
+        strict: bool,
+        offset: usize,
+        depth: usize,
+        path: &mut Vec<&'a str>,
+    ) -> BenResult<(Self, &'a [u8])> {
+        if encoded_value.is_empty() {
+            return Err(Box::new(BenError::UnexpectedTruncationError));
+        }
+        check_depth(encoded_value[0], depth, offset)?;
+        let rest = &encoded_value[1..];
         match encoded_value[0] as char {
-            x if x.is_ascii_digit() => Self::bendecode_s(encoded_value),
-            'i' => Self::bendecode_i(&encoded_value[1..]),
-            'l' => Self::bendecode_l(&encoded_value[1..], byte_mode_key),
-            'd' => Self::bendecode_d(&encoded_value[1..], byte_mode_key),
-            'e' => Err(Box::new(BenError::MisplacedClosingError)),
-            x => Err(Box::new(BenError::UnexpectedToken { token: x as u8 })),
+            x if x.is_ascii_digit() => Self::bendecode_s(encoded_value, offset),
+            'i' => Self::bendecode_i(rest, offset + 1),
+            'l' => Self::bendecode_l(rest, byte_mode_key, strict, offset + 1, depth + 1, path),
+            'd' => Self::bendecode_d(rest, byte_mode_key, strict, offset + 1, depth + 1, path),
+            'e' => Err(Box::new(BenError::MisplacedClosingError { offset })),
+            x => Err(Box::new(BenError::UnexpectedToken {
+                token: x as u8,
+                offset,
+            })),
         }
     }
 
-    fn bendecode_s(encoded_value: &[u8]) -> BenResult<(Bencode, &[u8])> {
+    fn bendecode_s(encoded_value: &[u8], offset: usize) -> BenResult<(Bencode, &[u8])> {
+        let (bytez, rem) = Self::bendecode_raw(encoded_value, offset)?;
+        // Only fall back to raw bytes when the string is not valid UTF-8.
+        match std::str::from_utf8(bytez) {
+            Ok(string) => Ok((Bencode::String(string.to_string()), rem)),
+            Err(_) => Ok((Bencode::ByteString(bytez.to_vec()), rem)),
+        }
+    }
+
+    fn bendecode_raw(encoded_value: &[u8], offset: usize) -> BenResult<(&[u8], &[u8])> {
         // Iterate through and find the character that matches ':'
-        let colon_index = encoded_value
-            .iter()
-            .position(|&x| x == b':')
-            .ok_or(BenError::MissingToken { token: b':' })?;
+        let colon_index =
+            encoded_value
+                .iter()
+                .position(|&x| x == b':')
+                .ok_or(BenError::MissingToken {
+                    token: b':',
+                    offset,
+                })?;
         let length_string = std::str::from_utf8(&encoded_value[..colon_index])?;
         let length = length_string.parse::<usize>()?;
-        let string =
-            std::str::from_utf8(&encoded_value[colon_index + 1..colon_index + 1 + length])?;
-        Ok((
-            Bencode::String(string.to_string()),
-            &encoded_value[colon_index + 1 + length..],
-        ))
+        let end = Self::string_end(encoded_value, colon_index, length)?;
+        Ok((&encoded_value[colon_index + 1..end], &encoded_value[end..]))
     }
 
-    fn bendecode_i(encoded_value: &[u8]) -> BenResult<(Bencode, &[u8])> {
-        let ending_index = encoded_value
-            .iter()
-            .position(|&x| x == b'e')
-            .ok_or(BenError::MissingToken { token: b'e' })?;
-        let i_string = std::str::from_utf8(&encoded_value[..ending_index])?;
-        let number = i_string.parse::<i64>().unwrap();
-        Ok((Bencode::Number(number), &encoded_value[ending_index + 1..]))
+    fn bendecode_i(encoded_value: &[u8], offset: usize) -> BenResult<(Bencode, &[u8])> {
+        let (number, rem) = Self::bendecode_number(encoded_value, offset)?;
+        Ok((Bencode::Number(number), rem))
     }
 
-    fn bendecode_l(
-        encoded_value: &[u8],
-        byte_mode_key: fn(&str) -> Option<usize>,
-    ) -> BenResult<(Bencode, &[u8])> {
+    fn bendecode_number(encoded_value: &[u8], offset: usize) -> BenResult<(i64, &[u8])> {
+        let ending_index =
+            encoded_value
+                .iter()
+                .position(|&x| x == b'e')
+                .ok_or(BenError::MissingToken {
+                    token: b'e',
+                    offset,
+                })?;
+        let i_bytes = &encoded_value[..ending_index];
+        let invalid = || BenError::InvalidInteger {
+            value: i_bytes.to_vec(),
+            offset,
+        };
+        let digits = i_bytes.strip_prefix(b"-").unwrap_or(i_bytes);
+        // Spec forbids empty digits, leading zeroes and negative zero.
+        if digits.is_empty()
+            || !digits.iter().all(|x| x.is_ascii_digit())
+            || (digits[0] == b'0' && (digits.len() > 1 || digits.len() != i_bytes.len()))
+        {
+            return Err(Box::new(invalid()));
+        }
+        // Well formed by now, so the only way parsing can fail is a number past i64.
+        let number = std::str::from_utf8(i_bytes)?.parse::<i64>().map_err(|_| {
+            BenError::IntegerOverflow {
+                value: i_bytes.to_vec(),
+                offset,
+            }
+        })?;
+        Ok((number, &encoded_value[ending_index + 1..]))
+    }
+
+    fn bendecode_l<'a>(
+        encoded_value: &'a [u8],
+        byte_mode_key: &dyn Fn(&[&str]) -> Option<usize>,
+        strict: bool,
+        offset: usize,
+        depth: usize,
+        path: &mut Vec<&'a str>,
+    ) -> BenResult<(Bencode, &'a [u8])> {
         let mut list = Vec::new();
         let mut rem = encoded_value;
         while !rem.is_empty() && rem[0] != b'e' {
-            let (val, returned) = Bencode::from_bytes(rem, byte_mode_key)?;
+            let position = offset + encoded_value.len() - rem.len();
+            let (val, returned) = Self::decode(rem, byte_mode_key, strict, position, depth, path)?;
             list.push(val);
             rem = returned;
         }
+        // Ran out of bytes before the closing 'e'
+        if rem.is_empty() {
+            return Err(Box::new(BenError::UnexpectedTruncationError));
+        }
         Ok((Bencode::List(list), &rem[1..]))
     }
 
     fn bendecode_bytez(
         encoded_value: &[u8],
         chunk_size: usize,
+        offset: usize,
     ) -> BenResult<(Vec<Vec<u8>>, &[u8])> {
         // Iterate through and find the character that matches ':'
-        let colon_index = encoded_value
-            .iter()
-            .position(|&x| x == b':')
-            .ok_or(BenError::MissingToken { token: b':' })?;
+        let colon_index =
+            encoded_value
+                .iter()
+                .position(|&x| x == b':')
+                .ok_or(BenError::MissingToken {
+                    token: b':',
+                    offset,
+                })?;
         let length_string = std::str::from_utf8(&encoded_value[..colon_index])?;
         let length = length_string.parse::<usize>()?;
         // Length should be a multiple of chunk_size!
@@ -121,45 +347,268 @@ impl Bencode {
             return Err(Box::new(BenError::UnexpectedTruncationError));
         }
 
-        let bytes = &encoded_value[colon_index + 1..colon_index + 1 + length];
+        let end = Self::string_end(encoded_value, colon_index, length)?;
+        let bytes = &encoded_value[colon_index + 1..end];
         Ok((
             bytes
                 .chunks(chunk_size)
                 .map(|x| x.to_vec())
                 .collect::<Vec<Vec<u8>>>(),
-            &encoded_value[colon_index + 1 + length..],
+            &encoded_value[end..],
         ))
     }
 
-    fn bendecode_d(
-        encoded_value: &[u8],
-        byte_mode_key: fn(&str) -> Option<usize>,
-    ) -> BenResult<(Bencode, &[u8])> {
+    // Where a string of `length` bytes after the colon ends, as long as the input has that many.
+    fn string_end(encoded_value: &[u8], colon_index: usize, length: usize) -> BenResult<usize> {
+        (colon_index + 1)
+            .checked_add(length)
+            .filter(|&end| end <= encoded_value.len())
+            .ok_or_else(|| Box::new(BenError::UnexpectedTruncationError) as Box<dyn Error>)
+    }
+
+    fn bendecode_d<'a>(
+        encoded_value: &'a [u8],
+        byte_mode_key: &dyn Fn(&[&str]) -> Option<usize>,
+        strict: bool,
+        offset: usize,
+        depth: usize,
+        path: &mut Vec<&'a str>,
+    ) -> BenResult<(Bencode, &'a [u8])> {
         // We know that they must be strings
-        let mut dict = IndexMap::new();
+        let mut dict: IndexMap<String, BencodeDictValues> = IndexMap::new();
         let mut rem = encoded_value;
         while !rem.is_empty() && rem[0] != b'e' {
-            let (key, returned) = Self::bendecode_s(rem)?;
-            // NOTE: This is impossible to fail if the above did not return
-            if let Bencode::String(s) = key {
-                match byte_mode_key(&s) {
-                    None => {
-                        let (val, returned) = Bencode::from_bytes(returned, byte_mode_key)?;
-                        dict.insert(s, BencodeDictValues::Bencode(val));
-                        rem = returned;
-                    }
-                    Some(chunk_size) => {
-                        let (val, returned) = Self::bendecode_bytez(returned, chunk_size)?;
-                        dict.insert(s, BencodeDictValues::Bytes(val));
-                        rem = returned;
-                    }
+            let key_offset = offset + encoded_value.len() - rem.len();
+            let (key, returned) = Self::bendecode_raw(rem, key_offset)?;
+            // Keys are looked up by name, so they still have to be valid UTF-8.
+            let key = std::str::from_utf8(key)?;
+            if strict && dict.last().is_some_and(|(last, _)| last.as_str() >= key) {
+                return Err(Box::new(BenError::UnsortedKeys {
+                    key: key.to_string(),
+                    offset: key_offset,
+                }));
+            }
+            let value_offset = offset + encoded_value.len() - returned.len();
+            path.push(key);
+            // Byte mode only applies to string values, anything else is decoded as usual.
+            let chunk_size = byte_mode_key(path)
+                .filter(|&size| size > 0)
+                .filter(|_| returned.first().is_some_and(u8::is_ascii_digit));
+            match chunk_size {
+                None => {
+                    let (val, returned) =
+                        Self::decode(returned, byte_mode_key, strict, value_offset, depth, path)?;
+                    dict.insert(key.to_string(), BencodeDictValues::Bencode(val));
+                    rem = returned;
+                }
+                Some(chunk_size) => {
+                    let (val, returned) =
+                        Self::bendecode_bytez(returned, chunk_size, value_offset)?;
+                    dict.insert(key.to_string(), BencodeDictValues::Bytes(val));
+                    rem = returned;
                 }
             }
+            path.pop();
+        }
+        // Ran out of bytes before the closing 'e'
+        if rem.is_empty() {
+            return Err(Box::new(BenError::UnexpectedTruncationError));
         }
         Ok((Bencode::Dict(dict), &rem[1..]))
     }
 }
 
+// Fails when `token` starts a list or dictionary `depth` levels in and that is one too many.
+pub(crate) fn check_depth(token: u8, depth: usize, offset: usize) -> Result<(), BenError> {
+    if matches!(token, b'l' | b'd') && depth >= MAX_DEPTH {
+        return Err(BenError::MaxDepthExceeded { offset });
+    }
+    Ok(())
+}
+
+impl Bencode {
+    // Indented, JSON-like view for people to read. Strings print as text when they are printable
+    // and as hex otherwise, byte mode values print as a list of hex chunks.
+    pub fn to_pretty_string(&self) -> String {
+        let mut out = String::new();
+        self.write_pretty(&mut out, 0);
+        out
+    }
+
+    fn write_pretty(&self, out: &mut String, depth: usize) {
+        match self {
+            Bencode::String(s) if !s.chars().any(char::is_control) => {
+                out.push_str(&format!("{:?}", s))
+            }
+            Bencode::String(s) => out.push_str(&pretty_hex(s.as_bytes())),
+            Bencode::ByteString(bytez) => out.push_str(&pretty_hex(bytez)),
+            Bencode::Number(i) => out.push_str(&i.to_string()),
+            Bencode::List(list) => write_pretty_items(out, depth, '[', ']', list, |value, out| {
+                value.write_pretty(out, depth + 1)
+            }),
+            Bencode::Dict(dict) => {
+                write_pretty_items(out, depth, '{', '}', dict, |(key, value), out| {
+                    out.push_str(&format!("{:?}: ", key));
+                    match value {
+                        BencodeDictValues::Bencode(bencode) => bencode.write_pretty(out, depth + 1),
+                        BencodeDictValues::Bytes(chunks) => {
+                            write_pretty_items(out, depth + 1, '[', ']', chunks, |chunk, out| {
+                                out.push_str(&pretty_hex(chunk))
+                            })
+                        }
+                    }
+                })
+            }
+        }
+    }
+}
+
+fn pretty_hex(bytez: &[u8]) -> String {
+    let hex: String = bytez.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("<hex {}>", hex)
+}
+
+// One item per line, indented a level deeper than the brackets around them.
+fn write_pretty_items<T>(
+    out: &mut String,
+    depth: usize,
+    open: char,
+    close: char,
+    items: impl IntoIterator<Item = T>,
+    write_item: impl Fn(T, &mut String),
+) {
+    out.push(open);
+    let mut empty = true;
+    for item in items {
+        out.push_str(if empty { "\n" } else { ",\n" });
+        out.push_str(&"  ".repeat(depth + 1));
+        write_item(item, out);
+        empty = false;
+    }
+    if !empty {
+        out.push('\n');
+        out.push_str(&"  ".repeat(depth));
+    }
+    out.push(close);
+}
+
+// Keeps count of how far into the stream we are, for errors.
+struct OffsetReader<'r, R> {
+    reader: &'r mut R,
+    offset: usize,
+}
+
+impl<R: Read> OffsetReader<'_, R> {
+    fn read_byte(&mut self) -> BenResult<u8> {
+        let mut byte = [0u8];
+        match self.reader.read_exact(&mut byte) {
+            Ok(()) => {
+                self.offset += 1;
+                Ok(byte[0])
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                Err(Box::new(BenError::UnexpectedTruncationError))
+            }
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    // Reads `<length>:<bytes>` given the first digit of the length.
+    fn read_raw(&mut self, first: u8) -> BenResult<Vec<u8>> {
+        let mut length_bytes = vec![first];
+        let offset = self.offset - 1;
+        loop {
+            if length_bytes.len() > MAX_DIGITS {
+                return Err(Box::new(BenError::InvalidInteger {
+                    value: length_bytes,
+                    offset,
+                }));
+            }
+            match self.read_byte()? {
+                b':' => break,
+                x if x.is_ascii_digit() => length_bytes.push(x),
+                x => {
+                    return Err(Box::new(BenError::UnexpectedToken {
+                        token: x,
+                        offset: self.offset - 1,
+                    }))
+                }
+            }
+        }
+        let length = std::str::from_utf8(&length_bytes)?.parse::<usize>()?;
+        // Don't trust the length for the allocation, the stream may well end sooner.
+        let mut bytez = Vec::new();
+        (&mut *self.reader)
+            .take(length as u64)
+            .read_to_end(&mut bytez)?;
+        self.offset += bytez.len();
+        if bytez.len() != length {
+            return Err(Box::new(BenError::UnexpectedTruncationError));
+        }
+        Ok(bytez)
+    }
+}
+
+// Builds a dictionary from `key => value` pairs, any value with a From impl works.
+// Keys end up sorted, as canonical bencode requires.
+#[macro_export]
+macro_rules! dict {
+    ($($key:expr => $value:expr),* $(,)?) => {
+        $crate::Bencode::dict([$((String::from($key), $crate::Bencode::from($value))),*])
+    };
+}
+
+impl Bencode {
+    // Dictionary with its keys sorted, later duplicates win.
+    pub fn dict(entries: impl IntoIterator<Item = (String, Bencode)>) -> Self {
+        let mut entries: Vec<_> = entries.into_iter().collect();
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Bencode::Dict(
+            entries
+                .into_iter()
+                .map(|(key, value)| (key, BencodeDictValues::Bencode(value)))
+                .collect(),
+        )
+    }
+}
+
+impl From<i64> for Bencode {
+    fn from(value: i64) -> Self {
+        Bencode::Number(value)
+    }
+}
+
+impl From<&str> for Bencode {
+    fn from(value: &str) -> Self {
+        Bencode::String(value.to_string())
+    }
+}
+
+impl From<String> for Bencode {
+    fn from(value: String) -> Self {
+        Bencode::String(value)
+    }
+}
+
+// Raw bytes always encode as-is, whether they happen to be UTF-8 or not.
+impl From<Vec<u8>> for Bencode {
+    fn from(value: Vec<u8>) -> Self {
+        Bencode::ByteString(value)
+    }
+}
+
+impl From<&[u8]> for Bencode {
+    fn from(value: &[u8]) -> Self {
+        Bencode::ByteString(value.to_vec())
+    }
+}
+
+impl From<Vec<Bencode>> for Bencode {
+    fn from(value: Vec<Bencode>) -> Self {
+        Bencode::List(value)
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum BencodeDictValues {
     Bencode(Bencode),
@@ -167,28 +616,340 @@ pub enum BencodeDictValues {
     Bytes(Vec<Vec<u8>>),
 }
 
+// Offsets are in bytes from the start of the input.
 #[derive(Debug)]
 pub enum BenError {
-    MisplacedClosingError,
+    MisplacedClosingError { offset: usize },
     UnexpectedTruncationError,
-    UnexpectedToken { token: u8 },
-    MissingToken { token: u8 },
+    UnexpectedToken { token: u8, offset: usize },
+    MissingToken { token: u8, offset: usize },
+    InvalidInteger { value: Vec<u8>, offset: usize },
+    // Valid bencode, which has no limit on integers, but too big for an i64.
+    IntegerOverflow { value: Vec<u8>, offset: usize },
+    UnsortedKeys { key: String, offset: usize },
+    // A list or dictionary past MAX_DEPTH.
+    MaxDepthExceeded { offset: usize },
+    // More input after a value that should have been all of it.
+    TrailingData { offset: usize },
+    // JSON with no bencode equivalent, like floats, booleans and null.
+    UnsupportedJson(String),
 }
 
 impl std::error::Error for BenError {}
 impl std::fmt::Display for BenError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            BenError::MisplacedClosingError => {
-                write!(f, "Unexpected closing tag 'e' found.")
+            BenError::MisplacedClosingError { offset } => {
+                write!(f, "Unexpected closing tag 'e' found at byte {}.", offset)
             }
             BenError::UnexpectedTruncationError => write!(f, "Unexpected ending of byte stream."),
-            BenError::UnexpectedToken { token } => {
-                write!(f, "Unexpected token : {}.", token)
+            BenError::UnexpectedToken { token, offset } => {
+                write!(f, "Unexpected token : {} at byte {}.", token, offset)
+            }
+            BenError::MissingToken { token, offset } => {
+                write!(
+                    f,
+                    "Missing token in stream : {} after byte {}.",
+                    token, offset
+                )
+            }
+            BenError::InvalidInteger { value, offset } => {
+                write!(
+                    f,
+                    "Invalid integer : {} at byte {}.",
+                    String::from_utf8_lossy(value),
+                    offset
+                )
+            }
+            BenError::IntegerOverflow { value, offset } => {
+                write!(
+                    f,
+                    "Integer out of range : {} at byte {}.",
+                    String::from_utf8_lossy(value),
+                    offset
+                )
             }
-            BenError::MissingToken { token } => {
-                write!(f, "Missing token in stream : {}.", token)
+            BenError::UnsortedKeys { key, offset } => {
+                write!(
+                    f,
+                    "Dictionary key out of order : {} at byte {}.",
+                    key, offset
+                )
+            }
+            BenError::MaxDepthExceeded { offset } => {
+                write!(
+                    f,
+                    "Nested deeper than {} levels at byte {}.",
+                    MAX_DEPTH, offset
+                )
+            }
+            BenError::TrailingData { offset } => {
+                write!(f, "Trailing data after the value at byte {}.", offset)
+            }
+            BenError::UnsupportedJson(value) => {
+                write!(f, "No bencode equivalent for JSON value : {}.", value)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(encoded: &[u8]) -> BenResult<Bencode> {
+        Bencode::from_bytes(encoded, |_| None).map(|(value, _)| value)
+    }
+
+    fn decode_error(encoded: &[u8]) -> BenError {
+        let error = decode(encoded).expect_err("should not decode");
+        *error.downcast::<BenError>().expect("should be a BenError")
+    }
+
+    #[test]
+    fn values_round_trip() {
+        let encoded = b"d4:listli1ei-20e3:abce3:numi42e3:rawd4:data2:\xff\xfeee";
+        let (value, rest) = Bencode::from_bytes(encoded, |_| None).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(value.to_bytes().unwrap(), encoded);
+    }
+
+    #[test]
+    fn empty_input_is_an_error() {
+        for encoded in [&b""[..], b"l", b"d"] {
+            assert!(
+                matches!(decode_error(encoded), BenError::UnexpectedTruncationError),
+                "{:?}",
+                String::from_utf8_lossy(encoded)
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_integers_are_refused() {
+        for encoded in [&b"i12x3e"[..], b"ie", b"i-e", b"i-0e", b"i03e", b"i--1e"] {
+            assert!(
+                matches!(decode_error(encoded), BenError::InvalidInteger { .. }),
+                "{:?}",
+                String::from_utf8_lossy(encoded)
+            );
+        }
+        assert!(matches!(
+            decode_error(b"i12"),
+            BenError::MissingToken { token: b'e', .. }
+        ));
+        assert_eq!(decode(b"i-12e").unwrap(), Bencode::Number(-12));
+        assert_eq!(decode(b"i0e").unwrap(), Bencode::Number(0));
+    }
+
+    #[test]
+    fn strict_decoding_wants_sorted_keys() {
+        assert!(Bencode::from_bytes_strict(b"d1:ai1e1:bi2ee", |_| None).is_ok());
+        let error = Bencode::from_bytes_strict(b"d1:bi1e1:ai2ee", |_| None).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<BenError>(),
+            Some(BenError::UnsortedKeys { key, offset: 7 }) if key == "a"
+        ));
+    }
+
+    #[test]
+    fn reader_stops_after_the_value() {
+        let mut reader = &b"d1:ai1eei2e"[..];
+        let value = Bencode::from_reader(&mut reader, |_| None).unwrap();
+        assert_eq!(value.to_bytes().unwrap(), b"d1:ai1ee");
+        assert_eq!(reader, b"i2e");
+        assert!(Bencode::from_reader(&mut &b"d1:ai1"[..], |_| None).is_err());
+    }
+
+    #[test]
+    fn builder_output_decodes_to_the_same_value() {
+        let built = dict! {
+            "zebra" => 1,
+            "apple" => "pie",
+            "list" => vec![Bencode::from(-3), Bencode::from(&b"\xff"[..])],
+        };
+        let encoded = built.to_bytes().unwrap();
+        assert_eq!(
+            encoded,
+            b"d5:apple3:pie4:listli-3e1:\xffe5:zebrai1ee".to_vec()
+        );
+        let (decoded, _) = Bencode::from_bytes_strict(&encoded, |_| None).unwrap();
+        assert_eq!(decoded, built);
+    }
+
+    #[test]
+    fn strings_longer_than_the_input_are_refused() {
+        assert!(matches!(
+            decode_error(b"100:ab"),
+            BenError::UnexpectedTruncationError
+        ));
+        assert!(matches!(
+            decode_error(b"18446744073709551615:ab"),
+            BenError::UnexpectedTruncationError
+        ));
+        assert!(matches!(
+            decode_error(b"d3:keyi1e"),
+            BenError::UnexpectedTruncationError
+        ));
+        // Every way of cutting a string short.
+        let encoded = b"l10:0123456789e";
+        for end in 0..encoded.len() {
+            assert!(decode(&encoded[..end]).is_err());
+        }
+    }
+
+    #[test]
+    fn byte_mode_splits_into_chunks() {
+        let encoded = b"d4:infod6:pieces4:abcdee";
+        let (value, _) = Bencode::from_bytes(encoded, |path| match path {
+            ["info", "pieces"] => Some(2),
+            _ => None,
+        })
+        .unwrap();
+        let Bencode::Dict(dict) = &value else {
+            panic!("not a dictionary");
+        };
+        let Some(BencodeDictValues::Bencode(Bencode::Dict(info))) = dict.get("info") else {
+            panic!("no info dictionary");
+        };
+        assert_eq!(
+            info.get("pieces"),
+            Some(&BencodeDictValues::Bytes(vec![
+                b"ab".to_vec(),
+                b"cd".to_vec()
+            ]))
+        );
+        assert_eq!(value.to_bytes().unwrap(), encoded);
+    }
+
+    #[test]
+    fn zero_sized_chunks_are_not_split() {
+        let encoded = b"d6:pieces4:abcde";
+        let zero = |_: &[&str]| Some(0);
+        let (value, _) = Bencode::from_bytes(encoded, zero).unwrap();
+        assert_eq!(value, Bencode::from_bytes(encoded, |_| None).unwrap().0);
+        let read = Bencode::from_reader(&mut &encoded[..], zero).unwrap();
+        assert_eq!(read, value);
+    }
+
+    #[test]
+    fn endless_digits_are_cut_off() {
+        // Integers and string lengths that never end, the reader has to give up on its own.
+        for first in [&b"i"[..], b"1"] {
+            let mut reader = first.chain(std::io::repeat(b'1'));
+            let error = Bencode::from_reader(&mut reader, |_| None).unwrap_err();
+            assert!(matches!(
+                error.downcast_ref::<BenError>(),
+                Some(BenError::InvalidInteger { value, offset: 0 | 1 }) if value.len() <= 21
+            ));
+        }
+    }
+
+    #[test]
+    fn nesting_is_capped() {
+        let nested = |depth: usize| [b"l".repeat(depth), b"e".repeat(depth)].concat();
+        assert!(decode(&nested(MAX_DEPTH)).is_ok());
+        assert!(matches!(
+            decode_error(&nested(MAX_DEPTH + 1)),
+            BenError::MaxDepthExceeded { offset } if offset == MAX_DEPTH
+        ));
+        let mut reader = &nested(MAX_DEPTH + 1)[..];
+        assert!(Bencode::from_reader(&mut reader, |_| None).is_err());
+        assert!(BencodeRef::from_bytes(&nested(MAX_DEPTH)).is_ok());
+        assert!(BencodeRef::from_bytes(&nested(MAX_DEPTH + 1)).is_err());
+    }
+
+    #[test]
+    fn integers_past_i64_overflow() {
+        assert_eq!(
+            decode(b"i9223372036854775807e").unwrap(),
+            Bencode::Number(i64::MAX)
+        );
+        assert!(matches!(
+            decode_error(b"i9223372036854775808e"),
+            BenError::IntegerOverflow { offset: 1, .. }
+        ));
+    }
+
+    #[test]
+    fn try_decode_wants_exactly_one_value() {
+        assert_eq!(Bencode::try_decode(b"i1e").unwrap(), Bencode::Number(1));
+        let error = Bencode::try_decode(b"i1ei2e").unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<BenError>(),
+            Some(BenError::TrailingData { offset: 3 })
+        ));
+    }
+
+    // Inputs that once made the decoder panic, or would have.
+    #[test]
+    fn hostile_inputs_are_errors() {
+        let deep = b"d1:a".repeat(MAX_DEPTH + 1);
+        let inputs: [&[u8]; 17] = [
+            b"",
+            b"i",
+            b"l",
+            b"d",
+            b"e",
+            b"x",
+            b"1",
+            b"1:",
+            b"-1:a",
+            b":",
+            b"99999999999999999999999:a",
+            b"di1ei2ee",
+            b"d1:a",
+            b"d1:ae",
+            b"d2:\xff\xfei1ee",
+            b"li1e",
+            &deep,
+        ];
+        for input in inputs {
+            assert!(
+                Bencode::try_decode(input).is_err(),
+                "{:?}",
+                String::from_utf8_lossy(input)
+            );
+            let mut reader = input;
+            assert!(Bencode::from_reader(&mut reader, |_| None).is_err());
+        }
+    }
+
+    // Random bytes, and random mangling of valid input, only ever give Ok or Err.
+    #[test]
+    fn random_input_never_panics() {
+        let valid = b"d4:infod6:lengthi12e4:name1:a6:pieces4:abcde4:listli-1e0:lee1:zi0ee";
+        // xorshift, seeded so failures can be replayed.
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for _ in 0..20_000 {
+            let len = (next() % 64) as usize;
+            let noise: Vec<u8> = (0..len).map(|_| next() as u8).collect();
+            let _ = Bencode::try_decode(&noise);
+            let mut mangled = valid.to_vec();
+            for _ in 0..1 + next() % 3 {
+                let at = (next() % mangled.len() as u64) as usize;
+                match next() % 3 {
+                    0 => mangled[at] = next() as u8,
+                    1 => mangled.truncate(at),
+                    _ => mangled.insert(at, b"ield:0123456789-"[(next() % 16) as usize]),
+                }
+                if mangled.is_empty() {
+                    break;
+                }
             }
+            let _ = Bencode::try_decode(&mangled);
+            let _ = Bencode::from_bytes_strict(&mangled, |path| match path {
+                ["info", "pieces"] => Some(2),
+                _ => None,
+            });
+            let _ = Bencode::from_reader(&mut &mangled[..], |_| Some(2));
+            let _ = BencodeRef::from_bytes(&mangled);
         }
     }
 }
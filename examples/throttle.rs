@@ -0,0 +1,66 @@
+// Downloads 4 MiB from a seeder on localhost with the download rate capped at 500 KiB/s, and
+// prints the rate it actually got. Run with `cargo run --example throttle`.
+
+use std::{fs, net::TcpListener, thread, time::Instant};
+
+use bit_torrust::{
+    download::Download,
+    peer::{Peer, PeerConnection, PieceData},
+    storage::MemoryStore,
+    torrent::{CreateOptions, Torrent},
+};
+
+const LIMIT: u64 = 500 * 1024;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let data: Vec<u8> = (0..4 << 20).map(|i| (i % 251) as u8).collect();
+    let path = std::env::temp_dir().join(format!("bit-torrust-throttle-{}", std::process::id()));
+    fs::write(&path, &data)?;
+    let info = Torrent::create(&path, Some(1 << 18), None, &CreateOptions::default())?.info;
+    fs::remove_file(&path)?;
+
+    let mut store = MemoryStore::new(info.pieces().len());
+    for (index, piece) in data.chunks(info.piece_length()).enumerate() {
+        store.insert(PieceData {
+            index: index as u32,
+            piece: piece.to_vec(),
+        });
+    }
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let peer = Peer::from(listener.local_addr()?);
+
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            let (stream, _) = listener.accept().unwrap();
+            let mut seeder = PeerConnection::accept(stream, &|_| Some(&info)).unwrap();
+            seeder.set_store(&store);
+            seeder.announce_pieces().unwrap();
+            // Ends once the download hangs up.
+            let _ = seeder.seed();
+        });
+
+        let mut download = Download::new(&info, vec![peer], 1, 0);
+        download.set_rate_limits(LIMIT, 0);
+        let started = Instant::now();
+        let report = |progress: &bit_torrust::download::Progress| {
+            let elapsed = started.elapsed().as_secs_f64();
+            println!(
+                "{:>5.1}s  {}/{} pieces  {:.0} KiB/s",
+                elapsed,
+                progress.completed_pieces,
+                progress.total_pieces,
+                progress.downloaded as f64 / elapsed / 1024.0
+            );
+        };
+        download.set_progress(&report);
+        download.run()?;
+        let elapsed = started.elapsed().as_secs_f64();
+        println!(
+            "{} bytes in {elapsed:.1}s, {:.0} KiB/s with a limit of {} KiB/s",
+            data.len(),
+            data.len() as f64 / elapsed / 1024.0,
+            LIMIT / 1024
+        );
+        Ok(())
+    })
+}
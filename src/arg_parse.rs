@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::{net::SocketAddr, path::PathBuf};
 
 use clap::{Parser, Subcommand};
 
@@ -15,11 +15,41 @@ pub enum Action {
         bencode: String,
     },
     Info {
+        /// A .torrent file, `-` for stdin or an http(s) URL
         file: PathBuf,
+        /// Also ask the trackers for peers and swarm stats
         #[arg(long, short = 'p')]
         peer_discovery: bool,
     },
     Download {
+        /// A .torrent file, `-` for stdin, an http(s) URL or a magnet link
         file: PathBuf,
+        /// Directory to download into, or the file name to use for single file torrents
+        #[arg(long, short = 'o')]
+        output: Option<PathBuf>,
+        /// Port to listen on, the first free one of 6881-6889 otherwise
+        #[arg(long)]
+        port: Option<u16>,
+        /// Check the finished files against the md5sums some older torrents carry
+        #[arg(long)]
+        md5: bool,
+        /// Connect to these peers (ip:port) instead of asking the trackers, can be repeated
+        #[arg(long = "peer")]
+        peers: Vec<SocketAddr>,
+        /// Same as --peer, for a file of them, one ip:port per line. `#` starts a comment
+        #[arg(long)]
+        peers_file: Option<PathBuf>,
+        /// Cap on the download rate in KiB/s, 0 for no limit
+        #[arg(long, default_value_t = 0)]
+        download_limit: u64,
+        /// Cap on the upload rate in KiB/s, 0 for no limit
+        #[arg(long, default_value_t = 0)]
+        upload_limit: u64,
+    },
+    Verify {
+        /// A .torrent file, `-` for stdin or an http(s) URL
+        file: PathBuf,
+        /// The downloaded file, or the directory of a multi file torrent
+        data: PathBuf,
     },
 }
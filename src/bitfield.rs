@@ -0,0 +1,84 @@
+use bit_vec::BitVec;
+
+// Which pieces a peer has, bit i (counting from the high bit of the first byte) is piece i.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bitfield(BitVec);
+
+impl Bitfield {
+    // Nothing yet, peers that never send a bitfield start out like this.
+    pub fn new(piece_count: usize) -> Self {
+        Self(BitVec::from_elem(piece_count, false))
+    }
+
+    // Every piece, what a HaveAll message says.
+    pub fn full(piece_count: usize) -> Self {
+        Self(BitVec::from_elem(piece_count, true))
+    }
+
+    // What we can advertise once these pieces are verified, `to_bytes` gives the payload of a
+    // Bitfield message. Indices past the piece count are ignored.
+    pub fn from_pieces(pieces: impl IntoIterator<Item = u32>, piece_count: usize) -> Self {
+        let mut bitfield = Self::new(piece_count);
+        for index in pieces {
+            bitfield.set_piece(index as usize);
+        }
+        bitfield
+    }
+
+    pub fn from_bytes(bytes: &[u8], piece_count: usize) -> Self {
+        let mut bits = BitVec::from_bytes(bytes);
+        // Spare bits at the end are just padding.
+        bits.truncate(piece_count);
+        Self(bits)
+    }
+
+    // Whether `bytes` is a well formed bitfield for this many pieces, exactly as many bytes as
+    // needed with every spare bit at the end cleared.
+    pub fn is_valid(bytes: &[u8], piece_count: usize) -> bool {
+        if bytes.len() != piece_count.div_ceil(8) {
+            return false;
+        }
+        let spare = bytes.len() * 8 - piece_count;
+        bytes
+            .last()
+            .is_none_or(|last| last & ((1u16 << spare) - 1) as u8 == 0)
+    }
+
+    // Wire form, padded with zero bits up to a whole byte.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.0.to_bytes()
+    }
+
+    pub fn has_piece(&self, index: usize) -> bool {
+        self.0.get(index).unwrap_or(false)
+    }
+
+    pub fn set_piece(&mut self, index: usize) {
+        if index < self.0.len() {
+            self.0.set(index, true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bitfields_must_fit_the_piece_count() {
+        assert!(Bitfield::is_valid(&[0xff], 8));
+        assert!(!Bitfield::is_valid(&[0xff, 0x00], 8));
+        assert!(!Bitfield::is_valid(&[0xff], 9));
+        // Spare bits at the end have to be clear.
+        assert!(!Bitfield::is_valid(&[0xff, 0x40], 9));
+        assert!(Bitfield::is_valid(&[], 0));
+    }
+
+    #[test]
+    fn verified_pieces_make_a_padded_bitfield() {
+        let bitfield = Bitfield::from_pieces([0, 7, 9, 42], 10);
+        assert_eq!(bitfield.to_bytes(), [0b1000_0001, 0b0100_0000]);
+        assert!(Bitfield::is_valid(&bitfield.to_bytes(), 10));
+        assert_eq!(Bitfield::from_bytes(&bitfield.to_bytes(), 10), bitfield);
+    }
+}
@@ -0,0 +1,184 @@
+// Tit-for-tat choking, as described in the original BitTorrent spec.
+// Every 10 seconds the interested peers that gave us the most (or took the most, once we are
+// seeding) over the last period get unchoked, everyone else gets choked. On top of that one
+// random peer is unchoked every 30 seconds regardless of its rate, so newcomers get a chance to
+// show what they can do.
+
+use std::time::{Duration, Instant};
+
+use crate::{peer::Peer, random::random_index};
+
+const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+const OPTIMISTIC_INTERVAL: Duration = Duration::from_secs(30);
+// Regular unchokes, the optimistic unchoke comes on top of these.
+const UPLOAD_SLOTS: usize = 3;
+
+pub struct Choker {
+    peers: Vec<PeerStats>,
+    last_rechoke: Option<Instant>,
+    last_optimistic: Option<Instant>,
+    optimistic: Option<Peer>,
+}
+
+struct PeerStats {
+    peer: Peer,
+    // Totals as last reported, and as of the last rechoke. Downloads are added up piece by piece,
+    // see `record_received`.
+    downloaded: u64,
+    uploaded: u64,
+    rechoke_downloaded: u64,
+    rechoke_uploaded: u64,
+    interested: bool,
+    unchoked: bool,
+}
+
+impl Default for Choker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Choker {
+    pub fn new() -> Self {
+        Self {
+            peers: Vec::new(),
+            last_rechoke: None,
+            last_optimistic: None,
+            optimistic: None,
+        }
+    }
+
+    // Latest upload total of a connection, the peer is added on first sight.
+    pub fn update(&mut self, peer: &Peer, uploaded: u64, interested: bool) {
+        let stats = self.stats(peer);
+        stats.uploaded = uploaded;
+        stats.interested = interested;
+    }
+
+    // Block bytes the peer sent us for a piece.
+    pub fn record_received(&mut self, peer: &Peer, bytes: u64) {
+        self.stats(peer).downloaded += bytes;
+    }
+
+    fn stats(&mut self, peer: &Peer) -> &mut PeerStats {
+        match self.peers.iter().position(|stats| stats.peer == *peer) {
+            Some(position) => &mut self.peers[position],
+            None => {
+                self.peers.push(PeerStats {
+                    peer: peer.clone(),
+                    downloaded: 0,
+                    uploaded: 0,
+                    rechoke_downloaded: 0,
+                    rechoke_uploaded: 0,
+                    interested: false,
+                    unchoked: false,
+                });
+                self.peers.last_mut().unwrap()
+            }
+        }
+    }
+
+    pub fn remove(&mut self, peer: &Peer) {
+        self.peers.retain(|stats| stats.peer != *peer);
+        if self.optimistic.as_ref() == Some(peer) {
+            self.optimistic = None;
+        }
+    }
+
+    pub fn is_unchoked(&self, peer: &Peer) -> bool {
+        self.peers
+            .iter()
+            .any(|stats| stats.peer == *peer && stats.unchoked)
+    }
+
+    // Picks who to unchoke if the last round is old enough. While downloading peers are ranked
+    // by how fast they send to us, once seeding by how fast they take from us.
+    pub fn rechoke(&mut self, seeding: bool) {
+        let now = Instant::now();
+        if self
+            .last_rechoke
+            .is_some_and(|last| now - last < RECHOKE_INTERVAL)
+        {
+            return;
+        }
+        self.last_rechoke = Some(now);
+
+        // Every round covers the same interval, so the bytes moved stand in for the rate. A peer
+        // that reconnected reports totals starting over, below what we saw last round.
+        let mut ranked: Vec<(u64, usize)> = self
+            .peers
+            .iter()
+            .enumerate()
+            .filter(|(_, stats)| stats.interested)
+            .map(|(i, stats)| {
+                let moved = if seeding {
+                    stats.uploaded.saturating_sub(stats.rechoke_uploaded)
+                } else {
+                    stats.downloaded.saturating_sub(stats.rechoke_downloaded)
+                };
+                (moved, i)
+            })
+            .collect();
+        ranked.sort_by_key(|(moved, _)| std::cmp::Reverse(*moved));
+        let regular: Vec<usize> = ranked.iter().take(UPLOAD_SLOTS).map(|(_, i)| *i).collect();
+
+        if self
+            .last_optimistic
+            .is_none_or(|last| now - last >= OPTIMISTIC_INTERVAL)
+        {
+            self.last_optimistic = Some(now);
+            let choked: Vec<usize> = (0..self.peers.len())
+                .filter(|i| self.peers[*i].interested && !regular.contains(i))
+                .collect();
+            self.optimistic = match choked.len() {
+                0 => None,
+                n => Some(self.peers[choked[random_index(n)]].peer.clone()),
+            };
+        }
+
+        for (i, stats) in self.peers.iter_mut().enumerate() {
+            stats.unchoked = regular.contains(&i) || self.optimistic.as_ref() == Some(&stats.peer);
+            stats.rechoke_downloaded = stats.downloaded;
+            stats.rechoke_uploaded = stats.uploaded;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(port: u16) -> Peer {
+        Peer::from(std::net::SocketAddr::from(([127, 0, 0, 1], port)))
+    }
+
+    #[test]
+    fn fastest_uploaders_get_unchoked() {
+        let mut choker = Choker::new();
+        for port in 1..=5 {
+            choker.update(&peer(port), 0, true);
+            choker.record_received(&peer(port), port as u64 * 100);
+        }
+        choker.rechoke(false);
+        for port in 3..=5 {
+            assert!(choker.is_unchoked(&peer(port)));
+        }
+        // Whoever is left, one of them may be the optimistic unchoke.
+        let unchoked = (1..=5)
+            .filter(|&port| choker.is_unchoked(&peer(port)))
+            .count();
+        assert_eq!(unchoked, UPLOAD_SLOTS + 1);
+    }
+
+    #[test]
+    fn reconnected_peers_starting_over_are_fine() {
+        let mut choker = Choker::new();
+        choker.update(&peer(1), 1000, true);
+        choker.rechoke(true);
+        // A new connection to the same peer, its total starts from zero again.
+        choker.update(&peer(1), 10, true);
+        choker.last_rechoke = None;
+        choker.rechoke(true);
+        assert!(choker.is_unchoked(&peer(1)));
+    }
+}
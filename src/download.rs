@@ -0,0 +1,853 @@
+use std::{
+    collections::VecDeque,
+    error::Error,
+    net::{Shutdown, TcpListener, TcpStream},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, info, warn};
+
+use crate::{
+    bitfield::Bitfield,
+    choker::Choker,
+    info_hash::InfoHash,
+    listener,
+    peer::{
+        ConnectOptions, Peer, PeerConnection, PeerError, PieceData, PieceStats, SharedPiece,
+        DEFAULT_BLOCK_LEN, DEFAULT_PIPELINE_DEPTH,
+    },
+    picker::{PiecePicker, RarestFirst},
+    pool::PeerPool,
+    storage::{self, DiskStore, PieceStore},
+    throttle::RateLimiter,
+    torrent::Info,
+    tracker::{TrackerEvent, TrackerService},
+};
+
+// The download rate is averaged over this much of the recent past.
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+// With this few pieces left, idle connections join in on pieces others are downloading so one
+// slow peer can't hold up the end of the download.
+const ENDGAME_PIECES: usize = 4;
+// How often the tracker is checked on while downloading, it is only announced to once its
+// interval is up.
+const REANNOUNCE_POLL: Duration = Duration::from_secs(1);
+// How long to leave the tracker alone after a failed re-announce.
+const REANNOUNCE_RETRY: Duration = Duration::from_secs(60);
+// The tracker only gets this long to hear we are leaving.
+const GOODBYE_TIMEOUT: Duration = Duration::from_secs(5);
+// How often `DownloadOptions::shutdown` is looked at.
+const SHUTDOWN_POLL: Duration = Duration::from_millis(100);
+// Workers without a connection check back this often for new peers, or the download being over.
+const PEER_POLL: Duration = Duration::from_millis(100);
+// How long a new connection may keep us choked before we try another peer.
+const UNCHOKE_TIMEOUT: Duration = Duration::from_secs(30);
+// A connection gets this long for every block of a piece before the piece is handed to someone
+// else, so a peer trickling blocks just fast enough to dodge the read timeout can't hold it.
+pub const DEFAULT_BLOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How `Torrent::download` and `Magnet::download` go about it.
+pub struct DownloadOptions<'a> {
+    // We listen on the first free one and announce it, a single port is `port..=port`.
+    pub ports: RangeInclusive<u16>,
+    pub max_connections: usize,
+    pub max_retries: usize,
+    pub pipeline_depth: usize,
+    pub progress: Option<&'a (dyn Fn(&Progress) + Sync)>,
+    // Also check the finished files against the torrent's `md5sum`s, where it has any.
+    pub verify_md5: bool,
+    // Peers to connect to straight away. When there are any, the trackers are left out of it: the
+    // whole download runs off this list (and peers found through PEX or connecting to us), failed
+    // peers are retried like any others, and there is no tracker to re-announce to.
+    pub peers: Vec<Peer>,
+    // Set from anywhere to stop early, see `Download::shutdown`.
+    pub shutdown: Option<&'a AtomicBool>,
+    pub connect: ConnectOptions,
+    // Bytes per second over all connections together, 0 is unlimited.
+    pub download_limit: u64,
+    pub upload_limit: u64,
+    // See `Download::set_block_timeout`.
+    pub block_timeout: Duration,
+    // Size of the block requests, see `Download::set_block_len`.
+    pub block_len: usize,
+}
+
+impl Default for DownloadOptions<'_> {
+    fn default() -> Self {
+        Self {
+            ports: listener::DEFAULT_PORTS,
+            max_connections: 5,
+            max_retries: 3,
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+            progress: None,
+            verify_md5: false,
+            peers: Vec::new(),
+            shutdown: None,
+            connect: ConnectOptions::default(),
+            download_limit: 0,
+            upload_limit: 0,
+            block_timeout: DEFAULT_BLOCK_TIMEOUT,
+            block_len: DEFAULT_BLOCK_LEN,
+        }
+    }
+}
+
+// What a finished download left behind.
+#[derive(Debug, Clone)]
+pub struct DownloadSummary {
+    // The file, or the directory of a multi file torrent.
+    pub path: PathBuf,
+    // Pieces that were already on disk from an earlier run.
+    pub resumed_pieces: usize,
+    // The port we listened on.
+    pub port: u16,
+}
+
+// Downloads into `output` (see `storage::output_base`) from `options.peers`, the peers the
+// tracker gives us and any that connect to `listener`, then tells the tracker we're done and
+// leaving. Without a tracker service only the given peers and incoming ones are used, and there
+// is no re-announcing. Pieces already on disk are kept. The tracker hears how far along we are
+// on every announce.
+pub fn download_to(
+    info: &Info,
+    listener: &TcpListener,
+    mut tracker_service: Option<&mut TrackerService>,
+    output: &Path,
+    options: &DownloadOptions,
+) -> Result<DownloadSummary, Box<dyn Error>> {
+    let path = storage::output_base(info, output)?;
+    let store = DiskStore::open_at(info, &path)?;
+    let missing = store.missing_pieces();
+    let resumed_pieces = info.pieces().len() - missing.len();
+    let mut peers = options.peers.clone();
+    if let Some(service) = tracker_service.as_deref_mut() {
+        // Magnet links already announced to find the metadata, announcing again this soon could
+        // break the tracker's `min interval`, the first re-announce catches it up instead.
+        if service.peers().is_empty() {
+            service.get_peers(0, 0, bytes_left(info, &missing))?;
+        }
+        peers.extend_from_slice(service.peers());
+    }
+    let mut download = Download::new(info, peers, options.max_connections, options.max_retries);
+    download.set_store(&store);
+    download.set_pipeline_depth(options.pipeline_depth);
+    download.set_connect_options(options.connect.clone());
+    download.set_rate_limits(options.download_limit, options.upload_limit);
+    download.set_block_timeout(options.block_timeout);
+    download.set_block_len(options.block_len);
+    if let Some(progress) = options.progress {
+        download.set_progress(progress);
+    }
+    let info_hash = info.get_hash();
+    let serving = |hash: &InfoHash| (*hash == info_hash).then_some(info);
+    let stop = AtomicBool::new(false);
+    let result = thread::scope(|scope| {
+        scope.spawn(|| {
+            let accepted = &mut |peer, connection| {
+                download.pool().add_connection(peer, connection);
+            };
+            if let Err(e) = listener::accept_peers(listener, &serving, &stop, accepted) {
+                warn!("Stopped accepting peers : {e}");
+            }
+        });
+        if let Some(shutdown) = options.shutdown {
+            scope.spawn(|| {
+                while !stop.load(Ordering::Relaxed) {
+                    if shutdown.load(Ordering::Relaxed) {
+                        download.shutdown();
+                        break;
+                    }
+                    thread::sleep(SHUTDOWN_POLL);
+                }
+            });
+        }
+        if let Some(service) = tracker_service.as_deref_mut() {
+            scope.spawn(|| {
+                let mut next_try = Instant::now();
+                while !stop.load(Ordering::Relaxed) {
+                    thread::sleep(REANNOUNCE_POLL);
+                    if Instant::now() < next_try {
+                        continue;
+                    }
+                    let stats = download.stats();
+                    match service.maybe_reannounce(stats.uploaded, stats.downloaded, stats.left) {
+                        Ok(Some(peers)) => download.pool().add_peers(&peers),
+                        Ok(None) => {}
+                        Err(e) => {
+                            warn!("Could not reannounce to tracker : {e}");
+                            next_try = Instant::now() + REANNOUNCE_RETRY;
+                        }
+                    }
+                }
+            });
+        }
+        let result = download.run();
+        stop.store(true, Ordering::Relaxed);
+        result
+    });
+    // Whatever verified before a shutdown or failure is kept for the next run.
+    if let Err(e) = store.flush() {
+        warn!("Could not flush the download to disk : {e}");
+    }
+
+    // Failing to say goodbye to the tracker is not worth an error, or a long wait.
+    if let Some(service) = tracker_service {
+        let stats = download.stats();
+        let events = match result {
+            Ok(_) => &[TrackerEvent::Completed, TrackerEvent::Stopped][..],
+            Err(_) => &[TrackerEvent::Stopped],
+        };
+        service.set_timeout(GOODBYE_TIMEOUT);
+        for &event in events {
+            if let Err(e) = service.announce(event, stats.uploaded, stats.downloaded, stats.left) {
+                warn!("Could not announce {event:?} to tracker : {e}");
+            }
+        }
+    }
+    result?;
+    store.finish()?;
+    if options.verify_md5 {
+        storage::verify_md5(info, &path)?;
+    }
+    Ok(DownloadSummary {
+        path,
+        resumed_pieces,
+        port: listener.local_addr()?.port(),
+    })
+}
+
+// Downloads the pieces of a torrent from several peers at once, one thread per connection.
+// Pieces are handed out from a shared queue, a connection that fails puts its piece back for
+// the others to pick up and is replaced by another one from the pool. Workers that find the pool
+// empty wait for peers from re-announces, PEX or incoming connections. The last few pieces are
+// downloaded from every connection that has them at once, see `ENDGAME_PIECES`. A piece is given up on
+// after failing `max_retries` times.
+pub struct Download<'a> {
+    info: &'a Info,
+    pool: PeerPool<'a>,
+    workers: usize,
+    max_retries: usize,
+    pipeline_depth: usize,
+    store: Option<&'a DiskStore<'a>>,
+    progress: Option<&'a (dyn Fn(&Progress) + Sync)>,
+    // With a store we also upload what we have, to whoever the choker picks.
+    choker: Mutex<Choker>,
+    // Which queued piece a connection goes for next, only ever locked while holding the queue.
+    picker: Mutex<Box<dyn PiecePicker>>,
+    // Running totals for the tracker, see `stats`.
+    uploaded: AtomicU64,
+    downloaded: AtomicU64,
+    left: AtomicU64,
+    // Set by `shutdown`, along with a handle on every connection in use to close.
+    shutting_down: AtomicBool,
+    active: Mutex<Vec<(Peer, TcpStream)>>,
+    // Shared by every connection, see `set_rate_limits`.
+    download_limit: Arc<RateLimiter>,
+    upload_limit: Arc<RateLimiter>,
+    block_timeout: Duration,
+    block_len: usize,
+}
+
+// What the tracker is told on announces, in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferStats {
+    // Blocks served to peers, counted between pieces.
+    pub uploaded: u64,
+    // Verified pieces only, by this run.
+    pub downloaded: u64,
+    // Pieces we don't have yet, including ones that were given up on.
+    pub left: u64,
+}
+
+// Reported every time a piece completes.
+#[derive(Debug, Clone)]
+pub struct Progress {
+    // Including pieces that were already in the store.
+    pub completed_pieces: usize,
+    pub total_pieces: usize,
+    // Only counts what was downloaded by this run.
+    pub downloaded: u64,
+    // Bytes per second over the last few seconds.
+    pub rate: f64,
+}
+
+struct WorkQueue {
+    queue: VecDeque<u32>,
+    // Pieces being downloaded, by one connection or several in endgame.
+    active: Vec<Arc<SharedPiece>>,
+    pieces: Vec<Option<PieceData>>,
+    attempts: Vec<usize>,
+    remaining: usize,
+    started: Instant,
+    completed: usize,
+    downloaded: u64,
+    // When recent pieces completed and their size, for the rate.
+    recent: VecDeque<(Instant, usize)>,
+}
+
+impl WorkQueue {
+    fn record(&mut self, len: usize, total_pieces: usize) -> Progress {
+        let now = Instant::now();
+        self.completed += 1;
+        self.downloaded += len as u64;
+        self.recent.push_back((now, len));
+        while self
+            .recent
+            .front()
+            .is_some_and(|(at, _)| now - *at > RATE_WINDOW)
+        {
+            self.recent.pop_front();
+        }
+        // Don't average over time before the download even started.
+        let window = RATE_WINDOW.min(now - self.started).as_secs_f64();
+        let recent: usize = self.recent.iter().map(|(_, len)| len).sum();
+        Progress {
+            completed_pieces: self.completed,
+            total_pieces,
+            downloaded: self.downloaded,
+            rate: if window > 0.0 {
+                recent as f64 / window
+            } else {
+                0.0
+            },
+        }
+    }
+}
+
+impl<'a> Download<'a> {
+    pub fn new(
+        info: &'a Info,
+        peers: Vec<Peer>,
+        max_connections: usize,
+        max_retries: usize,
+    ) -> Self {
+        Self {
+            info,
+            workers: max_connections,
+            pool: PeerPool::new(info, peers, max_connections),
+            max_retries,
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+            store: None,
+            progress: None,
+            choker: Mutex::new(Choker::new()),
+            picker: Mutex::new(Box::new(RarestFirst::new(info.pieces().len()))),
+            uploaded: AtomicU64::new(0),
+            downloaded: AtomicU64::new(0),
+            left: AtomicU64::new(info.total_length() as u64),
+            shutting_down: AtomicBool::new(false),
+            active: Mutex::new(Vec::new()),
+            download_limit: Arc::default(),
+            upload_limit: Arc::default(),
+            block_timeout: DEFAULT_BLOCK_TIMEOUT,
+            block_len: DEFAULT_BLOCK_LEN,
+        }
+    }
+
+    // Stops `run` early from another thread. Connections in use are closed so nobody waits on a
+    // peer, pieces already verified are in the store (and its resume file) while partly
+    // downloaded ones are dropped. `run` then fails with `DownloadError::Interrupted`.
+    pub fn shutdown(&self) {
+        self.shutting_down.store(true, Ordering::Relaxed);
+        for (_, stream) in self.active.lock().unwrap().iter() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::Relaxed)
+    }
+
+    // Safe to call from other threads while `run` is going.
+    pub fn stats(&self) -> TransferStats {
+        TransferStats {
+            uploaded: self.uploaded.load(Ordering::Relaxed),
+            downloaded: self.downloaded.load(Ordering::Relaxed),
+            left: self.left.load(Ordering::Relaxed),
+        }
+    }
+
+    // Where connections come from, more peers can be added to it while downloading.
+    pub fn pool(&self) -> &PeerPool<'a> {
+        &self.pool
+    }
+
+    // Called from the worker threads as pieces complete.
+    pub fn set_progress(&mut self, progress: &'a (dyn Fn(&Progress) + Sync)) {
+        self.progress = Some(progress);
+    }
+
+    // Pieces already in the store are skipped and new ones are written to it as soon as they
+    // arrive instead of being handed back by `run`.
+    pub fn set_store(&mut self, store: &'a DiskStore<'a>) {
+        self.store = Some(store);
+        self.left = AtomicU64::new(bytes_left(self.info, &store.missing_pieces()));
+    }
+
+    // Block requests kept in flight on every connection.
+    pub fn set_pipeline_depth(&mut self, pipeline_depth: usize) {
+        self.pipeline_depth = pipeline_depth;
+    }
+
+    // How new connections to the pool's peers are made.
+    pub fn set_connect_options(&mut self, connect_options: ConnectOptions) {
+        self.pool.set_connect_options(connect_options);
+    }
+
+    // Caps on the total transfer rates in bytes per second, 0 is unlimited.
+    pub fn set_rate_limits(&mut self, download: u64, upload: u64) {
+        self.download_limit = Arc::new(RateLimiter::new(download));
+        self.upload_limit = Arc::new(RateLimiter::new(upload));
+    }
+
+    // A piece gets `block_timeout` for each of its blocks to come in over one connection, after
+    // which that connection is dropped and the piece goes back in the queue.
+    pub fn set_block_timeout(&mut self, block_timeout: Duration) {
+        self.block_timeout = block_timeout;
+    }
+
+    // Pieces are requested in blocks of this size, 16 KiB unless set.
+    pub fn set_block_len(&mut self, block_len: usize) {
+        self.block_len = block_len;
+    }
+
+    // How long a connection may take over a piece. With a download limit, a connection's share
+    // of it could need longer than the timeout allows, so it gets twice that instead.
+    fn piece_budget(&self, piece: &SharedPiece) -> Duration {
+        let budget = self.block_timeout * piece.block_count() as u32;
+        match self.download_limit.rate() {
+            0 => budget,
+            rate => {
+                let share = rate as f64 / self.workers.max(1) as f64;
+                let needed = self.info.piece_len(piece.index()) as f64 / share;
+                budget.max(Duration::from_secs_f64(2.0 * needed))
+            }
+        }
+    }
+
+    // Rarest first unless told otherwise, `Sequential` suits streaming better.
+    pub fn set_picker(&mut self, picker: impl PiecePicker + 'static) {
+        self.picker = Mutex::new(Box::new(picker));
+    }
+
+    pub fn run(&self) -> Result<Vec<PieceData>, DownloadError> {
+        let piece_count = self.info.pieces().len();
+        let queue: VecDeque<u32> = (0..piece_count as u32)
+            .filter(|&index| !self.has_stored(index))
+            .collect();
+        info!(
+            "Downloading {} of {piece_count} pieces over up to {} connections",
+            queue.len(),
+            self.workers
+        );
+        let work = (
+            Mutex::new(WorkQueue {
+                remaining: queue.len(),
+                completed: piece_count - queue.len(),
+                queue,
+                active: Vec::new(),
+                pieces: (0..piece_count).map(|_| None).collect(),
+                attempts: vec![0; piece_count],
+                started: Instant::now(),
+                downloaded: 0,
+                recent: VecDeque::new(),
+            }),
+            Condvar::new(),
+        );
+
+        thread::scope(|scope| {
+            for _ in 0..self.workers {
+                let work = &work;
+                scope.spawn(move || self.worker(work));
+            }
+        });
+
+        if self.is_shutting_down() {
+            return Err(DownloadError::Interrupted);
+        }
+        let (work, _) = work;
+        let pieces = work
+            .into_inner()
+            .expect("Workers should not poison the queue")
+            .pieces;
+        let missing: Vec<u32> = pieces
+            .iter()
+            .enumerate()
+            .filter(|(_, piece)| piece.is_none())
+            .map(|(i, _)| i as u32)
+            .filter(|&index| !self.has_stored(index))
+            .collect();
+        if !missing.is_empty() {
+            return Err(DownloadError::MissingPieces(missing));
+        }
+        Ok(pieces.into_iter().flatten().collect())
+    }
+
+    fn has_stored(&self, index: u32) -> bool {
+        self.store.is_some_and(|store| store.has_piece(index))
+    }
+
+    fn worker(&self, work: &(Mutex<WorkQueue>, Condvar)) {
+        // Keep taking connections from the pool until we run out of pieces, waiting for peers
+        // whenever there are none to be had.
+        while work.0.lock().unwrap().remaining > 0 && !self.is_shutting_down() {
+            let Some((peer, mut connection)) = self.pool.checkout() else {
+                self.pool.wait(PEER_POLL);
+                continue;
+            };
+            if let Ok(stream) = connection.try_clone_stream() {
+                let mut active = self.active.lock().unwrap();
+                // A shutdown that came in before we got here would have missed this one.
+                if self.is_shutting_down() {
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
+                active.push((peer.clone(), stream));
+            }
+            let failed = self.work_on(&peer, &mut connection, work);
+            self.active
+                .lock()
+                .unwrap()
+                .retain(|(active, _)| *active != peer);
+            if failed {
+                self.choker.lock().unwrap().remove(&peer);
+                self.pool.discard(&peer, connection);
+            } else {
+                self.pool.checkin(peer, connection);
+                return;
+            }
+        }
+    }
+
+    // Gets the connection going and downloads over it, returns whether it failed.
+    fn work_on(
+        &self,
+        peer: &Peer,
+        connection: &mut PeerConnection<'a>,
+        work: &(Mutex<WorkQueue>, Condvar),
+    ) -> bool {
+        connection.set_pipeline_depth(self.pipeline_depth);
+        connection.set_rate_limits(
+            Arc::clone(&self.download_limit),
+            Arc::clone(&self.upload_limit),
+        );
+        if let Some(store) = self.store {
+            connection.set_store(store);
+        }
+        if connection.announce_pieces().is_err() {
+            return true;
+        }
+        // Private torrents keep to the peers their trackers give out.
+        if !self.info.is_private() && connection.enable_pex().is_err() {
+            return true;
+        }
+        let deadline = Instant::now() + UNCHOKE_TIMEOUT;
+        connection.express_interest(Some(deadline)).is_err()
+            || self.download_from(peer, connection, work)
+    }
+
+    // Feeds the connection's upload numbers to the choker and applies its decision for this peer.
+    // Connections are only looked at between pieces, which is often enough for 10 second rounds.
+    fn update_choking(
+        &self,
+        peer: &Peer,
+        connection: &mut PeerConnection,
+    ) -> Result<(), PeerError> {
+        if self.store.is_none() {
+            return Ok(());
+        }
+        let unchoked = {
+            let mut choker = self.choker.lock().unwrap();
+            choker.update(peer, connection.uploaded(), connection.peer_interested());
+            choker.rechoke(false);
+            choker.is_unchoked(peer)
+        };
+        connection.set_choking(!unchoked)
+    }
+
+    // Downloads pieces over one connection, returns whether the connection failed (or has nothing
+    // left to offer) and another peer should be tried.
+    fn download_from(
+        &self,
+        peer: &Peer,
+        connection: &mut PeerConnection,
+        work: &(Mutex<WorkQueue>, Condvar),
+    ) -> bool {
+        // The picker counts this peer's pieces for as long as we are connected.
+        let reported = connection.bitfield().clone();
+        self.picker.lock().unwrap().peer_added(&reported);
+        // Pooled connections bring their totals along, only what's new is counted.
+        let mut counted = connection.uploaded();
+        let failed = self.download_pieces(peer, connection, work, reported, &mut counted);
+        self.count_uploaded(connection, &mut counted);
+        self.picker
+            .lock()
+            .unwrap()
+            .peer_removed(connection.bitfield());
+        failed
+    }
+
+    // Pieces cut short by a shutdown are not worth mentioning.
+    fn report_failure(&self, index: u32, peer: &Peer, e: &dyn Error) {
+        if !self.is_shutting_down() {
+            warn!("Piece {index} failed from {peer} : {e}");
+        }
+    }
+
+    // Pieces are timed per connection, the choker ranks peers by what they sent us.
+    fn record_piece_stats(&self, peer: &Peer, index: u32, stats: &PieceStats) {
+        let rate = stats.bytes as f64 / stats.elapsed.as_secs_f64().max(0.001) / 1024.0;
+        debug!(
+            "Got {} bytes of piece {index} from {peer} in {:.2?} ({rate:.1} KiB/s)",
+            stats.bytes, stats.elapsed
+        );
+        self.choker
+            .lock()
+            .unwrap()
+            .record_received(peer, stats.bytes);
+    }
+
+    // Adds what the connection uploaded since `counted` to the running total.
+    fn count_uploaded(&self, connection: &PeerConnection, counted: &mut u64) {
+        let uploaded = connection.uploaded();
+        self.uploaded
+            .fetch_add(uploaded - *counted, Ordering::Relaxed);
+        *counted = uploaded;
+    }
+
+    fn download_pieces(
+        &self,
+        peer: &Peer,
+        connection: &mut PeerConnection,
+        (lock, cvar): &(Mutex<WorkQueue>, Condvar),
+        mut reported: Bitfield,
+        counted: &mut u64,
+    ) -> bool {
+        loop {
+            let discovered = connection.take_pex_peers();
+            if !discovered.is_empty() {
+                self.pool.add_peers(&discovered);
+            }
+            let piece = {
+                let mut work = lock.lock().unwrap();
+                let mut picker = self.picker.lock().unwrap();
+                // Pieces the peer announced since we last looked. Only a second Bitfield message
+                // could take any away, which means counting the peer all over again.
+                let current = connection.bitfield();
+                if *current != reported {
+                    let pieces = 0..self.info.pieces().len();
+                    if pieces
+                        .clone()
+                        .any(|i| reported.has_piece(i) && !current.has_piece(i))
+                    {
+                        picker.peer_removed(&reported);
+                        picker.peer_added(current);
+                    } else {
+                        for i in pieces.filter(|&i| current.has_piece(i) && !reported.has_piece(i))
+                        {
+                            picker.peer_has(i as u32);
+                        }
+                    }
+                    reported = current.clone();
+                }
+                drop(picker);
+                loop {
+                    if work.remaining == 0 {
+                        return false;
+                    }
+                    if self.is_shutting_down() {
+                        return true;
+                    }
+                    // Only pieces this peer advertises.
+                    let picked = self
+                        .picker
+                        .lock()
+                        .unwrap()
+                        .pick(work.queue.make_contiguous(), connection.bitfield());
+                    if let Some(position) =
+                        picked.and_then(|index| work.queue.iter().position(|&i| i == index))
+                    {
+                        let index = work.queue.remove(position).unwrap();
+                        let piece = Arc::new(SharedPiece::with_block_len(
+                            self.info,
+                            index,
+                            self.block_len,
+                        ));
+                        work.active.push(Arc::clone(&piece));
+                        break piece;
+                    }
+                    // Endgame, rather than sit idle, help with a piece another connection is on,
+                    // the one with the fewest helpers so far.
+                    if work.remaining <= ENDGAME_PIECES {
+                        let joined = work
+                            .active
+                            .iter()
+                            .filter(|piece| {
+                                connection.has_piece(piece.index()) && !piece.is_complete()
+                            })
+                            .min_by_key(|piece| Arc::strong_count(piece));
+                        if let Some(piece) = joined {
+                            break Arc::clone(piece);
+                        }
+                    }
+                    if !work.queue.is_empty() {
+                        // The peer has nothing we still need.
+                        return true;
+                    }
+                    // Everything left is in flight, wait in case someone gives a piece back.
+                    work = cvar.wait(work).unwrap();
+                }
+            };
+            let index = piece.index();
+            let deadline = Instant::now() + self.piece_budget(&piece);
+            let downloaded = connection.download_shared(&piece, Some(deadline));
+            if let Ok(stats) = &downloaded {
+                self.record_piece_stats(peer, index, stats);
+            }
+            let mut work = lock.lock().unwrap();
+            // Whoever takes the piece off the active list finishes it, the others move on.
+            let position = work.active.iter().position(|p| Arc::ptr_eq(p, &piece));
+            let result = match (downloaded, position) {
+                (Ok(_), None) => continue,
+                (Ok(_), Some(position)) => {
+                    work.active.swap_remove(position);
+                    drop(work);
+                    let result = piece
+                        .assemble(self.info)
+                        .map_err(Box::<dyn Error>::from)
+                        .and_then(|piece| {
+                            let len = piece.piece.len();
+                            match self.store {
+                                Some(store) => Ok((len, store.write_piece(&piece).map(|_| None)?)),
+                                None => Ok((len, Some(piece))),
+                            }
+                        });
+                    work = lock.lock().unwrap();
+                    result
+                }
+                (
+                    Err(
+                        e @ (PeerError::ConnectionClosed
+                        | PeerError::PieceTimeout { .. }
+                        | PeerError::RequestRejected { .. }
+                        | PeerError::FastNotNegotiated),
+                    ),
+                    Some(position),
+                ) if Arc::strong_count(&piece) == 2 => {
+                    // Not the piece's fault, it goes back without using up a retry. The peer
+                    // leaving needs no mention.
+                    if !matches!(e, PeerError::ConnectionClosed) {
+                        self.report_failure(index, peer, &e);
+                    }
+                    work.active.swap_remove(position);
+                    work.queue.push_back(index);
+                    cvar.notify_all();
+                    return true;
+                }
+                (Err(e), position) => {
+                    // Connections still helping with the piece are left to finish it.
+                    match position {
+                        Some(position) if Arc::strong_count(&piece) == 2 => {
+                            work.active.swap_remove(position);
+                        }
+                        _ => {
+                            self.report_failure(index, peer, &e);
+                            return true;
+                        }
+                    }
+                    Err(e.into())
+                }
+            };
+            match result {
+                Ok((len, piece)) => {
+                    debug!("Piece {index} done from {peer}");
+                    work.pieces[index as usize] = piece;
+                    work.remaining -= 1;
+                    let progress = work.record(len, self.info.pieces().len());
+                    cvar.notify_all();
+                    // Don't hold up the other workers while the callback runs.
+                    drop(work);
+                    self.downloaded.fetch_add(len as u64, Ordering::Relaxed);
+                    self.left.fetch_sub(len as u64, Ordering::Relaxed);
+                    self.count_uploaded(connection, counted);
+                    if let Some(report) = self.progress {
+                        report(&progress);
+                    }
+                    if self.update_choking(peer, connection).is_err() {
+                        return true;
+                    }
+                }
+                Err(e) => {
+                    self.report_failure(index, peer, &*e);
+                    work.attempts[index as usize] += 1;
+                    if work.attempts[index as usize] > self.max_retries {
+                        // Out of retries, this piece is reported as missing.
+                        work.remaining -= 1;
+                    } else {
+                        work.queue.push_back(index);
+                    }
+                    cvar.notify_all();
+                    return true;
+                }
+            }
+        }
+    }
+}
+
+fn bytes_left(info: &Info, missing: &[u32]) -> u64 {
+    missing
+        .iter()
+        .map(|&index| info.piece_len(index) as u64)
+        .sum()
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum DownloadError {
+    #[error("Could not download pieces `{0:?}`.")]
+    MissingPieces(Vec<u32>),
+    #[error("Download was shut down before it finished.")]
+    Interrupted,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStore;
+
+    #[test]
+    fn nothing_is_left_once_every_piece_verifies() {
+        let data: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        let info = Info::for_data(&data, 32768);
+        let mut store = MemoryStore::new(info.pieces().len());
+        for (index, piece) in data.chunks(info.piece_length()).enumerate() {
+            store.insert(PieceData {
+                index: index as u32,
+                piece: piece.to_vec(),
+            });
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = Peer::from(listener.local_addr().unwrap());
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let (stream, _) = listener.accept().unwrap();
+                let mut seeder = PeerConnection::accept(stream, &|_| Some(&info)).unwrap();
+                seeder.set_store(&store);
+                seeder.announce_pieces().unwrap();
+                // Ends once the download hangs up.
+                let _ = seeder.seed();
+            });
+            let download = Download::new(&info, vec![peer], 1, 0);
+            assert_eq!(download.stats().left, data.len() as u64);
+            let pieces = download.run().unwrap();
+            assert_eq!(pieces.len(), info.pieces().len());
+            let stats = download.stats();
+            assert_eq!(stats.left, 0);
+            assert_eq!(stats.downloaded, data.len() as u64);
+        });
+    }
+}
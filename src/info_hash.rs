@@ -0,0 +1,70 @@
+// The SHA-1 of a torrent's bencoded info dictionary, which is what names the torrent everywhere:
+// handshakes, tracker requests and magnet links. Those each want it in a different encoding, which
+// all live here.
+
+use std::fmt;
+
+use crate::torrent::url_encode;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct InfoHash([u8; 20]);
+
+impl InfoHash {
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+
+    // Lowercase, as magnet links usually have it.
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0)
+    }
+
+    // For tracker queries.
+    pub fn to_url_encoded(&self) -> String {
+        url_encode(&self.0)
+    }
+
+    // 40 hex characters, either case.
+    pub fn from_hex(hash: &str) -> Option<Self> {
+        if hash.len() != 40 {
+            return None;
+        }
+        Some(Self(hex::decode(hash).ok()?.try_into().ok()?))
+    }
+
+    // 32 characters of RFC 4648 base32 without padding, either case.
+    pub fn from_base32(hash: &str) -> Option<Self> {
+        if hash.len() != 32 {
+            return None;
+        }
+        let mut bytes = Vec::with_capacity(20);
+        let mut buffer = 0u32;
+        let mut bits = 0;
+        for chr in hash.bytes() {
+            let value = match chr.to_ascii_uppercase() {
+                x @ b'A'..=b'Z' => x - b'A',
+                x @ b'2'..=b'7' => x - b'2' + 26,
+                _ => return None,
+            };
+            buffer = (buffer << 5) | value as u32;
+            bits += 5;
+            if bits >= 8 {
+                bits -= 8;
+                bytes.push((buffer >> bits) as u8);
+            }
+        }
+        Some(Self(bytes.try_into().ok()?))
+    }
+}
+
+impl From<[u8; 20]> for InfoHash {
+    fn from(bytes: [u8; 20]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl fmt::Display for InfoHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_hex())
+    }
+}
@@ -1,3 +1,16 @@
+pub mod bitfield;
+pub mod choker;
+pub mod download;
+pub mod info_hash;
+pub mod listener;
+pub mod magnet;
+pub mod metadata;
+pub mod peer;
+pub mod pex;
+pub mod picker;
+pub mod pool;
+mod random;
+pub mod storage;
+pub mod throttle;
 pub mod torrent;
 pub mod tracker;
-pub mod peer;
@@ -0,0 +1,111 @@
+// Where other peers reach us. We listen on the first free port of a range, the traditional one
+// being 6881-6889, and announce whichever port we got to the trackers.
+
+use std::{
+    io,
+    net::{Ipv4Addr, TcpListener},
+    ops::RangeInclusive,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
+
+use log::{info, warn};
+
+use crate::{
+    info_hash::InfoHash,
+    peer::{Peer, PeerConnection},
+    torrent::Info,
+};
+
+pub const DEFAULT_PORTS: RangeInclusive<u16> = 6881..=6889;
+// Connections are handshaken one at a time, a peer slower than this is not worth the wait.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// How often `stop` is checked while nobody is connecting.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Tries every port of the range in turn, failing with the last port's error if none is free.
+pub fn bind(ports: RangeInclusive<u16>) -> io::Result<TcpListener> {
+    let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, "Empty port range");
+    for port in ports {
+        match TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+// Accepts peers until `stop` is set. Every peer that handshakes for a torrent `serving` knows is
+// handed to `accepted`, the others are dropped.
+pub fn accept_peers<'a>(
+    listener: &TcpListener,
+    serving: &dyn Fn(&InfoHash) -> Option<&'a Info>,
+    stop: &AtomicBool,
+    accepted: &mut dyn FnMut(Peer, PeerConnection<'a>),
+) -> io::Result<()> {
+    listener.set_nonblocking(true)?;
+    while !stop.load(Ordering::Relaxed) {
+        let (stream, addr) = match listener.accept() {
+            Ok(incoming) => incoming,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_POLL_INTERVAL);
+                continue;
+            }
+            Err(e) if is_transient(&e) => {
+                // Out of file descriptors the next accept fails straight away, give some a chance
+                // to close first.
+                warn!("Could not accept a connection : {e}");
+                thread::sleep(ACCEPT_POLL_INTERVAL);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        match PeerConnection::accept(stream, serving) {
+            Ok(connection) => accepted(Peer::new(addr.ip(), addr.port()), connection),
+            Err(e) => info!("Incoming connection from {addr} failed : {e}"),
+        }
+    }
+    Ok(())
+}
+
+// Failures of accept that are down to one connection, or to running out of file descriptors for a
+// while, rather than to the listener.
+fn is_transient(error: &io::Error) -> bool {
+    // EMFILE and ENFILE, which have no ErrorKind. They are the same on Linux, macOS and the BSDs.
+    const TOO_MANY_FILES: [i32; 2] = [24, 23];
+    matches!(
+        error.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted
+    ) || (cfg!(unix)
+        && error
+            .raw_os_error()
+            .is_some_and(|code| TOO_MANY_FILES.contains(&code)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_listener_errors_stop_accepting() {
+        for kind in [
+            io::ErrorKind::ConnectionAborted,
+            io::ErrorKind::ConnectionReset,
+            io::ErrorKind::Interrupted,
+        ] {
+            assert!(is_transient(&io::Error::from(kind)));
+        }
+        if cfg!(unix) {
+            assert!(is_transient(&io::Error::from_raw_os_error(24)));
+        }
+        assert!(!is_transient(&io::Error::from(io::ErrorKind::InvalidInput)));
+        assert!(!is_transient(&io::Error::from(
+            io::ErrorKind::PermissionDenied
+        )));
+    }
+}
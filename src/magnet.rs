@@ -0,0 +1,199 @@
+// Magnet links look like magnet:?xt=urn:btih:<info hash>&dn=<name>&tr=<tracker url>
+// The info hash is either 40 hex characters or 32 base32 characters.
+//
+// A magnet link does not carry the info dictionary, so there are no pieces to download yet.
+// The metadata has to be fetched separately from peers before the torrent can be downloaded.
+
+use std::{error::Error, path::Path};
+
+use reqwest::Url;
+
+use crate::{
+    download::{self, DownloadOptions, DownloadSummary},
+    info_hash::InfoHash,
+    listener, metadata,
+    peer::Peer,
+    tracker::TrackerService,
+};
+
+// Announced as `left` until the metadata says how big the torrent is. Trackers take 0 to mean we
+// are seeding, and don't send seeders any seeders back.
+const UNKNOWN_LEFT: u64 = 16384;
+
+#[derive(Debug, Clone)]
+pub struct Magnet {
+    pub info_hash: InfoHash,
+    pub display_name: Option<String>,
+    pub trackers: Vec<String>,
+}
+
+impl Magnet {
+    pub fn parse(link: &str) -> Result<Self, MagnetError> {
+        let url = Url::parse(link).map_err(|_| MagnetError::NotAMagnetLink)?;
+        if url.scheme() != "magnet" {
+            return Err(MagnetError::NotAMagnetLink);
+        }
+
+        let mut info_hash = None;
+        let mut display_name = None;
+        let mut trackers = Vec::new();
+        // Values are already percent decoded here.
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "xt" if info_hash.is_none() => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_info_hash(hash)?);
+                    }
+                }
+                "dn" => display_name = Some(value.to_string()),
+                "tr" => trackers.push(value.to_string()),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            info_hash: info_hash.ok_or(MagnetError::MissingInfoHash)?,
+            display_name,
+            trackers,
+        })
+    }
+
+    // Same as Torrent::download, with the metadata fetched from the first peers that have it.
+    pub fn download(
+        &self,
+        output: &Path,
+        options: DownloadOptions,
+    ) -> Result<DownloadSummary, Box<dyn Error>> {
+        let listener = listener::bind(options.ports.clone())?;
+        let port = listener.local_addr()?.port();
+        let mut tracker_service = options
+            .peers
+            .is_empty()
+            .then(|| TrackerService::from_magnet(port, self));
+        let peers = match &mut tracker_service {
+            Some(service) => announce_for_metadata(service)?,
+            None => options.peers.clone(),
+        };
+        let info = metadata::fetch_from_peers(&peers, &self.info_hash)?;
+        download::download_to(&info, &listener, tracker_service.as_mut(), output, &options)
+    }
+}
+
+// The first announce, made before we know the size of the torrent.
+fn announce_for_metadata(service: &mut TrackerService) -> Result<Vec<Peer>, Box<dyn Error>> {
+    service.get_peers(0, 0, UNKNOWN_LEFT)
+}
+
+// 40 hex or 32 base32 characters, either case, as found after `urn:btih:`. Anything else is an
+// InvalidInfoHash.
+pub fn parse_info_hash(hash: &str) -> Result<InfoHash, MagnetError> {
+    InfoHash::from_hex(hash)
+        .or_else(|| InfoHash::from_base32(hash))
+        .ok_or_else(|| MagnetError::InvalidInfoHash(hash.to_string()))
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum MagnetError {
+    #[error("Not a magnet link.")]
+    NotAMagnetLink,
+    #[error("Magnet link does not have a BitTorrent info hash.")]
+    MissingInfoHash,
+    #[error("Invalid info hash. (`{0}`)")]
+    InvalidInfoHash(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    const HEX: &str = "2732883b560d39220f4cf903723296151e5765b3";
+    const BASE32: &str = "E4ZIQO2WBU4SED2M7EBXEMUWCUPFOZNT";
+
+    #[test]
+    fn magnet_links_are_parsed() {
+        let magnet = Magnet::parse(&format!(
+            "magnet:?xt=urn:btih:{HEX}&dn=some%20file&tr=http%3A%2F%2Fa%2Fannounce&tr=udp%3A%2F%2Fb%3A80"
+        ))
+        .unwrap();
+        assert_eq!(magnet.info_hash.to_hex(), HEX);
+        assert_eq!(magnet.display_name.as_deref(), Some("some file"));
+        assert_eq!(magnet.trackers, ["http://a/announce", "udp://b:80"]);
+    }
+
+    #[test]
+    fn hex_and_base32_links_agree() {
+        let hex = Magnet::parse(&format!("magnet:?xt=urn:btih:{HEX}")).unwrap();
+        let base32 = Magnet::parse(&format!("magnet:?xt=urn:btih:{BASE32}")).unwrap();
+        assert_eq!(base32.info_hash, hex.info_hash);
+    }
+
+    #[test]
+    fn bad_magnet_links_are_refused() {
+        for hash in [&HEX[1..], "E4ZIQO2WBU4SEDZM7EBXEMUWCUPFOZN1", "xyz"] {
+            assert!(matches!(
+                Magnet::parse(&format!("magnet:?xt=urn:btih:{hash}")),
+                Err(MagnetError::InvalidInfoHash(_))
+            ));
+        }
+        assert!(matches!(
+            Magnet::parse("http://example.com/?xt=urn:btih:x"),
+            Err(MagnetError::NotAMagnetLink)
+        ));
+        assert!(matches!(
+            Magnet::parse("magnet:?dn=name"),
+            Err(MagnetError::MissingInfoHash)
+        ));
+    }
+
+    #[test]
+    fn info_hashes_parse_in_either_case() {
+        let expected = parse_info_hash(HEX).unwrap();
+        assert_eq!(expected.to_hex(), HEX);
+        assert_eq!(parse_info_hash(&HEX.to_uppercase()).unwrap(), expected);
+        assert_eq!(parse_info_hash(BASE32).unwrap(), expected);
+        assert_eq!(parse_info_hash(&BASE32.to_lowercase()).unwrap(), expected);
+        for hash in [
+            "",
+            &HEX[2..],
+            &BASE32[1..],
+            "g732883b560d39220f4cf903723296151e5765b3",
+        ] {
+            assert!(matches!(
+                parse_info_hash(hash),
+                Err(MagnetError::InvalidInfoHash(invalid)) if invalid == hash
+            ));
+        }
+    }
+
+    #[test]
+    fn metadata_announces_are_not_from_a_seeder() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let tracker = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 4096];
+            let len = stream.read(&mut request).unwrap();
+            let body = b"d8:intervali900e5:peers6:\x7f\x00\x00\x01\x1a\xe1e";
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            stream.write_all(response.as_bytes()).unwrap();
+            stream.write_all(body).unwrap();
+            String::from_utf8_lossy(&request[..len]).into_owned()
+        });
+        let magnet = Magnet::parse(&format!("magnet:?xt=urn:btih:{HEX}&tr={url}")).unwrap();
+        let mut service = TrackerService::from_magnet(6881, &magnet);
+        let peers = announce_for_metadata(&mut service).unwrap();
+        assert_eq!(peers, [Peer::new([127, 0, 0, 1].into(), 6881)]);
+        let request = tracker.join().unwrap();
+        let query = request.lines().next().unwrap();
+        assert!(query.contains(&format!("&left={UNKNOWN_LEFT}&")), "{query}");
+        assert!(query.contains("&event=started"), "{query}");
+    }
+}
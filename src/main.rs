@@ -1,30 +1,89 @@
 mod arg_parse;
 
-use bit_torrust::{torrent::Torrent, tracker::TrackerService};
-use std::{error::Error, fs::File, io::Write};
+use bencode::Bencode;
+use bit_torrust::{
+    download::{DownloadOptions, Progress},
+    magnet::Magnet,
+    peer::Peer,
+    storage,
+    torrent::Torrent,
+    tracker::{TrackerEvent, TrackerService, DEFAULT_HTTP_TIMEOUT},
+};
+use std::{
+    error::Error,
+    fs, io,
+    net::SocketAddr,
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 use clap::Parser;
 
+// Set by Ctrl-C while downloading.
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
 fn main() -> Result<(), Box<dyn Error>> {
+    // Warnings only unless RUST_LOG asks for more, `RUST_LOG=debug` shows tracker and peer
+    // traffic.
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
     let cli = arg_parse::Cli::parse();
     match &cli.action {
         arg_parse::Action::Decode { bencode } => {
-            let torrent_metadata = Torrent::from_bytes(bencode.as_bytes())?;
-            println!("Decoded Bencode = {:?}", torrent_metadata);
+            // Pieces hashes read better one per line than as one long string.
+            let (decoded, _) = Bencode::from_bytes(bencode.as_bytes(), |path| match path {
+                ["info", "pieces"] => Some(20),
+                _ => None,
+            })?;
+            println!("{}", decoded.to_pretty_string());
             Ok(())
         }
         arg_parse::Action::Info {
             file,
             peer_discovery,
         } => {
-            let torrent_metadata = Torrent::from_file(file)?;
+            let torrent_metadata = load_torrent(file)?;
+            println!("Info hash : {}", torrent_metadata.info.get_hash());
+            if let Some(comment) = &torrent_metadata.comment {
+                println!("Comment : {comment}");
+            }
+            if let Some(created_by) = &torrent_metadata.created_by {
+                println!("Created by : {created_by}");
+            }
+            if let Some(creation_date) = torrent_metadata.creation_date {
+                println!("Creation date : {creation_date} (UNIX time)");
+            }
+            if let Some(encoding) = &torrent_metadata.encoding {
+                println!("Encoding : {encoding}");
+            }
+            if let Some(source) = torrent_metadata.info.source() {
+                println!("Source : {source}");
+            }
+            if torrent_metadata.info.is_private() {
+                println!("Private : peers only come from the trackers");
+            }
             if *peer_discovery {
                 let mut tracker_service = TrackerService::new(6881, &torrent_metadata);
-                let peers = tracker_service.get_peers(
-                    0,
-                    0,
-                    torrent_metadata.info.get_file_length() as u64,
-                )?;
+                let left = torrent_metadata.info.total_length() as u64;
+                let peers = tracker_service.get_peers(0, 0, left)?;
+                if let Some(warning) = tracker_service.warning_message() {
+                    println!("Tracker warning : {warning}");
+                }
+                if let Some(complete) = tracker_service.complete() {
+                    println!("Seeders : {complete}");
+                }
+                if let Some(incomplete) = tracker_service.incomplete() {
+                    println!("Leechers : {incomplete}");
+                }
+                // Not every tracker can be scraped, that's no reason to fail.
+                if let Ok(stats) = tracker_service.scrape() {
+                    println!(
+                        "Swarm : {} seeders, {} leechers, downloaded {} times",
+                        stats.complete, stats.incomplete, stats.downloaded
+                    );
+                }
+                // Nobody is listening on the port we announced, so the tracker shouldn't hand us
+                // out to others. Whether it heard is not worth failing over.
+                let _ = tracker_service.announce(TrackerEvent::Stopped, 0, 0, left);
                 println!(
                     "Peers : \n{}",
                     peers
@@ -36,26 +95,118 @@ fn main() -> Result<(), Box<dyn Error>> {
             }
             Ok(())
         }
-        // NOTE: Currently downloads single file torrent only in sequence
-        arg_parse::Action::Download { file: torrent_file } => {
-            let torrent_metadata = Torrent::from_file(torrent_file)?;
-            let hash = torrent_metadata.info.get_hash().bytes();
-            let mut tracker_service = TrackerService::new(6881, &torrent_metadata);
-            let peers =
-                tracker_service.get_peers(0, 0, torrent_metadata.info.get_file_length() as u64)?;
-            // TODO: Maintain a pool of connections to peers
-            let mut connection = peers[0].connect(&torrent_metadata.info, &hash)?;
-            let mut pieces = Vec::new();
-            for i in 0..torrent_metadata.info.pieces.len() {
-                println!("Downloading piece {i}");
-                pieces.push(connection.download_piece(i as u32)?);
-            }
-            let mut file = File::create(&torrent_metadata.info.name)?;
-            println!("Saved to {}", &torrent_metadata.info.name);
-            for piece in pieces.into_iter() {
-                file.write_all(&piece.piece)?;
+        arg_parse::Action::Download {
+            file: torrent_file,
+            output,
+            port,
+            md5,
+            peers,
+            peers_file,
+            download_limit,
+            upload_limit,
+        } => {
+            let report = |progress: &Progress| {
+                println!(
+                    "{}/{} pieces ({:.1}%), {:.1} KiB/s",
+                    progress.completed_pieces,
+                    progress.total_pieces,
+                    progress.completed_pieces as f64 * 100.0 / progress.total_pieces as f64,
+                    progress.rate / 1024.0
+                );
+            };
+            let mut options = DownloadOptions {
+                progress: Some(&report),
+                verify_md5: *md5,
+                peers: peers.iter().map(|&addr| Peer::from(addr)).collect(),
+                shutdown: Some(&SHUTDOWN),
+                download_limit: download_limit * 1024,
+                upload_limit: upload_limit * 1024,
+                ..DownloadOptions::default()
+            };
+            // The first Ctrl-C lets the download wind down and tell the tracker, a second one
+            // doesn't wait for that.
+            ctrlc::set_handler(|| {
+                if SHUTDOWN.swap(true, Ordering::Relaxed) {
+                    std::process::exit(130);
+                }
+                println!("Shutting down, press Ctrl-C again to quit right away");
+            })?;
+            if let Some(port) = *port {
+                options.ports = port..=port;
+            }
+            if let Some(peers_file) = peers_file {
+                options.peers.extend(read_peers(peers_file)?);
+            }
+            let output = output.as_deref().unwrap_or(Path::new("."));
+            let magnet_link = torrent_file
+                .to_str()
+                .filter(|file| file.starts_with("magnet:"));
+            let summary = match magnet_link {
+                Some(link) => Magnet::parse(link)?.download(output, options)?,
+                None => load_torrent(torrent_file)?.download(output, options)?,
+            };
+            if summary.resumed_pieces > 0 {
+                println!(
+                    "Resumed with {} pieces already on disk",
+                    summary.resumed_pieces
+                );
+            }
+            println!("Saved to {}", summary.path.display());
+            Ok(())
+        }
+        arg_parse::Action::Verify { file, data } => {
+            let torrent_metadata = load_torrent(file)?;
+            let verified = storage::verify_pieces(&torrent_metadata.info, data)?;
+            let (good, bad): (Vec<_>, Vec<_>) = (0..verified.len()).partition(|&i| verified[i]);
+            let join = |indices: Vec<usize>| {
+                indices
+                    .into_iter()
+                    .map(|i| i.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            };
+            let percentage = good.len() as f64 * 100.0 / verified.len().max(1) as f64;
+            println!("Good pieces : {}", join(good));
+            println!("Bad pieces : {}", join(bad));
+            println!("Complete : {percentage:.2}%");
+            // Silent unless the torrent has md5sums and a file fails them.
+            if let Err(e) = storage::verify_md5(&torrent_metadata.info, data) {
+                println!("MD5 : {e}");
             }
             Ok(())
         }
     }
 }
+
+// One ip:port per line, blank lines and `#` comments are skipped.
+fn read_peers(file: &Path) -> Result<Vec<Peer>, Box<dyn Error>> {
+    let mut peers = Vec::new();
+    for line in fs::read_to_string(file)?.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let addr: SocketAddr = line
+            .parse()
+            .map_err(|e| format!("Invalid peer `{line}` in {} : {e}", file.display()))?;
+        peers.push(Peer::from(addr));
+    }
+    Ok(peers)
+}
+
+// `-` reads the torrent from stdin and http(s) URLs are fetched, anything else is a path.
+fn load_torrent(file: &Path) -> Result<Torrent, Box<dyn Error>> {
+    match file.to_str() {
+        Some("-") => Torrent::from_reader(io::stdin().lock()),
+        Some(url) if url.starts_with("http://") || url.starts_with("https://") => {
+            let response = reqwest::blocking::Client::builder()
+                .timeout(DEFAULT_HTTP_TIMEOUT)
+                .build()?
+                .get(url)
+                .send()?
+                .error_for_status()?;
+            Torrent::from_reader(response)
+        }
+        _ => Torrent::from_file(file),
+    }
+}
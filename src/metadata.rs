@@ -0,0 +1,169 @@
+// Fetches the info dictionary of a magnet link from peers with the ut_metadata extension (BEP 9),
+// which is carried over the extension protocol (BEP 10).
+// The metadata is split into 16KiB pieces, every piece is requested with
+// d8:msg_typei0e5:piecei<index>ee and comes back as d8:msg_typei1e5:piecei<index>e...e<data>.
+
+use std::{error::Error, io::Write, net::TcpStream, time::Duration};
+
+use bencode::{Bencode, BencodeDictValues};
+use log::warn;
+use sha1_smol::Sha1;
+
+use crate::{
+    info_hash::InfoHash,
+    peer::{
+        has_reserved_bit, ConnectOptions, Peer, PeerConnection, PeerMessage, EXTENDED_HANDSHAKE_ID,
+        EXTENSION_PROTOCOL_BIT,
+    },
+    torrent::Info,
+};
+
+const METADATA_PIECE_LEN: usize = 2 << 13;
+// Extended message id we ask peers to use when sending us ut_metadata messages.
+const UT_METADATA_ID: u8 = 1;
+const MSG_TYPE_DATA: i64 = 1;
+const MSG_TYPE_REJECT: i64 = 2;
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+// Refuse to allocate for absurd sizes from misbehaving peers.
+const MAX_METADATA_SIZE: usize = 2 << 23;
+
+// Tries every peer in turn until one hands over metadata matching the info hash.
+pub fn fetch_from_peers(peers: &[Peer], info_hash: &InfoHash) -> Result<Info, Box<dyn Error>> {
+    let mut last_error: Box<dyn Error> = Box::new(MetadataError::NoPeers);
+    for peer in peers {
+        match fetch_metadata(peer, info_hash) {
+            Ok(info) => return Ok(info),
+            Err(e) => {
+                warn!("Could not fetch metadata from {peer} : {e}");
+                last_error = e;
+            }
+        }
+    }
+    Err(last_error)
+}
+
+pub fn fetch_metadata(peer: &Peer, info_hash: &InfoHash) -> Result<Info, Box<dyn Error>> {
+    let mut connection = peer.dial(&ConnectOptions::default())?;
+    connection.set_read_timeout(Some(READ_TIMEOUT))?;
+    let (_, reserved) = PeerConnection::handshake(&mut connection, info_hash)?;
+    // Without the extension protocol there is no asking for ut_metadata either.
+    if !has_reserved_bit(&reserved, EXTENSION_PROTOCOL_BIT) {
+        return Err(Box::new(MetadataError::Unsupported));
+    }
+
+    let handshake = format!("d1:md11:ut_metadatai{}eee", UT_METADATA_ID);
+    send_extended(
+        &mut connection,
+        EXTENDED_HANDSHAKE_ID,
+        handshake.into_bytes(),
+    )?;
+
+    // Wait for the peer's extended handshake, telling us its ut_metadata id and the size.
+    let (peer_metadata_id, metadata_size) = loop {
+        if let Some(PeerMessage::Extended {
+            id: EXTENDED_HANDSHAKE_ID,
+            payload,
+        }) = PeerConnection::decode_message(&mut connection, None)?
+        {
+            break parse_extended_handshake(&payload)?;
+        }
+    };
+
+    let piece_count = metadata_size.div_ceil(METADATA_PIECE_LEN);
+    for piece in 0..piece_count {
+        let request = format!("d8:msg_typei0e5:piecei{}ee", piece);
+        send_extended(&mut connection, peer_metadata_id, request.into_bytes())?;
+    }
+
+    let mut metadata = vec![0u8; metadata_size];
+    let mut received = vec![false; piece_count];
+    while received.iter().any(|x| !x) {
+        let Some(PeerMessage::Extended {
+            id: UT_METADATA_ID,
+            payload,
+        }) = PeerConnection::decode_message(&mut connection, None)?
+        else {
+            continue;
+        };
+        let (message, data) = Bencode::from_bytes(&payload, |_| None)?;
+        let Bencode::Dict(table) = message else {
+            return Err(Box::new(MetadataError::InvalidMessage));
+        };
+        let msg_type = get_number(&table, "msg_type")?;
+        let piece = get_number(&table, "piece")? as usize;
+        if msg_type == MSG_TYPE_REJECT {
+            return Err(Box::new(MetadataError::Rejected));
+        }
+        if msg_type != MSG_TYPE_DATA || piece >= piece_count {
+            continue;
+        }
+        let begin = piece * METADATA_PIECE_LEN;
+        let end = (begin + METADATA_PIECE_LEN).min(metadata_size);
+        if data.len() != end - begin {
+            return Err(Box::new(MetadataError::InvalidMessage));
+        }
+        metadata[begin..end].copy_from_slice(data);
+        received[piece] = true;
+    }
+
+    let mut sha1 = Sha1::new();
+    sha1.update(&metadata);
+    if sha1.digest().bytes() != *info_hash.as_bytes() {
+        return Err(Box::new(MetadataError::HashMismatch));
+    }
+    Info::from_bytes(&metadata)
+}
+
+fn send_extended(
+    connection: &mut TcpStream,
+    id: u8,
+    payload: Vec<u8>,
+) -> Result<(), Box<dyn Error>> {
+    connection.write_all(&PeerConnection::message_payload(PeerMessage::Extended {
+        id,
+        payload,
+    }))?;
+    Ok(())
+}
+
+fn parse_extended_handshake(payload: &[u8]) -> Result<(u8, usize), MetadataError> {
+    let Ok((Bencode::Dict(table), _)) = Bencode::from_bytes(payload, |_| None) else {
+        return Err(MetadataError::InvalidMessage);
+    };
+    let id = match table.get("m") {
+        Some(BencodeDictValues::Bencode(Bencode::Dict(m))) => {
+            get_number(m, "ut_metadata").map_err(|_| MetadataError::Unsupported)?
+        }
+        _ => return Err(MetadataError::Unsupported),
+    };
+    let size = get_number(&table, "metadata_size").map_err(|_| MetadataError::Unsupported)?;
+    // An id of 0 means the peer disabled the extension.
+    match (u8::try_from(id), usize::try_from(size)) {
+        (Ok(id), Ok(size)) if id != 0 && size > 0 && size <= MAX_METADATA_SIZE => Ok((id, size)),
+        _ => Err(MetadataError::Unsupported),
+    }
+}
+
+fn get_number(
+    table: &bencode::IndexMap<String, BencodeDictValues>,
+    key: &str,
+) -> Result<i64, MetadataError> {
+    match table.get(key) {
+        Some(BencodeDictValues::Bencode(Bencode::Number(n))) => Ok(*n),
+        _ => Err(MetadataError::InvalidMessage),
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum MetadataError {
+    #[error("No peers to fetch metadata from.")]
+    NoPeers,
+    #[error("Peer does not support ut_metadata.")]
+    Unsupported,
+    #[error("Peer rejected a metadata request.")]
+    Rejected,
+    #[error("Invalid ut_metadata message.")]
+    InvalidMessage,
+    #[error("Metadata does not match the info hash.")]
+    HashMismatch,
+}
@@ -1,51 +1,166 @@
 use std::{
-    collections::BinaryHeap,
+    collections::VecDeque,
     error::Error,
     io::{Read, Write},
-    net::{IpAddr, Ipv4Addr, TcpStream},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs},
+    ops::RangeInclusive,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
 };
 
+use bencode::{Bencode, BencodeDictValues};
 use bit_vec::BitVec;
 use byteorder::{BigEndian, ByteOrder, ReadBytesExt};
+use log::{debug, trace};
+use sha1_smol::Sha1;
 
-use crate::torrent::Info;
+use crate::{
+    bitfield::Bitfield,
+    info_hash::InfoHash,
+    pex::{self, PexMessage},
+    random::random_index,
+    storage::PieceStore,
+    throttle::RateLimiter,
+    torrent::Info,
+};
+
+#[cfg(feature = "async")]
+mod asynchronous;
+#[cfg(feature = "async")]
+pub use asynchronous::AsyncPeerConnection;
+
+// Azureus style client id, `BT` for bit-torrust followed by the version.
+const PEER_ID_PREFIX: &[u8; 8] = b"-BT0001-";
+static LOCAL_PEER_ID: OnceLock<PeerId> = OnceLock::new();
+// Pieces are requested this much at a time unless told otherwise, 16 KiB like nearly every
+// other client.
+pub const DEFAULT_BLOCK_LEN: usize = 2 << 13;
+// Larger requests than this are refused, as most clients do.
+const MAX_REQUEST_LEN: usize = 2 << 16;
+// Longer messages are refused instead of buffered, blocks and bitfields are well below this.
+const MAX_MESSAGE_LEN: u32 = 1 << 20;
+// Outstanding block requests kept per connection, enough to keep the pipe full on most links
+// without flooding slow peers.
+pub const DEFAULT_PIPELINE_DEPTH: usize = 5;
+// Dialing a peer that isn't there can otherwise hang for as long as the OS lets it.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// How long a peer may stay completely silent before we give up on it.
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(120);
+// Peers drop connections that stay silent for around two minutes, so we talk well before that.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(60);
+// How often a shared piece is checked for blocks that came in over other connections.
+const SHARED_POLL_INTERVAL: Duration = Duration::from_millis(50);
+// Extended message id of the extended handshake itself (BEP 10).
+pub(crate) const EXTENDED_HANDSHAKE_ID: u8 = 0;
+// Bits of the handshake's reserved bytes, as the byte and its mask.
+pub(crate) const EXTENSION_PROTOCOL_BIT: (usize, u8) = (5, 0x10);
+const DHT_BIT: (usize, u8) = (7, 0x01);
+const FAST_BIT: (usize, u8) = (7, 0x04);
+// `PeerConnection::download_rate` is averaged over this much of the recent past.
+const RATE_WINDOW: Duration = Duration::from_secs(5);
 
-pub(crate) const PEER_ID: &str = "1337cafebabedeadbeef";
-const PIECE_BLOCK_LEN: usize = 2 << 13;
+// How peers are dialed.
+#[derive(Debug, Clone)]
+pub struct ConnectOptions {
+    // Peers that don't accept the connection in time fail with `PeerError::PeerUnreachable`.
+    pub timeout: Duration,
+}
 
-#[derive(Debug)]
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        Self {
+            timeout: DEFAULT_CONNECT_TIMEOUT,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Peer {
     ip_addr: IpAddr,
     port: u16,
 }
 
+// Compact form, the address followed by the port, both big endian. 6 bytes for IPv4 and 18 bytes
+// for IPv6 (BEP 7).
 impl TryFrom<&[u8]> for Peer {
     type Error = PeerError;
     fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
-        if value.len() != 6 {
-            return Err(PeerError::UnknownBytesListFormat);
-        }
-        Ok(Self {
-            ip_addr: IpAddr::V4(Ipv4Addr::new(value[0], value[1], value[2], value[3])),
-            port: ((value[4] as u16) << 8) | value[5] as u16,
-        })
+        let ip_addr = match value.len() {
+            6 => IpAddr::V4(Ipv4Addr::new(value[0], value[1], value[2], value[3])),
+            18 => IpAddr::V6(Ipv6Addr::from(
+                <[u8; 16]>::try_from(&value[..16]).expect("Slice should have the right length!"),
+            )),
+            _ => return Err(PeerError::UnknownBytesListFormat),
+        };
+        let port = BigEndian::read_u16(&value[value.len() - 2..]);
+        Ok(Self { ip_addr, port })
+    }
+}
+
+// Non-compact form, a dictionary with `ip` (an address or a DNS name) and `port` keys.
+impl TryFrom<&Bencode> for Peer {
+    type Error = PeerError;
+    fn try_from(value: &Bencode) -> Result<Self, Self::Error> {
+        let Bencode::Dict(peer_table) = value else {
+            return Err(PeerError::UnknownPeerFormat);
+        };
+        let ip = match peer_table.get("ip") {
+            Some(BencodeDictValues::Bencode(Bencode::String(ip))) => ip,
+            _ => return Err(PeerError::UnknownPeerFormat),
+        };
+        let port = match peer_table.get("port") {
+            Some(BencodeDictValues::Bencode(Bencode::Number(port))) => {
+                u16::try_from(*port).map_err(|_| PeerError::UnknownPeerFormat)?
+            }
+            _ => return Err(PeerError::UnknownPeerFormat),
+        };
+        let ip_addr = match ip.parse::<IpAddr>() {
+            Ok(ip_addr) => ip_addr,
+            Err(_) => (ip.as_str(), port)
+                .to_socket_addrs()?
+                .next()
+                .ok_or(PeerError::UnknownPeerFormat)?
+                .ip(),
+        };
+        Ok(Self::new(ip_addr, port))
     }
 }
 
 impl Peer {
+    pub fn new(ip_addr: IpAddr, port: u16) -> Self {
+        Self { ip_addr, port }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::new(self.ip_addr, self.port)
+    }
+
     pub fn connect<'a>(
-        &'a self,
+        &self,
         info_table: &'a Info,
-        info_hash: &'a [u8],
-    ) -> Result<PeerConnection, Box<dyn Error>> {
-        let connection = TcpStream::connect((self.ip_addr, self.port))?;
-        PeerConnection::new(connection, info_table, info_hash)
+        info_hash: &InfoHash,
+        options: &ConnectOptions,
+    ) -> Result<PeerConnection<'a>, Box<dyn Error>> {
+        PeerConnection::new(self.dial(options)?, info_table, info_hash)
+    }
+
+    // Just the TCP connection, refused and timed out connections alike are `PeerUnreachable`.
+    pub(crate) fn dial(&self, options: &ConnectOptions) -> Result<TcpStream, PeerError> {
+        TcpStream::connect_timeout(&self.addr(), options.timeout)
+            .map_err(PeerError::PeerUnreachable)
+    }
+}
+
+impl From<SocketAddr> for Peer {
+    fn from(addr: SocketAddr) -> Self {
+        Self::new(addr.ip(), addr.port())
     }
 }
 
 impl std::fmt::Display for Peer {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}:{}", self.ip_addr, self.port)
+        // Goes through SocketAddr so IPv6 addresses get their brackets.
+        write!(f, "{}", self.addr())
     }
 }
 
@@ -53,34 +168,266 @@ pub struct PeerConnection<'a> {
     connection: TcpStream,
     info_table: &'a Info,
     peer_id: PeerId,
-    bitfield: Option<Vec<u8>>,
+    // Extensions the peer supports, as sent in its handshake.
+    reserved: [u8; 8],
+    bitfield: Bitfield,
     choke: bool,
+    read_timeout: Duration,
+    pipeline_depth: usize,
+    block_len: usize,
+    last_sent: Instant,
+    // Uploading side, requests are queued so a Cancel can still catch them.
+    store: Option<&'a dyn PieceStore>,
+    am_choking: bool,
+    // Set once something above this connection decides who gets unchoked.
+    choke_managed: bool,
+    peer_interested: bool,
+    requested: VecDeque<(u32, u32, u32)>,
+    // Piece data in either direction, for working out rates.
+    downloaded: u64,
+    uploaded: u64,
+    // When blocks came in over the last RATE_WINDOW and their size, see `download_rate`.
+    recent_blocks: VecDeque<(Instant, usize)>,
+    connected_at: Instant,
+    // Set once we asked for PEX, peers it brings up wait here for `take_pex_peers`.
+    pex: bool,
+    pex_peers: Vec<Peer>,
+    // Fast Extension (BEP 6), pieces the peer lets us request while choked and pieces it suggests.
+    allowed_fast: Vec<u32>,
+    suggested: Vec<u32>,
+    // Whether we told the peer which pieces we have, see `announce_pieces`.
+    announced: bool,
+    // Usually shared with the other connections, see `set_rate_limits`.
+    download_limit: Arc<RateLimiter>,
+    upload_limit: Arc<RateLimiter>,
+}
+
+impl<'a> PeerConnection<'a> {
+    // Lets this connection serve the peer's requests from pieces we already have.
+    pub fn set_store(&mut self, store: &'a dyn PieceStore) {
+        self.store = Some(store);
+    }
 }
 
 impl PeerConnection<'_> {
     pub fn new<'a>(
         mut connection: TcpStream,
         info_table: &'a Info,
-        info_hash: &'a [u8],
+        info_hash: &InfoHash,
     ) -> Result<PeerConnection<'a>, Box<dyn Error>> {
-        let peer_id = Self::handshake(&mut connection, info_hash)?;
-        Ok(PeerConnection {
+        let (peer_id, reserved) = Self::handshake(&mut connection, info_hash)?;
+        Self::log_handshake(&connection, &peer_id);
+        Ok(Self::from_stream(connection, info_table, peer_id, reserved))
+    }
+    // Receiving side of the handshake. The peer speaks first, and we only answer if `serving`
+    // knows the torrent it asks for.
+    pub fn accept<'a>(
+        mut connection: TcpStream,
+        serving: &dyn Fn(&InfoHash) -> Option<&'a Info>,
+    ) -> Result<PeerConnection<'a>, PeerError> {
+        let mut request_buf = [0u8; 68];
+        connection.read_exact(&mut request_buf)?;
+        let info_hash = InfoHash::from(
+            <[u8; 20]>::try_from(&request_buf[28..48])
+                .expect("Slice should already have the right length!"),
+        );
+        let info_table = serving(&info_hash).ok_or(PeerError::InvalidInfoHash)?;
+        let (peer_id, reserved) = Self::parse_handshake(&request_buf, &info_hash)?;
+        connection.write_all(&Self::handshake_request(&info_hash))?;
+        Self::log_handshake(&connection, &peer_id);
+        Ok(Self::from_stream(connection, info_table, peer_id, reserved))
+    }
+    fn log_handshake(connection: &TcpStream, peer_id: &PeerId) {
+        if let Ok(addr) = connection.peer_addr() {
+            let id = String::from_utf8_lossy(peer_id.as_bytes());
+            debug!("Handshake with {addr} done, peer id {id}");
+        }
+    }
+    fn from_stream(
+        connection: TcpStream,
+        info_table: &Info,
+        peer_id: PeerId,
+        reserved: [u8; 8],
+    ) -> PeerConnection<'_> {
+        // Requests are small writes one after the other, Nagle would hold all but the first
+        // back until the peer acknowledges it.
+        let _ = connection.set_nodelay(true);
+        PeerConnection {
             connection,
             info_table,
             peer_id,
-            bitfield: None,
+            reserved,
+            bitfield: Bitfield::new(info_table.pieces().len()),
             choke: true,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+            block_len: DEFAULT_BLOCK_LEN,
+            last_sent: Instant::now(),
+            store: None,
+            am_choking: true,
+            choke_managed: false,
+            peer_interested: false,
+            requested: VecDeque::new(),
+            downloaded: 0,
+            uploaded: 0,
+            recent_blocks: VecDeque::new(),
+            connected_at: Instant::now(),
+            pex: false,
+            pex_peers: Vec::new(),
+            allowed_fast: Vec::new(),
+            suggested: Vec::new(),
+            announced: false,
+            download_limit: Arc::default(),
+            upload_limit: Arc::default(),
+        }
+    }
+    // Peers silent for longer than this are dropped with `PeerError::PeerTimeout`.
+    pub fn set_read_timeout(&mut self, read_timeout: Duration) {
+        self.read_timeout = read_timeout;
+    }
+    // How many block requests may be in flight at once, at least one.
+    pub fn set_pipeline_depth(&mut self, pipeline_depth: usize) {
+        self.pipeline_depth = pipeline_depth.max(1);
+    }
+    // Size of the requests `download_piece` makes, at most the 128 KiB peers are likely to serve.
+    // `download_shared` goes by the piece's own block size instead.
+    pub fn set_block_len(&mut self, block_len: usize) {
+        self.block_len = block_len.clamp(1, MAX_REQUEST_LEN);
+    }
+    // Blocks received wait on `download` before we read on, blocks served wait on `upload`
+    // before they are sent. Unlimited until set.
+    pub fn set_rate_limits(&mut self, download: Arc<RateLimiter>, upload: Arc<RateLimiter>) {
+        self.download_limit = download;
+        self.upload_limit = upload;
+    }
+    pub fn peer_id(&self) -> &[u8; 20] {
+        &self.peer_id.0
+    }
+    pub fn reserved(&self) -> &[u8; 8] {
+        &self.reserved
+    }
+    // The peer speaks the extension protocol (BEP 10), which ut_metadata and ut_pex go over.
+    pub fn supports_extensions(&self) -> bool {
+        has_reserved_bit(&self.reserved, EXTENSION_PROTOCOL_BIT)
+    }
+    // The peer runs a DHT node (BEP 5).
+    pub fn supports_dht(&self) -> bool {
+        has_reserved_bit(&self.reserved, DHT_BIT)
+    }
+    // We both speak the Fast Extension (BEP 6), so requests get an answer either way.
+    pub fn supports_fast(&self) -> bool {
+        has_reserved_bit(&self.reserved, FAST_BIT)
+    }
+    // Pieces the peer suggested we download, most likely ones it has cached.
+    pub fn suggested_pieces(&self) -> &[u32] {
+        &self.suggested
+    }
+    // Tells the peer which pieces the store has, once per connection. With the Fast Extension
+    // this is HaveAll or HaveNone when they fit, without it a peer with nothing says nothing.
+    pub fn announce_pieces(&mut self) -> Result<(), PeerError> {
+        let Some(store) = self.store else {
+            return Ok(());
+        };
+        if self.announced {
+            return Ok(());
+        }
+        self.announced = true;
+        let piece_count = self.info_table.pieces().len() as u32;
+        let have: Vec<u32> = (0..piece_count).filter(|&i| store.has_piece(i)).collect();
+        let message = match have.len() {
+            0 if self.supports_fast() => PeerMessage::HaveNone,
+            0 => return Ok(()),
+            len if len == piece_count as usize && self.supports_fast() => PeerMessage::HaveAll,
+            _ => {
+                PeerMessage::Bitfield(Bitfield::from_pieces(have, piece_count as usize).to_bytes())
+            }
+        };
+        self.send_message(message)
+    }
+    // Advertises ut_pex to the peer, if it speaks the extension protocol at all. Whatever it
+    // sends from then on is collected as messages are read, see `take_pex_peers`.
+    pub fn enable_pex(&mut self) -> Result<(), PeerError> {
+        if self.pex || !self.supports_extensions() {
+            return Ok(());
+        }
+        self.pex = true;
+        self.send_message(PeerMessage::Extended {
+            id: EXTENDED_HANDSHAKE_ID,
+            payload: pex::handshake_payload(),
         })
     }
-    fn handshake(connection: &mut TcpStream, info_hash: &[u8]) -> Result<PeerId, PeerError> {
-        // Try handshake
-        // <19 in byte>BitTorrent protocol<8Bytes0><20byte sha1 info table hash><20peerid>
-        let mut buf = [0u8; 68];
-        buf[0] = 19;
-        buf[1..20].clone_from_slice(b"BitTorrent protocol");
-        buf[28..48].clone_from_slice(info_hash);
-        buf[48..].clone_from_slice(PEER_ID.as_bytes());
-
+    // Peers learnt through PEX since the last call.
+    pub fn take_pex_peers(&mut self) -> Vec<Peer> {
+        std::mem::take(&mut self.pex_peers)
+    }
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded
+    }
+    // Bytes per second received from the peer over the last few seconds.
+    pub fn download_rate(&self) -> f64 {
+        let now = Instant::now();
+        let recent: usize = self
+            .recent_blocks
+            .iter()
+            .filter(|(at, _)| now - *at <= RATE_WINDOW)
+            .map(|(_, len)| len)
+            .sum();
+        // Don't average over time before we were even connected.
+        let window = RATE_WINDOW.min(now - self.connected_at).as_secs_f64();
+        if window > 0.0 {
+            recent as f64 / window
+        } else {
+            0.0
+        }
+    }
+    // Counts a new block towards `downloaded` and the rate.
+    fn record_block(&mut self, len: usize) {
+        let now = Instant::now();
+        self.downloaded += len as u64;
+        self.recent_blocks.push_back((now, len));
+        while self
+            .recent_blocks
+            .front()
+            .is_some_and(|(at, _)| now - *at > RATE_WINDOW)
+        {
+            self.recent_blocks.pop_front();
+        }
+    }
+    // Another handle on the socket, shutting it down makes whoever is blocked on this connection
+    // give up.
+    pub(crate) fn try_clone_stream(&self) -> std::io::Result<TcpStream> {
+        self.connection.try_clone()
+    }
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded
+    }
+    pub fn peer_interested(&self) -> bool {
+        self.peer_interested
+    }
+    // Chokes or unchokes the peer. From then on the connection no longer unchokes interested
+    // peers by itself, whoever called this is in charge.
+    pub fn set_choking(&mut self, choking: bool) -> Result<(), PeerError> {
+        self.choke_managed = true;
+        if choking == self.am_choking {
+            return Ok(());
+        }
+        self.am_choking = choking;
+        // Choking drops whatever the peer had asked for, with the Fast Extension we say so.
+        if choking {
+            self.send_message(PeerMessage::Choke)?;
+            for (index, begin, length) in std::mem::take(&mut self.requested) {
+                self.reject_request(index, begin, length)?;
+            }
+            Ok(())
+        } else {
+            self.send_message(PeerMessage::Unchoke)
+        }
+    }
+    pub(crate) fn handshake(
+        connection: &mut TcpStream,
+        info_hash: &InfoHash,
+    ) -> Result<(PeerId, [u8; 8]), PeerError> {
+        let buf = Self::handshake_request(info_hash);
         match connection.write(&buf) {
             Ok(68) => {}
             _ => return Err(PeerError::PeerHandshakeFailed),
@@ -88,25 +435,266 @@ impl PeerConnection<'_> {
 
         let mut response_buf = [0u8; 68];
         connection.read_exact(&mut response_buf)?;
+        Self::parse_handshake(&response_buf, info_hash)
+    }
+    fn handshake_request(info_hash: &InfoHash) -> [u8; 68] {
+        // Try handshake
+        // <19 in byte>BitTorrent protocol<8Bytes0><20byte sha1 info table hash><20peerid>
+        let mut buf = [0u8; 68];
+        buf[0] = 19;
+        buf[1..20].clone_from_slice(b"BitTorrent protocol");
+        // We speak the extension protocol (BEP 10) and the Fast Extension (BEP 6), we have no DHT
+        // node though.
+        for (byte, mask) in [EXTENSION_PROTOCOL_BIT, FAST_BIT] {
+            buf[20 + byte] |= mask;
+        }
+        buf[28..48].clone_from_slice(info_hash.as_bytes());
+        buf[48..].clone_from_slice(PeerId::local().as_bytes());
+        buf
+    }
+    // The peer's id and reserved bytes from its handshake.
+    fn parse_handshake(
+        response_buf: &[u8; 68],
+        info_hash: &InfoHash,
+    ) -> Result<(PeerId, [u8; 8]), PeerError> {
+        if response_buf[..20] != Self::handshake_request(info_hash)[..20] {
+            return Err(PeerError::PeerHandshakeFailed);
+        }
+        // Peer is in some other swarm
+        if response_buf[28..48] != *info_hash.as_bytes() {
+            return Err(PeerError::InvalidInfoHash);
+        }
 
-        let mut peer_id = [0u8; 20];
-        peer_id.clone_from_slice(&response_buf[48..]);
-        Ok(PeerId(
-            response_buf[48..]
+        Ok((
+            PeerId(
+                response_buf[48..]
+                    .try_into()
+                    .expect("Slice should already have the right length!"),
+            ),
+            response_buf[20..28]
                 .try_into()
                 .expect("Slice should already have the right length!"),
         ))
     }
     fn receive_decode(&mut self) -> Result<Option<PeerMessage>, PeerError> {
+        self.receive_decode_until(None)
+    }
+    // Same as receive_decode, but stops waiting and returns `Ok(None)` once `stop` is true.
+    fn receive_decode_until(
+        &mut self,
+        stop: Option<&dyn Fn() -> bool>,
+    ) -> Result<Option<PeerMessage>, PeerError> {
+        // Wait for the start of the next message in short steps, sending keep-alives while the
+        // peer is quiet. Only peeking means a timeout can never cut a message in half.
+        let mut step = self.read_timeout.min(KEEP_ALIVE_INTERVAL);
+        if stop.is_some() {
+            step = step.min(SHARED_POLL_INTERVAL);
+        }
+        self.connection.set_read_timeout(Some(step))?;
+        let idle_since = Instant::now();
+        loop {
+            if stop.is_some_and(|stop| stop()) {
+                return Ok(None);
+            }
+            // Serve queued requests only while the peer has nothing else to say.
+            if !self.requested.is_empty() && !self.has_incoming()? {
+                self.serve_next_request()?;
+                continue;
+            }
+            match self.connection.peek(&mut [0u8; 1]) {
+                Ok(_) => break,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    if idle_since.elapsed() >= self.read_timeout {
+                        return Err(PeerError::PeerTimeout);
+                    }
+                    if self.last_sent.elapsed() >= KEEP_ALIVE_INTERVAL {
+                        self.send(&[0, 0, 0, 0])?;
+                    }
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let piece_count = self.info_table.pieces().len();
+        let message = Self::decode_message(&mut self.connection, Some(piece_count))?;
+        if let Some(message) = &message {
+            trace!("Received {message} from {}", self.remote());
+            check_negotiated(message, &self.reserved)?;
+        }
+        // Whatever we are doing, the peer's pieces are kept up to date.
+        match &message {
+            Some(PeerMessage::Bitfield(bitfield)) => {
+                if !Bitfield::is_valid(bitfield, piece_count) {
+                    return Err(PeerError::InvalidBitfield);
+                }
+                self.bitfield = Bitfield::from_bytes(bitfield, piece_count);
+            }
+            Some(PeerMessage::Have(index)) => self.bitfield.set_piece(*index as usize),
+            Some(PeerMessage::HaveAll) => self.bitfield = Bitfield::full(piece_count),
+            Some(PeerMessage::HaveNone) => self.bitfield = Bitfield::new(piece_count),
+            Some(PeerMessage::SuggestPiece(index))
+                if (*index as usize) < piece_count && !self.suggested.contains(index) =>
+            {
+                self.suggested.push(*index);
+            }
+            Some(PeerMessage::AllowedFast(index))
+                if (*index as usize) < piece_count && !self.allowed_fast.contains(index) =>
+            {
+                self.allowed_fast.push(*index);
+            }
+            // Broken PEX messages are not worth dropping the peer over.
+            Some(PeerMessage::Extended {
+                id: pex::UT_PEX_ID,
+                payload,
+            }) if self.pex => {
+                if let Some(pex) = PexMessage::parse(payload) {
+                    self.pex_peers.extend(pex.added);
+                }
+            }
+            _ => {}
+        }
+        if let Some(message) = &message {
+            self.handle_upload_message(message)?;
+        }
+        Ok(message)
+    }
+    fn has_incoming(&self) -> Result<bool, PeerError> {
+        self.connection.set_nonblocking(true)?;
+        let result = self.connection.peek(&mut [0u8; 1]);
+        self.connection.set_nonblocking(false)?;
+        match result {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+    fn handle_upload_message(&mut self, message: &PeerMessage) -> Result<(), PeerError> {
+        match *message {
+            PeerMessage::Interested => {
+                self.peer_interested = true;
+                // Without anyone managing chokes, anyone interested gets unchoked once we can
+                // serve.
+                if self.am_choking && !self.choke_managed && self.store.is_some() {
+                    self.am_choking = false;
+                    self.send_message(PeerMessage::Unchoke)?;
+                }
+            }
+            PeerMessage::NotInterested => self.peer_interested = false,
+            PeerMessage::Request {
+                index,
+                begin,
+                length,
+            } => {
+                if !self.am_choking && length as usize <= MAX_REQUEST_LEN {
+                    self.requested.push_back((index, begin, length));
+                } else {
+                    self.reject_request(index, begin, length)?;
+                }
+            }
+            PeerMessage::Cancel {
+                index,
+                begin,
+                length,
+            } => self.requested.retain(|r| *r != (index, begin, length)),
+            _ => {}
+        }
+        Ok(())
+    }
+    fn serve_next_request(&mut self) -> Result<(), PeerError> {
+        let Some((index, begin, length)) = self.requested.pop_front() else {
+            return Ok(());
+        };
+        // Requests for anything we don't have are dropped, or rejected with the Fast Extension.
+        match self
+            .store
+            .and_then(|store| store.read_block(index, begin, length))
+        {
+            Some(block) => {
+                self.upload_limit.consume(block.len());
+                self.uploaded += block.len() as u64;
+                self.send_message(PeerMessage::Piece {
+                    index,
+                    begin,
+                    block,
+                })
+            }
+            None => self.reject_request(index, begin, length),
+        }
+    }
+    // Requests we won't serve are simply ignored by peers without the Fast Extension.
+    fn reject_request(&mut self, index: u32, begin: u32, length: u32) -> Result<(), PeerError> {
+        if !self.supports_fast() {
+            return Ok(());
+        }
+        self.send_message(PeerMessage::RejectRequest {
+            index,
+            begin,
+            length,
+        })
+    }
+    // Keeps serving the peer's requests until the connection fails or goes quiet.
+    pub fn seed(&mut self) -> Result<(), PeerError> {
+        loop {
+            self.receive_decode()?;
+        }
+    }
+    fn send(&mut self, payload: &[u8]) -> Result<(), PeerError> {
+        self.connection.write_all(payload)?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+    fn send_message(&mut self, message: PeerMessage) -> Result<(), PeerError> {
+        trace!("Sent {message} to {}", self.remote());
+        self.send(&Self::message_payload(message))
+    }
+    // The peer's address for logging, empty once the connection is gone.
+    fn remote(&self) -> String {
+        self.connection
+            .peer_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_default()
+    }
+    // Bitfields longer than `piece_count` needs are refused before they are read, without a
+    // piece count anything up to MAX_MESSAGE_LEN goes.
+    pub(crate) fn decode_message<R: Read>(
+        reader: &mut R,
+        piece_count: Option<usize>,
+    ) -> Result<Option<PeerMessage>, PeerError> {
         // Length is a 4byte int:msgcode[payload]
         let mut len_buf = [0u8; 4];
-        self.connection.read_exact(&mut len_buf)?;
+        // Hanging up between messages is how peers leave, only EOF inside a message is an error.
+        let first = loop {
+            match reader.read(&mut len_buf[..1]) {
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                result => break result?,
+            }
+        };
+        if first == 0 {
+            return Err(PeerError::ConnectionClosed);
+        }
+        reader.read_exact(&mut len_buf[1..])?;
         let len = BigEndian::read_u32(&len_buf);
         if len == 0 {
             // Just keep-alive, go next
             return Ok(None);
         }
-        let msg_type = self.connection.read_u8()?;
+        if len > MAX_MESSAGE_LEN {
+            return Err(PeerError::PeerMessageTooLong(len));
+        }
+        let msg_type = reader.read_u8()?;
+        // Nothing is read until the length is known to fit, a wrong one would leave us reading
+        // from the middle of the next message, or allocating whatever the peer asks for.
+        let expected = Self::message_len_range(msg_type, piece_count);
+        if len < *expected.start() {
+            return Err(PeerError::PeerMessageTooShort(len));
+        }
+        if len > *expected.end() {
+            return Err(PeerError::PeerMessageTooLong(len));
+        }
         // The actual msg len does not count the msg code
         let actual_msg_len = len - 1;
         match msg_type {
@@ -115,24 +703,24 @@ impl PeerConnection<'_> {
             2 => Ok(Some(PeerMessage::Interested)),
             3 => Ok(Some(PeerMessage::NotInterested)),
             4 =>
-                Ok(Some(PeerMessage::Have(self.connection.read_u32::<BigEndian>()?)))
+                Ok(Some(PeerMessage::Have(reader.read_u32::<BigEndian>()?)))
             ,
-            //TODO : Check length with the received stuff.
+            // Checked against the piece count in receive_decode, which knows it.
             5 => {
                 let mut bitfield = vec![0u8; actual_msg_len as usize];
-                self.connection.read_exact(&mut bitfield)?;
+                reader.read_exact(&mut bitfield)?;
                 Ok(Some(PeerMessage::Bitfield(bitfield)))
             }
             6 => Ok(Some(PeerMessage::Request {
-                index: self.connection.read_u32::<BigEndian>()?,
-                begin: self.connection.read_u32::<BigEndian>()?,
-                length: self.connection.read_u32::<BigEndian>()?,
+                index: reader.read_u32::<BigEndian>()?,
+                begin: reader.read_u32::<BigEndian>()?,
+                length: reader.read_u32::<BigEndian>()?,
             })),
             7 => {
-                let index = self.connection.read_u32::<BigEndian>()?;
-                let begin = self.connection.read_u32::<BigEndian>()?;
+                let index = reader.read_u32::<BigEndian>()?;
+                let begin = reader.read_u32::<BigEndian>()?;
                 let mut piece_data = vec![0u8; (actual_msg_len - 8) as usize];
-                self.connection.read_exact(&mut piece_data)?;
+                reader.read_exact(&mut piece_data)?;
                 Ok(Some(PeerMessage::Piece {
                     index,
                     begin,
@@ -140,14 +728,46 @@ impl PeerConnection<'_> {
                 }))
             }
             8 => Ok(Some(PeerMessage::Cancel {
-                index: self.connection.read_u32::<BigEndian>()?,
-                begin: self.connection.read_u32::<BigEndian>()?,
-                length: self.connection.read_u32::<BigEndian>()?,
+                index: reader.read_u32::<BigEndian>()?,
+                begin: reader.read_u32::<BigEndian>()?,
+                length: reader.read_u32::<BigEndian>()?,
+            })),
+            13 => Ok(Some(PeerMessage::SuggestPiece(
+                reader.read_u32::<BigEndian>()?,
+            ))),
+            14 => Ok(Some(PeerMessage::HaveAll)),
+            15 => Ok(Some(PeerMessage::HaveNone)),
+            16 => Ok(Some(PeerMessage::RejectRequest {
+                index: reader.read_u32::<BigEndian>()?,
+                begin: reader.read_u32::<BigEndian>()?,
+                length: reader.read_u32::<BigEndian>()?,
             })),
+            17 => Ok(Some(PeerMessage::AllowedFast(
+                reader.read_u32::<BigEndian>()?,
+            ))),
+            20 => {
+                let id = reader.read_u8()?;
+                let mut payload = vec![0u8; (actual_msg_len - 1) as usize];
+                reader.read_exact(&mut payload)?;
+                Ok(Some(PeerMessage::Extended { id, payload }))
+            }
             _ => Err(PeerError::TcpStreamGarbageReceived),
         }
     }
-    fn message_payload(&self, message: PeerMessage) -> Vec<u8> {
+    // Lengths a message of this type can have, counting the message code.
+    fn message_len_range(msg_type: u8, piece_count: Option<usize>) -> RangeInclusive<u32> {
+        match msg_type {
+            0..=3 | 14 | 15 => 1..=1,
+            4 | 13 | 17 => 5..=5,
+            5 => 1..=piece_count.map_or(MAX_MESSAGE_LEN, |count| 1 + count.div_ceil(8) as u32),
+            6 | 8 | 16 => 13..=13,
+            // We never ask for blocks over MAX_REQUEST_LEN.
+            7 => 9..=9 + MAX_REQUEST_LEN as u32,
+            20 => 2..=MAX_MESSAGE_LEN,
+            _ => 1..=MAX_MESSAGE_LEN,
+        }
+    }
+    pub(crate) fn message_payload(message: PeerMessage) -> Vec<u8> {
         match message {
             PeerMessage::Choke => vec![0, 0, 0, 1, 0],
             PeerMessage::Unchoke => vec![0, 0, 0, 1, 1],
@@ -161,10 +781,7 @@ impl PeerConnection<'_> {
             }
             PeerMessage::Bitfield(bitfield) => {
                 let len = (1 + bitfield.len() as u32).to_be_bytes();
-                len.into_iter()
-                    .chain([5u8].into_iter())
-                    .chain(bitfield)
-                    .collect()
+                len.into_iter().chain([5u8]).chain(bitfield).collect()
             }
             PeerMessage::Request {
                 index,
@@ -173,7 +790,7 @@ impl PeerConnection<'_> {
             } => {
                 let len = (13u32).to_be_bytes();
                 len.into_iter()
-                    .chain([6u8].into_iter())
+                    .chain([6u8])
                     .chain(index.to_be_bytes())
                     .chain(begin.to_be_bytes())
                     .chain(length.to_be_bytes())
@@ -186,6 +803,7 @@ impl PeerConnection<'_> {
             } => {
                 let len = (9 + block.len() as u32).to_be_bytes();
                 len.into_iter()
+                    .chain([7u8])
                     .chain(index.to_be_bytes())
                     .chain(begin.to_be_bytes())
                     .chain(block)
@@ -198,127 +816,412 @@ impl PeerConnection<'_> {
             } => {
                 let len = (13u32).to_be_bytes();
                 len.into_iter()
-                    .chain([6u8].into_iter())
+                    .chain([8u8])
+                    .chain(index.to_be_bytes())
+                    .chain(begin.to_be_bytes())
+                    .chain(length.to_be_bytes())
+                    .collect()
+            }
+            PeerMessage::SuggestPiece(index) => {
+                let mut payload = vec![0, 0, 0, 5, 13];
+                payload.extend_from_slice(&index.to_be_bytes());
+                payload
+            }
+            PeerMessage::HaveAll => vec![0, 0, 0, 1, 14],
+            PeerMessage::HaveNone => vec![0, 0, 0, 1, 15],
+            PeerMessage::RejectRequest {
+                index,
+                begin,
+                length,
+            } => {
+                let len = (13u32).to_be_bytes();
+                len.into_iter()
+                    .chain([16u8])
                     .chain(index.to_be_bytes())
                     .chain(begin.to_be_bytes())
                     .chain(length.to_be_bytes())
                     .collect()
             }
+            PeerMessage::AllowedFast(index) => {
+                let mut payload = vec![0, 0, 0, 5, 17];
+                payload.extend_from_slice(&index.to_be_bytes());
+                payload
+            }
+            PeerMessage::Extended { id, payload } => {
+                let len = (2 + payload.len() as u32).to_be_bytes();
+                len.into_iter().chain([20u8, id]).chain(payload).collect()
+            }
         }
     }
-    pub fn download_piece(&mut self, piece_index: u32) -> Result<PieceData, PeerError> {
-        self.connection
-            .write_all(&self.message_payload(PeerMessage::Interested))?;
+    // Requests blocks not asked for yet until `pipeline_depth` of them are in flight.
+    fn request_blocks(
+        &mut self,
+        piece_index: u32,
+        piece_len: usize,
+        block_len: usize,
+        piece_received: &BitVec,
+        piece_requested: &mut BitVec,
+    ) -> Result<(), PeerError> {
+        let requests = Self::next_requests(
+            piece_index,
+            piece_len,
+            block_len,
+            self.pipeline_depth,
+            piece_received,
+            piece_requested,
+        );
+        for request in requests {
+            self.send_message(request)?;
+        }
+        Ok(())
+    }
+    // The requests request_blocks sends, marked as requested.
+    fn next_requests(
+        piece_index: u32,
+        piece_len: usize,
+        block_len: usize,
+        pipeline_depth: usize,
+        piece_received: &BitVec,
+        piece_requested: &mut BitVec,
+    ) -> Vec<PeerMessage> {
+        let mut in_flight = piece_requested
+            .iter()
+            .zip(piece_received.iter())
+            .filter(|&(requested, received)| requested && !received)
+            .count();
+        let mut requests = Vec::new();
+        for i in 0..piece_requested.len() {
+            if in_flight >= pipeline_depth {
+                break;
+            }
+            if piece_requested[i] {
+                continue;
+            }
+            piece_requested.set(i, true);
+            in_flight += 1;
+            let begin = i * block_len;
+            requests.push(PeerMessage::Request {
+                index: piece_index,
+                begin: begin as u32,
+                // Only the last block of a piece can be truncated.
+                length: block_len.min(piece_len - begin) as u32,
+            });
+        }
+        requests
+    }
+    // Takes back the request for block `block_index` of the piece.
+    fn cancel_block(&mut self, piece: &SharedPiece, block_index: usize) -> Result<(), PeerError> {
+        let begin = block_index * piece.block_len;
+        self.send_message(PeerMessage::Cancel {
+            index: piece.index,
+            begin: begin as u32,
+            length: piece.block_len.min(piece.piece_len - begin) as u32,
+        })
+    }
+    pub fn has_piece(&self, index: u32) -> bool {
+        self.bitfield.has_piece(index as usize)
+    }
+    // Every piece the peer has told us about so far.
+    pub fn bitfield(&self) -> &Bitfield {
+        &self.bitfield
+    }
+    // Tells the peer we want pieces and waits to be unchoked, which also gives the peer the
+    // chance to tell us which pieces it has. Still choked past `deadline`, the wait ends with
+    // `PeerError::StillChoked`.
+    pub fn express_interest(&mut self, deadline: Option<Instant>) -> Result<(), PeerError> {
+        self.send_message(PeerMessage::Interested)?;
+        let stop = || deadline.is_some_and(|deadline| Instant::now() >= deadline);
         while self.choke {
-            if let Some(PeerMessage::Unchoke) = self.receive_decode()? {
+            if stop() {
+                return Err(PeerError::StillChoked);
+            }
+            let stop: Option<&dyn Fn() -> bool> = deadline.map(|_| &stop as _);
+            if let Some(PeerMessage::Unchoke) = self.receive_decode_until(stop)? {
                 self.choke = false;
             }
         }
-
-        let piece_len = self.info_table.piece_length;
-        let file_size = self.info_table.get_file_length();
-        // A rounded up division!
-        let block_number =
-            piece_len / PIECE_BLOCK_LEN + usize::from(file_size % PIECE_BLOCK_LEN == 0);
-        let mut piece_received = BitVec::from_elem(block_number, false);
-        let mut blocks = BinaryHeap::new();
-        
-        // Send Request
-        let last_piece = piece_index as usize == (self.info_table.pieces.len() - 1);
-        let irregular_blocks = file_size % PIECE_BLOCK_LEN != 0;
-        for i in 0..block_number {
-            let request_buf = self.message_payload(PeerMessage::Request {
-                index: piece_index,
-                begin: (i * PIECE_BLOCK_LEN) as u32,
-                // A truncated length is only present in last piece's last block.
-                length: if !last_piece && irregular_blocks && i == block_number - 1 {
-                    PIECE_BLOCK_LEN as u32
-                } else {
-                    (file_size % PIECE_BLOCK_LEN) as u32
-                },
-            });
-            self.connection.write_all(&request_buf)?;
+        Ok(())
+    }
+    // Choked peers still serve the pieces they allowed us with AllowedFast.
+    fn can_request(&self, piece_index: u32) -> bool {
+        !self.choke || self.allowed_fast.contains(&piece_index)
+    }
+    // A RejectRequest for a block of the piece we are on. The block can be asked for again, but
+    // a peer that rejects us while we are unchoked won't serve it, better get it elsewhere.
+    fn handle_rejection(
+        &mut self,
+        piece_index: u32,
+        block_index: usize,
+        piece_received: &BitVec,
+        piece_requested: &mut BitVec,
+    ) -> Result<(), PeerError> {
+        if block_index >= piece_requested.len() || piece_received[block_index] {
+            return Ok(());
+        }
+        piece_requested.set(block_index, false);
+        if !self.choke {
+            return Err(PeerError::RequestRejected { index: piece_index });
+        }
+        // No longer allowed, if it ever was.
+        self.allowed_fast.retain(|&index| index != piece_index);
+        Ok(())
+    }
+    // A piece from this connection alone, in blocks of `set_block_len`. Same as handing
+    // `download_shared` a piece nobody else has.
+    pub fn download_piece(&mut self, piece_index: u32) -> Result<DownloadedPiece, PeerError> {
+        let piece = SharedPiece::with_block_len(self.info_table, piece_index, self.block_len);
+        let stats = self.download_shared(&piece, None)?;
+        Ok(DownloadedPiece {
+            data: piece.assemble(self.info_table)?,
+            stats,
+        })
+    }
+    // Downloads into a piece other connections may be working on as well (endgame mode). Blocks
+    // already in are never requested, and requests for blocks that arrive over another connection
+    // first are cancelled. Returns once the piece has every block, from whichever connection.
+    // Past `deadline` whatever is still asked for is cancelled and `PeerError::PieceTimeout`
+    // returned, blocks that made it in stay in the piece.
+    pub fn download_shared(
+        &mut self,
+        piece: &SharedPiece,
+        deadline: Option<Instant>,
+    ) -> Result<PieceStats, PeerError> {
+        let started = Instant::now();
+        let mut bytes = 0;
+        let piece_index = piece.index;
+        if piece_index as usize >= self.info_table.pieces().len() {
+            return Err(PeerError::DownloadPieceFailed);
+        }
+        // Waiting to be unchoked counts against the deadline as well.
+        if !self.can_request(piece_index) {
+            match self.express_interest(deadline) {
+                Err(PeerError::StillChoked) => {
+                    return Err(PeerError::PieceTimeout { index: piece_index })
+                }
+                result => result?,
+            }
         }
 
-        // Receive loop
+        let piece_len = self.info_table.piece_len(piece_index);
+        // Blocks we know are in, and the ones this connection is done asking for.
+        let mut known = BitVec::from_elem(piece.block_count(), false);
+        let mut requested = known.clone();
         loop {
-            match self.receive_decode()? {
-                Some(PeerMessage::Bitfield(bitfield)) => self.bitfield = Some(bitfield),
-                Some(PeerMessage::Unchoke) => {
-                    self.choke = false;
+            let present = piece.received();
+            for i in 0..present.len() {
+                if !present[i] || known[i] {
+                    continue;
+                }
+                if requested[i] {
+                    self.cancel_block(piece, i)?;
                 }
+                requested.set(i, true);
+            }
+            known = present;
+            if known.all() {
+                return Ok(PieceStats {
+                    elapsed: started.elapsed(),
+                    bytes,
+                });
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                self.cancel_outstanding(piece, &known, &requested)?;
+                return Err(PeerError::PieceTimeout { index: piece_index });
+            }
+            if self.can_request(piece_index) {
+                self.request_blocks(
+                    piece_index,
+                    piece_len,
+                    piece.block_len,
+                    &known,
+                    &mut requested,
+                )?;
+            }
+
+            let stop = || {
+                piece.received() != known
+                    || deadline.is_some_and(|deadline| Instant::now() >= deadline)
+            };
+            match self.receive_decode_until(Some(&stop))? {
+                Some(PeerMessage::Unchoke) => self.choke = false,
+                // Late blocks of an earlier piece are of no use anymore.
                 Some(PeerMessage::Piece {
                     index,
                     begin,
                     block,
-                }) => {
-                    // TODO: Check this?
-                    piece_received.set(begin as usize / PIECE_BLOCK_LEN, true);
-                    blocks.push(std::cmp::Reverse(BlockData {
-                        index,
-                        begin,
-                        block,
-                    }));
-                    if piece_received.all() {
-                        break;
+                }) if index == piece_index => {
+                    let len = block.len();
+                    bytes += len as u64;
+                    self.download_limit.consume(len);
+                    if piece.insert(begin, block)? {
+                        self.record_block(len);
                     }
+                    // Ours, no need to cancel it even if someone else was faster.
+                    known.set(begin as usize / piece.block_len, true);
                 }
                 Some(PeerMessage::Choke) => {
                     self.choke = true;
-                    break;
+                    if !self.supports_fast() {
+                        requested = known.clone();
+                    }
+                    self.send_message(PeerMessage::Interested)?;
+                }
+                Some(PeerMessage::RejectRequest { index, begin, .. }) if index == piece_index => {
+                    let rejected = self.handle_rejection(
+                        piece_index,
+                        begin as usize / piece.block_len,
+                        &known,
+                        &mut requested,
+                    );
+                    if rejected.is_err() {
+                        self.cancel_outstanding(piece, &known, &requested)?;
+                    }
+                    rejected?;
                 }
                 _ => {}
             };
         }
-
-        // Assemble piece
-        let piece = blocks.into_iter().enumerate().try_fold(
-            Vec::new(),
-            |mut acc, (i, std::cmp::Reverse(block))| {
-                if block.begin as usize != i * PIECE_BLOCK_LEN {
-                    return Err(PeerError::DownloadPieceFailed);
-                }
-                acc.extend(block.block);
-                Ok(acc)
-            },
-        )?;
-        Ok(PieceData {
-            index: piece_index,
-            piece,
-        })
+    }
+    // Cancels every block still asked for when giving up on a piece.
+    fn cancel_outstanding(
+        &mut self,
+        piece: &SharedPiece,
+        known: &BitVec,
+        requested: &BitVec,
+    ) -> Result<(), PeerError> {
+        for i in (0..known.len()).filter(|&i| requested[i] && !known[i]) {
+            self.cancel_block(piece, i)?;
+        }
+        Ok(())
     }
 }
 
-struct PeerId([u8; 20]);
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerId([u8; 20]);
+
+impl PeerId {
+    // The client prefix followed by random alphanumerics, which keeps the id printable so it can
+    // go in tracker queries as is.
+    pub fn generate() -> Self {
+        const ALPHABET: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+        let mut id = [0u8; 20];
+        id[..8].clone_from_slice(PEER_ID_PREFIX);
+        for byte in &mut id[8..] {
+            *byte = ALPHABET[random_index(ALPHABET.len())];
+        }
+        Self(id)
+    }
+
+    // Our own id, generated once and used in every handshake and announce of this session.
+    pub fn local() -> &'static PeerId {
+        LOCAL_PEER_ID.get_or_init(Self::generate)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+}
 pub struct PieceData {
     pub index: u32,
     pub piece: Vec<u8>,
 }
-#[derive(Eq)]
-struct BlockData {
+
+// What `PeerConnection::download_piece` got, and what it took to get it.
+pub struct DownloadedPiece {
+    pub data: PieceData,
+    pub stats: PieceStats,
+}
+
+// What one connection did for a piece in `PeerConnection::download_shared`.
+#[derive(Debug, Clone, Copy)]
+pub struct PieceStats {
+    // From the call until the piece was complete, waiting to be unchoked included.
+    pub elapsed: Duration,
+    // Block bytes this connection got for the piece, repeats of blocks already in included.
+    pub bytes: u64,
+}
+
+// The blocks of a piece as they come in, shared by every connection downloading it.
+pub struct SharedPiece {
     index: u32,
-    begin: u32,
-    block: Vec<u8>,
+    piece_len: usize,
+    // Every connection requests the piece in blocks of this size, so they line up.
+    block_len: usize,
+    blocks: Mutex<Vec<Option<Vec<u8>>>>,
 }
-impl PartialOrd for BlockData {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        match self.index.partial_cmp(&other.index) {
-            Some(core::cmp::Ordering::Equal) => self.begin.partial_cmp(&other.begin),
-            ord => ord,
+
+impl SharedPiece {
+    pub fn new(info: &Info, index: u32) -> Self {
+        Self::with_block_len(info, index, DEFAULT_BLOCK_LEN)
+    }
+
+    // Blocks of `block_len` instead of DEFAULT_BLOCK_LEN, see `PeerConnection::set_block_len`.
+    pub fn with_block_len(info: &Info, index: u32, block_len: usize) -> Self {
+        let piece_len = info.piece_len(index);
+        let block_len = block_len.clamp(1, MAX_REQUEST_LEN);
+        Self {
+            index,
+            piece_len,
+            block_len,
+            blocks: Mutex::new(vec![None; piece_len.div_ceil(block_len)]),
         }
     }
-}
-impl Ord for BlockData {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        match self.index.cmp(&other.index) {
-            core::cmp::Ordering::Equal => self.begin.cmp(&other.begin),
-            ord => ord,
+
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.blocks.lock().unwrap().iter().all(Option::is_some)
+    }
+
+    pub(crate) fn block_count(&self) -> usize {
+        self.blocks.lock().unwrap().len()
+    }
+
+    fn received(&self) -> BitVec {
+        self.blocks
+            .lock()
+            .unwrap()
+            .iter()
+            .map(Option::is_some)
+            .collect()
+    }
+
+    // Returns whether the block was new. Blocks that don't line up with ours are an error.
+    fn insert(&self, begin: u32, block: Vec<u8>) -> Result<bool, PeerError> {
+        let begin = begin as usize;
+        if !begin.is_multiple_of(self.block_len)
+            || begin >= self.piece_len
+            || block.len() != self.block_len.min(self.piece_len - begin)
+        {
+            return Err(PeerError::DownloadPieceFailed);
+        }
+        let mut blocks = self.blocks.lock().unwrap();
+        let slot = &mut blocks[begin / self.block_len];
+        if slot.is_some() {
+            return Ok(false);
         }
+        *slot = Some(block);
+        Ok(true)
     }
-}
-impl PartialEq for BlockData {
-    fn eq(&self, other: &Self) -> bool {
-        self.index == other.index && self.begin == other.begin && self.block == other.block
+
+    // The whole piece once every block is in, checked against the metainfo.
+    pub fn assemble(&self, info: &Info) -> Result<PieceData, PeerError> {
+        let blocks = self.blocks.lock().unwrap();
+        let mut piece = Vec::with_capacity(self.piece_len);
+        for block in blocks.iter() {
+            piece.extend(block.as_ref().ok_or(PeerError::DownloadPieceFailed)?);
+        }
+        let mut sha1 = Sha1::new();
+        sha1.update(&piece);
+        if sha1.digest().bytes() != info.pieces()[self.index as usize] {
+            return Err(PeerError::PieceHashMismatch { index: self.index });
+        }
+        Ok(PieceData {
+            index: self.index,
+            piece,
+        })
     }
 }
 
@@ -345,24 +1248,456 @@ pub enum PeerMessage {
         begin: u32,
         length: u32,
     } = 8,
+    // Fast Extension (BEP 6).
+    SuggestPiece(u32) = 13,
+    HaveAll = 14,
+    HaveNone = 15,
+    RejectRequest {
+        index: u32,
+        begin: u32,
+        length: u32,
+    } = 16,
+    AllowedFast(u32) = 17,
+    // Extension protocol (BEP 10), id 0 is the extended handshake.
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    } = 20,
+}
+
+pub(crate) fn has_reserved_bit(reserved: &[u8; 8], (byte, mask): (usize, u8)) -> bool {
+    reserved[byte] & mask != 0
+}
+
+// Fast Extension messages from a peer that didn't set the fast bit in its handshake, BEP 6 has
+// the connection closed over those.
+pub(crate) fn check_negotiated(message: &PeerMessage, reserved: &[u8; 8]) -> Result<(), PeerError> {
+    let fast = matches!(
+        message,
+        PeerMessage::SuggestPiece(_)
+            | PeerMessage::HaveAll
+            | PeerMessage::HaveNone
+            | PeerMessage::RejectRequest { .. }
+            | PeerMessage::AllowedFast(_)
+    );
+    if fast && !has_reserved_bit(reserved, FAST_BIT) {
+        return Err(PeerError::FastNotNegotiated);
+    }
+    Ok(())
+}
+
+// One line per message for logs, payloads only show their length.
+impl std::fmt::Display for PeerMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerMessage::Choke => write!(f, "Choke"),
+            PeerMessage::Unchoke => write!(f, "Unchoke"),
+            PeerMessage::Interested => write!(f, "Interested"),
+            PeerMessage::NotInterested => write!(f, "NotInterested"),
+            PeerMessage::Have(index) => write!(f, "Have{{index={index}}}"),
+            PeerMessage::Bitfield(bitfield) => write!(f, "Bitfield{{len={}}}", bitfield.len()),
+            PeerMessage::Request {
+                index,
+                begin,
+                length,
+            } => write!(f, "Request{{index={index},begin={begin},len={length}}}"),
+            PeerMessage::Piece {
+                index,
+                begin,
+                block,
+            } => write!(
+                f,
+                "Piece{{index={index},begin={begin},len={}}}",
+                block.len()
+            ),
+            PeerMessage::Cancel {
+                index,
+                begin,
+                length,
+            } => write!(f, "Cancel{{index={index},begin={begin},len={length}}}"),
+            PeerMessage::SuggestPiece(index) => write!(f, "SuggestPiece{{index={index}}}"),
+            PeerMessage::HaveAll => write!(f, "HaveAll"),
+            PeerMessage::HaveNone => write!(f, "HaveNone"),
+            PeerMessage::RejectRequest {
+                index,
+                begin,
+                length,
+            } => write!(
+                f,
+                "RejectRequest{{index={index},begin={begin},len={length}}}"
+            ),
+            PeerMessage::AllowedFast(index) => write!(f, "AllowedFast{{index={index}}}"),
+            PeerMessage::Extended { id, payload } => {
+                write!(f, "Extended{{id={id},len={}}}", payload.len())
+            }
+        }
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
 pub enum PeerError {
-    #[error("Should have exactly 6 bytes in length")]
+    #[error("Compact peer entries should be 6 bytes (IPv4) or 18 bytes (IPv6) long")]
     UnknownBytesListFormat,
+    #[error("Peer entry is not a valid dictionary")]
+    UnknownPeerFormat,
     #[error("Invalid info hash")]
     InvalidInfoHash,
     #[error("Handshake failed, just like in real life")]
     PeerHandshakeFailed,
     #[error("Piece download failed.")]
     DownloadPieceFailed,
+    #[error("Piece `{index}` does not match its hash.")]
+    PieceHashMismatch { index: u32 },
     #[error("Peer message is too short. (`{0}`)")]
     PeerMessageTooShort(u32),
     #[error("Peer message is too long. (`{0}`)")]
     PeerMessageTooLong(u32),
+    #[error("Peer is unreachable. (`{0}`)")]
+    PeerUnreachable(#[source] std::io::Error),
+    #[error("Peer closed the connection.")]
+    ConnectionClosed,
     #[error("TcpStream somewhat failed.")]
     TcpStreamConnectionFailure(#[from] std::io::Error),
+    #[error("Peer went quiet for too long.")]
+    PeerTimeout,
+    #[error("Piece `{index}` took too long.")]
+    PieceTimeout { index: u32 },
     #[error("Unexpected garbage values received.")]
     TcpStreamGarbageReceived,
+    #[error("Bitfield does not match the number of pieces.")]
+    InvalidBitfield,
+    #[error("Peer kept us choked for too long.")]
+    StillChoked,
+    #[error("Peer rejected a request for piece `{index}`.")]
+    RequestRejected { index: u32 },
+    #[error("Peer sent Fast Extension messages without negotiating it.")]
+    FastNotNegotiated,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    // Reads messages off `stream` until one that isn't a keep-alive.
+    fn next_message(stream: &mut TcpStream) -> PeerMessage {
+        loop {
+            if let Some(message) = PeerConnection::decode_message(stream, None).unwrap() {
+                return message;
+            }
+        }
+    }
+
+    fn send(stream: &mut TcpStream, message: PeerMessage) {
+        stream
+            .write_all(&PeerConnection::message_payload(message))
+            .unwrap();
+    }
+
+    // Answers the handshake on `stream` as a peer in the same swarm, one without any extensions.
+    fn handshake(stream: &mut TcpStream) {
+        let mut handshake = [0; 68];
+        stream.read_exact(&mut handshake).unwrap();
+        handshake[20..28].fill(0);
+        stream.write_all(&handshake).unwrap();
+    }
+
+    // A peer in the same swarm that sends `messages` and nothing else.
+    fn remote_peer(messages: Vec<PeerMessage>) -> Peer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            handshake(&mut stream);
+            for message in messages {
+                send(&mut stream, message);
+            }
+            // Kept open until the connection is dropped, so reads don't see EOF.
+            let _ = stream.read_to_end(&mut Vec::new());
+        });
+        Peer::new(addr.ip(), addr.port())
+    }
+
+    #[test]
+    fn bitfields_and_haves_are_tracked() {
+        let info = Info::for_data(&data(160), 16);
+        let info_hash = info.get_hash();
+        let peer = remote_peer(vec![
+            PeerMessage::Bitfield(vec![0b1000_0000, 0]),
+            PeerMessage::Have(9),
+            PeerMessage::Unchoke,
+        ]);
+        let mut connection = peer
+            .connect(&info, &info_hash, &ConnectOptions::default())
+            .unwrap();
+        connection.express_interest(None).unwrap();
+        assert!(connection.has_piece(0));
+        assert!(!connection.has_piece(1));
+        assert!(connection.has_piece(9));
+    }
+
+    #[test]
+    fn choke_mid_piece_asks_again() {
+        let data = data(4 * DEFAULT_BLOCK_LEN);
+        let info = Info::for_data(&data, data.len());
+        let blocks: Vec<Vec<u8>> = data.chunks(DEFAULT_BLOCK_LEN).map(<[u8]>::to_vec).collect();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let remote = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            handshake(&mut stream);
+            send(&mut stream, PeerMessage::Unchoke);
+            assert!(matches!(next_message(&mut stream), PeerMessage::Interested));
+            for _ in 0..blocks.len() {
+                assert!(matches!(
+                    next_message(&mut stream),
+                    PeerMessage::Request { .. }
+                ));
+            }
+            let piece = |stream: &mut TcpStream, i: usize| {
+                let block = blocks[i].clone();
+                let begin = (i * DEFAULT_BLOCK_LEN) as u32;
+                send(
+                    stream,
+                    PeerMessage::Piece {
+                        index: 0,
+                        begin,
+                        block,
+                    },
+                );
+            };
+            // Half the blocks, then a choke that drops the other requests.
+            piece(&mut stream, 0);
+            piece(&mut stream, 1);
+            send(&mut stream, PeerMessage::Choke);
+            send(&mut stream, PeerMessage::Unchoke);
+            assert!(matches!(next_message(&mut stream), PeerMessage::Interested));
+            // Only the blocks that never came are asked for again.
+            let asked: Vec<u32> = (0..2)
+                .map(|_| match next_message(&mut stream) {
+                    PeerMessage::Request {
+                        index: 0, begin, ..
+                    } => begin,
+                    _ => panic!("expected a request"),
+                })
+                .collect();
+            assert_eq!(
+                asked,
+                [2 * DEFAULT_BLOCK_LEN as u32, 3 * DEFAULT_BLOCK_LEN as u32]
+            );
+            piece(&mut stream, 2);
+            piece(&mut stream, 3);
+            // And nothing else until the download hangs up.
+            while let Ok(message) = PeerConnection::decode_message(&mut stream, None) {
+                assert!(!matches!(message, Some(PeerMessage::Request { .. })));
+            }
+        });
+        let info_hash = info.get_hash();
+        let peer = Peer::new(addr.ip(), addr.port());
+        let mut connection = peer
+            .connect(&info, &info_hash, &ConnectOptions::default())
+            .unwrap();
+        assert_eq!(connection.download_piece(0).unwrap().data.piece, data);
+        drop(connection);
+        remote.join().unwrap();
+    }
+
+    #[test]
+    fn request_and_cancel_have_their_own_ids() {
+        let request = PeerConnection::message_payload(PeerMessage::Request {
+            index: 1,
+            begin: 2,
+            length: 3,
+        });
+        assert_eq!(
+            request,
+            [0, 0, 0, 13, 6, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3]
+        );
+        let cancel = PeerConnection::message_payload(PeerMessage::Cancel {
+            index: 1,
+            begin: 2,
+            length: 3,
+        });
+        assert_eq!(cancel, [0, 0, 0, 13, 8, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3]);
+    }
+
+    #[test]
+    fn piece_messages_round_trip() {
+        let piece = PeerConnection::message_payload(PeerMessage::Piece {
+            index: 1,
+            begin: 2,
+            block: vec![0xaa, 0xbb],
+        });
+        assert_eq!(piece, [0, 0, 0, 11, 7, 0, 0, 0, 1, 0, 0, 0, 2, 0xaa, 0xbb]);
+        let decoded = PeerConnection::decode_message(&mut &piece[..], None).unwrap();
+        assert!(matches!(
+            decoded,
+            Some(PeerMessage::Piece { index: 1, begin: 2, block }) if block == [0xaa, 0xbb]
+        ));
+    }
+
+    #[test]
+    fn oversized_messages_are_refused_before_reading() {
+        let mut huge = &[0xff, 0xff, 0xff, 0xff, 7][..];
+        assert!(matches!(
+            PeerConnection::decode_message(&mut huge, None),
+            Err(PeerError::PeerMessageTooLong(u32::MAX))
+        ));
+        // A bitfield longer than the piece count needs.
+        let mut bitfield = &[0, 0, 0, 3, 5, 0xff, 0xff][..];
+        assert!(matches!(
+            PeerConnection::decode_message(&mut bitfield, Some(8)),
+            Err(PeerError::PeerMessageTooLong(3))
+        ));
+    }
+
+    #[test]
+    fn keep_alive_is_not_a_closed_connection() {
+        let mut keep_alive = &[0, 0, 0, 0][..];
+        assert!(matches!(
+            PeerConnection::decode_message(&mut keep_alive, None),
+            Ok(None)
+        ));
+        assert!(matches!(
+            PeerConnection::decode_message(&mut keep_alive, None),
+            Err(PeerError::ConnectionClosed)
+        ));
+        // Hanging up in the middle of a message is not a clean close.
+        let mut cut_short = &[0, 0, 0, 5, 4, 0][..];
+        assert!(matches!(
+            PeerConnection::decode_message(&mut cut_short, None),
+            Err(PeerError::TcpStreamConnectionFailure(_))
+        ));
+    }
+
+    #[test]
+    fn last_piece_shorter_than_a_block() {
+        let data = data(32768 + 1000);
+        let info = Info::for_data(&data, 32768);
+        let piece = SharedPiece::new(&info, 1);
+        assert_eq!(piece.block_count(), 1);
+        assert!(piece.insert(0, data[32768..].to_vec()).unwrap());
+        assert_eq!(piece.assemble(&info).unwrap().piece, &data[32768..]);
+    }
+
+    #[test]
+    fn piece_not_a_multiple_of_the_block_len() {
+        let data = data(40000);
+        let info = Info::for_data(&data, 40000);
+        let piece = SharedPiece::new(&info, 0);
+        assert_eq!(piece.block_count(), 3);
+        // Out of order, the short block first.
+        for begin in [32768, 0, 16384] {
+            let end = (begin + DEFAULT_BLOCK_LEN).min(data.len());
+            let block = data[begin..end].to_vec();
+            assert!(piece.insert(begin as u32, block).unwrap());
+        }
+        assert!(piece.is_complete());
+        assert_eq!(piece.assemble(&info).unwrap().piece, data);
+    }
+
+    #[test]
+    fn custom_block_len_round_trips() {
+        let data = data(5000);
+        let info = Info::for_data(&data, 5000);
+        let piece = SharedPiece::with_block_len(&info, 0, 1024);
+        assert_eq!(piece.block_count(), 5);
+        for (i, block) in data.chunks(1024).enumerate() {
+            piece.insert((i * 1024) as u32, block.to_vec()).unwrap();
+        }
+        assert_eq!(piece.assemble(&info).unwrap().piece, data);
+    }
+
+    #[test]
+    fn blocks_that_dont_line_up_are_refused() {
+        let data = data(40000);
+        let info = Info::for_data(&data, 40000);
+        let piece = SharedPiece::new(&info, 0);
+        // Not on a block boundary, past the end, and the wrong length.
+        assert!(piece.insert(100, vec![0; DEFAULT_BLOCK_LEN]).is_err());
+        assert!(piece.insert(49152, vec![0; 16]).is_err());
+        assert!(piece.insert(0, vec![0; 100]).is_err());
+        assert!(piece.insert(32768, vec![0; DEFAULT_BLOCK_LEN]).is_err());
+        assert_eq!(piece.received().iter().filter(|&b| b).count(), 0);
+    }
+
+    #[test]
+    fn repeated_blocks_are_not_new() {
+        let data = data(1000);
+        let info = Info::for_data(&data, 1000);
+        let piece = SharedPiece::new(&info, 0);
+        assert!(piece.insert(0, data.clone()).unwrap());
+        assert!(!piece.insert(0, data).unwrap());
+    }
+
+    #[test]
+    fn corrupt_pieces_fail_the_hash_check() {
+        let info = Info::for_data(&data(1000), 1000);
+        let piece = SharedPiece::new(&info, 0);
+        piece.insert(0, vec![0; 1000]).unwrap();
+        assert!(matches!(
+            piece.assemble(&info),
+            Err(PeerError::PieceHashMismatch { index: 0 })
+        ));
+    }
+
+    #[test]
+    fn generated_peer_ids_differ() {
+        let (first, second) = (PeerId::generate(), PeerId::generate());
+        assert_ne!(first, second);
+        assert_eq!(&first.as_bytes()[..8], PEER_ID_PREFIX);
+        assert!(first.as_bytes()[8..].iter().all(u8::is_ascii_alphanumeric));
+    }
+
+    // Connects to a peer that sends `messages` and says nothing else, with `reserved` as the
+    // bits it set in its handshake.
+    fn connection_to<'a>(
+        info: &'a Info,
+        reserved: [u8; 8],
+        messages: Vec<PeerMessage>,
+    ) -> PeerConnection<'a> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut remote, _) = listener.accept().unwrap();
+        for message in messages {
+            send(&mut remote, message);
+        }
+        // Kept open until the connection is dropped, so reads don't see EOF.
+        thread::spawn(move || {
+            let _ = remote.read_to_end(&mut Vec::new());
+        });
+        PeerConnection::from_stream(stream, info, PeerId([0; 20]), reserved)
+    }
+
+    #[test]
+    fn fast_messages_need_the_fast_bit() {
+        let info = Info::for_data(&data(1000), 1000);
+        let mut fast = [0; 8];
+        fast[FAST_BIT.0] |= FAST_BIT.1;
+        for message in [
+            PeerMessage::HaveAll,
+            PeerMessage::HaveNone,
+            PeerMessage::SuggestPiece(0),
+            PeerMessage::AllowedFast(0),
+            PeerMessage::RejectRequest {
+                index: 0,
+                begin: 0,
+                length: 1000,
+            },
+        ] {
+            let mut connection = connection_to(&info, [0; 8], vec![message]);
+            assert!(matches!(
+                connection.express_interest(None),
+                Err(PeerError::FastNotNegotiated)
+            ));
+        }
+        let messages = vec![PeerMessage::HaveAll, PeerMessage::Unchoke];
+        let mut connection = connection_to(&info, fast, messages);
+        connection.express_interest(None).unwrap();
+        assert!(connection.has_piece(0));
+    }
 }
@@ -0,0 +1,260 @@
+// PeerConnection on tokio, for downloading from many peers without a thread each. Only the reads
+// and writes are async, handshakes and messages are built and parsed by PeerConnection.
+
+use std::{future::Future, time::Duration};
+
+use bit_vec::BitVec;
+use log::trace;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+
+use super::{
+    check_negotiated, has_reserved_bit, ConnectOptions, Peer, PeerConnection, PeerError, PeerId,
+    PeerMessage, PieceData, SharedPiece, DEFAULT_BLOCK_LEN, DEFAULT_PIPELINE_DEPTH,
+    DEFAULT_READ_TIMEOUT, DHT_BIT, EXTENSION_PROTOCOL_BIT, FAST_BIT, MAX_MESSAGE_LEN,
+};
+use crate::{bitfield::Bitfield, info_hash::InfoHash, torrent::Info};
+
+pub struct AsyncPeerConnection<'a> {
+    connection: TcpStream,
+    info_table: &'a Info,
+    peer_id: PeerId,
+    // Extensions the peer supports, as sent in its handshake.
+    reserved: [u8; 8],
+    bitfield: Bitfield,
+    choke: bool,
+    read_timeout: Duration,
+    pipeline_depth: usize,
+    block_len: usize,
+    downloaded: u64,
+}
+
+impl<'a> AsyncPeerConnection<'a> {
+    pub async fn connect(
+        peer: &Peer,
+        info_table: &'a Info,
+        info_hash: &InfoHash,
+        options: &ConnectOptions,
+    ) -> Result<AsyncPeerConnection<'a>, PeerError> {
+        let mut connection = timeout(options.timeout, TcpStream::connect(peer.addr()))
+            .await
+            .unwrap_or_else(|e| Err(e.into()))
+            .map_err(PeerError::PeerUnreachable)?;
+        // See PeerConnection::from_stream.
+        let _ = connection.set_nodelay(true);
+        let response_buf = with_timeout(DEFAULT_READ_TIMEOUT, async {
+            connection
+                .write_all(&PeerConnection::handshake_request(info_hash))
+                .await?;
+            let mut response_buf = [0u8; 68];
+            connection.read_exact(&mut response_buf).await?;
+            Ok(response_buf)
+        })
+        .await?;
+        let (peer_id, reserved) = PeerConnection::parse_handshake(&response_buf, info_hash)?;
+        Ok(AsyncPeerConnection {
+            connection,
+            info_table,
+            peer_id,
+            reserved,
+            bitfield: Bitfield::new(info_table.pieces().len()),
+            choke: true,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+            block_len: DEFAULT_BLOCK_LEN,
+            downloaded: 0,
+        })
+    }
+
+    // Peers silent for longer than this are dropped with `PeerError::PeerTimeout`.
+    pub fn set_read_timeout(&mut self, read_timeout: Duration) {
+        self.read_timeout = read_timeout;
+    }
+
+    // How many block requests may be in flight at once, at least one.
+    pub fn set_pipeline_depth(&mut self, pipeline_depth: usize) {
+        self.pipeline_depth = pipeline_depth.max(1);
+    }
+    // See `PeerConnection::set_block_len`.
+    pub fn set_block_len(&mut self, block_len: usize) {
+        self.block_len = block_len;
+    }
+
+    pub fn peer_id(&self) -> &[u8; 20] {
+        &self.peer_id.0
+    }
+
+    pub fn reserved(&self) -> &[u8; 8] {
+        &self.reserved
+    }
+
+    pub fn supports_extensions(&self) -> bool {
+        has_reserved_bit(&self.reserved, EXTENSION_PROTOCOL_BIT)
+    }
+
+    pub fn supports_dht(&self) -> bool {
+        has_reserved_bit(&self.reserved, DHT_BIT)
+    }
+
+    pub fn supports_fast(&self) -> bool {
+        has_reserved_bit(&self.reserved, FAST_BIT)
+    }
+
+    pub fn has_piece(&self, index: u32) -> bool {
+        self.bitfield.has_piece(index as usize)
+    }
+
+    pub fn bitfield(&self) -> &Bitfield {
+        &self.bitfield
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded
+    }
+
+    async fn send(&mut self, message: PeerMessage) -> Result<(), PeerError> {
+        trace!("Sent {message} to {}", self.remote());
+        self.connection
+            .write_all(&PeerConnection::message_payload(message))
+            .await?;
+        Ok(())
+    }
+
+    // The peer's address for logging, empty once the connection is gone.
+    fn remote(&self) -> String {
+        self.connection
+            .peer_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_default()
+    }
+
+    // Reads a whole message before decoding it, so the blocking decoder can be used as is.
+    async fn receive_decode(&mut self) -> Result<Option<PeerMessage>, PeerError> {
+        let frame = with_timeout(self.read_timeout, async {
+            let len = self
+                .connection
+                .read_u32()
+                .await
+                .map_err(|e| match e.kind() {
+                    std::io::ErrorKind::UnexpectedEof => PeerError::ConnectionClosed,
+                    _ => e.into(),
+                })?;
+            if len > MAX_MESSAGE_LEN {
+                return Err(PeerError::PeerMessageTooLong(len));
+            }
+            let mut frame = vec![0u8; 4 + len as usize];
+            frame[..4].copy_from_slice(&len.to_be_bytes());
+            self.connection.read_exact(&mut frame[4..]).await?;
+            Ok(frame)
+        })
+        .await?;
+        let piece_count = self.info_table.pieces().len();
+        let message = PeerConnection::decode_message(&mut frame.as_slice(), Some(piece_count))?;
+        if let Some(message) = &message {
+            trace!("Received {message} from {}", self.remote());
+            check_negotiated(message, &self.reserved)?;
+        }
+        match &message {
+            Some(PeerMessage::Bitfield(bitfield)) => {
+                let piece_count = self.info_table.pieces().len();
+                if !Bitfield::is_valid(bitfield, piece_count) {
+                    return Err(PeerError::InvalidBitfield);
+                }
+                self.bitfield = Bitfield::from_bytes(bitfield, piece_count);
+            }
+            Some(PeerMessage::Have(index)) => self.bitfield.set_piece(*index as usize),
+            Some(PeerMessage::HaveAll) => self.bitfield = Bitfield::full(piece_count),
+            Some(PeerMessage::HaveNone) => self.bitfield = Bitfield::new(piece_count),
+            _ => {}
+        }
+        Ok(message)
+    }
+
+    // Tells the peer we want pieces and waits to be unchoked.
+    pub async fn express_interest(&mut self) -> Result<(), PeerError> {
+        self.send(PeerMessage::Interested).await?;
+        while self.choke {
+            if let Some(PeerMessage::Unchoke) = self.receive_decode().await? {
+                self.choke = false;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn download_piece(&mut self, piece_index: u32) -> Result<PieceData, PeerError> {
+        if piece_index as usize >= self.info_table.pieces().len() {
+            return Err(PeerError::DownloadPieceFailed);
+        }
+        if self.choke {
+            self.express_interest().await?;
+        }
+
+        let piece_len = self.info_table.piece_len(piece_index);
+        let piece = SharedPiece::with_block_len(self.info_table, piece_index, self.block_len);
+        let mut requested = BitVec::from_elem(piece.block_count(), false);
+        loop {
+            let received = piece.received();
+            if received.all() {
+                return piece.assemble(self.info_table);
+            }
+            if !self.choke {
+                let requests = PeerConnection::next_requests(
+                    piece_index,
+                    piece_len,
+                    piece.block_len,
+                    self.pipeline_depth,
+                    &received,
+                    &mut requested,
+                );
+                for request in requests {
+                    self.send(request).await?;
+                }
+            }
+            match self.receive_decode().await? {
+                Some(PeerMessage::Unchoke) => self.choke = false,
+                Some(PeerMessage::Piece {
+                    index,
+                    begin,
+                    block,
+                }) if index == piece_index => {
+                    let len = block.len() as u64;
+                    if piece.insert(begin, block)? {
+                        self.downloaded += len;
+                    }
+                }
+                Some(PeerMessage::Choke) => {
+                    // Whatever was in flight has been dropped, ask again once unchoked. With the
+                    // Fast Extension the peer rejects each request it drops instead.
+                    self.choke = true;
+                    if !self.supports_fast() {
+                        requested = piece.received();
+                    }
+                    self.send(PeerMessage::Interested).await?;
+                }
+                // See `PeerConnection::handle_rejection`.
+                Some(PeerMessage::RejectRequest { index, begin, .. }) if index == piece_index => {
+                    if !self.choke {
+                        return Err(PeerError::RequestRejected { index });
+                    }
+                    let block_index = begin as usize / piece.block_len;
+                    if block_index < requested.len() {
+                        requested.set(block_index, false);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+async fn with_timeout<T>(
+    duration: Duration,
+    future: impl Future<Output = Result<T, PeerError>>,
+) -> Result<T, PeerError> {
+    timeout(duration, future)
+        .await
+        .map_err(|_| PeerError::PeerTimeout)?
+}
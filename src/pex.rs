@@ -0,0 +1,48 @@
+// Peer exchange (BEP 11), carried over the extension protocol (BEP 10) as `ut_pex`.
+// Peers that support it tell us which peers they connected to (`added`) and lost (`dropped`)
+// since their last message, about once a minute. Both are compact peer lists, `added6` and
+// `dropped6` hold the IPv6 ones. Private torrents only get their peers from the trackers, so PEX
+// stays off for them.
+
+use bencode::BencodeRef;
+
+use crate::peer::Peer;
+
+// Extended message id we ask peers to use when sending us ut_pex messages.
+pub(crate) const UT_PEX_ID: u8 = 1;
+
+// Our extended handshake, only advertising ut_pex.
+pub(crate) fn handshake_payload() -> Vec<u8> {
+    format!("d1:md6:ut_pexi{}eee", UT_PEX_ID).into_bytes()
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PexMessage {
+    pub added: Vec<Peer>,
+    pub dropped: Vec<Peer>,
+}
+
+impl PexMessage {
+    // None unless the payload is a dictionary, entries that aren't valid compact peers are
+    // skipped.
+    pub fn parse(payload: &[u8]) -> Option<Self> {
+        let (message, _) = BencodeRef::from_bytes(payload).ok()?;
+        if !matches!(message, BencodeRef::Dict(_)) {
+            return None;
+        }
+        let peers = |key: &str, entry_len: usize| {
+            message
+                .get(key)
+                .and_then(BencodeRef::as_bytes)
+                .unwrap_or_default()
+                .chunks(entry_len)
+                .filter_map(|entry| Peer::try_from(entry).ok())
+                .collect::<Vec<Peer>>()
+        };
+        let mut added = peers("added", 6);
+        added.extend(peers("added6", 18));
+        let mut dropped = peers("dropped", 6);
+        dropped.extend(peers("dropped6", 18));
+        Some(Self { added, dropped })
+    }
+}
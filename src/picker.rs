@@ -0,0 +1,73 @@
+// Decides which piece to download next. Rarest first is the default, getting the pieces few
+// peers have out of the way early keeps them from disappearing with their owners and spreads
+// the load across the swarm. Sequential is there for streaming, where order matters more.
+
+use crate::bitfield::Bitfield;
+
+pub trait PiecePicker: Send {
+    // A connected peer's pieces, reported again (removed then added) whenever they change.
+    fn peer_added(&mut self, bitfield: &Bitfield);
+    fn peer_removed(&mut self, bitfield: &Bitfield);
+    // A connected peer announced one more piece with a Have message.
+    fn peer_has(&mut self, index: u32);
+    // Which of the wanted pieces to get next from a peer that has the pieces in `bitfield`.
+    fn pick(&mut self, wanted: &[u32], bitfield: &Bitfield) -> Option<u32>;
+}
+
+// Lowest index first.
+pub struct Sequential;
+
+impl PiecePicker for Sequential {
+    fn peer_added(&mut self, _: &Bitfield) {}
+    fn peer_removed(&mut self, _: &Bitfield) {}
+    fn peer_has(&mut self, _: u32) {}
+    fn pick(&mut self, wanted: &[u32], bitfield: &Bitfield) -> Option<u32> {
+        wanted
+            .iter()
+            .copied()
+            .filter(|&index| bitfield.has_piece(index as usize))
+            .min()
+    }
+}
+
+// The piece the fewest connected peers have, ties go to whichever is wanted first.
+pub struct RarestFirst {
+    availability: Vec<usize>,
+}
+
+impl RarestFirst {
+    pub fn new(piece_count: usize) -> Self {
+        Self {
+            availability: vec![0; piece_count],
+        }
+    }
+}
+
+impl PiecePicker for RarestFirst {
+    fn peer_added(&mut self, bitfield: &Bitfield) {
+        for (i, count) in self.availability.iter_mut().enumerate() {
+            if bitfield.has_piece(i) {
+                *count += 1;
+            }
+        }
+    }
+    fn peer_removed(&mut self, bitfield: &Bitfield) {
+        for (i, count) in self.availability.iter_mut().enumerate() {
+            if bitfield.has_piece(i) {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+    fn peer_has(&mut self, index: u32) {
+        if let Some(count) = self.availability.get_mut(index as usize) {
+            *count += 1;
+        }
+    }
+    fn pick(&mut self, wanted: &[u32], bitfield: &Bitfield) -> Option<u32> {
+        wanted
+            .iter()
+            .copied()
+            .filter(|&index| bitfield.has_piece(index as usize))
+            .min_by_key(|&index| self.availability.get(index as usize).copied().unwrap_or(0))
+    }
+}
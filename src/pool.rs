@@ -0,0 +1,234 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::{Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::info;
+
+use crate::{
+    info_hash::InfoHash,
+    peer::{ConnectOptions, Peer, PeerConnection},
+    torrent::Info,
+};
+
+// A peer that could not be connected to is tried again this much later, times its failures.
+const CONNECT_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_CONNECT_ATTEMPTS: usize = 3;
+
+// Hands out connections to the peers of one torrent, at most `max_connections` of them open at
+// once. This is the only place connections are made, peers that fail to connect or handshake are
+// retried with a backoff and given up on after a few attempts. Connections handed back with
+// `checkin` are reused before any new one is opened.
+pub struct PeerPool<'a> {
+    info: &'a Info,
+    info_hash: InfoHash,
+    max_connections: usize,
+    connect_options: ConnectOptions,
+    state: Mutex<PoolState<'a>>,
+    // Signalled whenever a checkout might succeed where it failed before.
+    available: Condvar,
+}
+
+struct PoolState<'a> {
+    // Peers not connected to yet, with their failed attempts and when they may be tried again.
+    candidates: VecDeque<(Peer, usize, Instant)>,
+    idle: Vec<(Peer, PeerConnection<'a>)>,
+    // Idle and checked out connections.
+    open: usize,
+    // Every peer that is a candidate or has a connection, so trackers and PEX handing out the
+    // same peer don't get it a second connection.
+    known: HashSet<Peer>,
+    // Peer ids of the open connections. A client that connects to us does so from some port of
+    // its own rather than the one it listens on, only its id gives it away.
+    peer_ids: HashSet<[u8; 20]>,
+}
+
+impl<'a> PeerPool<'a> {
+    pub fn new(info: &'a Info, peers: Vec<Peer>, max_connections: usize) -> Self {
+        let pool = Self {
+            info,
+            info_hash: info.get_hash(),
+            max_connections,
+            connect_options: ConnectOptions::default(),
+            state: Mutex::new(PoolState {
+                candidates: VecDeque::new(),
+                idle: Vec::new(),
+                open: 0,
+                known: HashSet::new(),
+                peer_ids: HashSet::new(),
+            }),
+            available: Condvar::new(),
+        };
+        pool.add_peers(&peers);
+        pool
+    }
+
+    pub fn set_connect_options(&mut self, connect_options: ConnectOptions) {
+        self.connect_options = connect_options;
+    }
+
+    // More peers, say from a tracker reannounce. Ones already known are ignored.
+    pub fn add_peers(&self, peers: &[Peer]) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        for peer in peers {
+            if state.known.insert(peer.clone()) {
+                state.candidates.push_back((peer.clone(), 0, now));
+            }
+        }
+        self.available.notify_all();
+    }
+
+    // Blocks until peers or connections come in, a slot frees up, or `timeout` passes. For
+    // whoever got None from `checkout` but still needs a connection.
+    pub fn wait(&self, timeout: Duration) {
+        let state = self.state.lock().unwrap();
+        drop(self.available.wait_timeout(state, timeout).unwrap());
+    }
+
+    // An idle connection if there is one, otherwise a new one. None once every peer has been
+    // used up or the limit of open connections is reached.
+    pub fn checkout(&self) -> Option<(Peer, PeerConnection<'a>)> {
+        loop {
+            let (peer, attempts) = {
+                let mut state = self.state.lock().unwrap();
+                if let Some(idle) = state.idle.pop() {
+                    return Some(idle);
+                }
+                if state.open >= self.max_connections {
+                    return None;
+                }
+                let now = Instant::now();
+                match state.candidates.iter().position(|(_, _, at)| *at <= now) {
+                    Some(position) => {
+                        let (peer, attempts, _) = state.candidates.remove(position).unwrap();
+                        // Counted as open while connecting so the limit holds.
+                        state.open += 1;
+                        (peer, attempts)
+                    }
+                    None => {
+                        // Everyone left is backing off, wait for the first of them.
+                        let wait = state.candidates.iter().map(|(_, _, at)| *at - now).min()?;
+                        drop(state);
+                        thread::sleep(wait);
+                        continue;
+                    }
+                }
+            };
+            match peer.connect(self.info, &self.info_hash, &self.connect_options) {
+                Ok(connection) => {
+                    let mut state = self.state.lock().unwrap();
+                    if state.peer_ids.insert(*connection.peer_id()) {
+                        return Some((peer, connection));
+                    }
+                    // Connected to us already. The peer stays known so it isn't tried again.
+                    info!("Already connected to the client at {peer}");
+                    state.open -= 1;
+                }
+                Err(e) => {
+                    info!("Could not connect to {peer} : {e}");
+                    let mut state = self.state.lock().unwrap();
+                    state.open -= 1;
+                    if attempts + 1 < MAX_CONNECT_ATTEMPTS {
+                        let retry_at = Instant::now() + CONNECT_BACKOFF * (attempts as u32 + 1);
+                        state.candidates.push_back((peer, attempts + 1, retry_at));
+                    } else {
+                        state.known.remove(&peer);
+                    }
+                }
+            }
+        }
+    }
+
+    // A connection the peer opened to us. Dropped when we are already at the limit, or connected
+    // to the same client.
+    pub fn add_connection(&self, peer: Peer, connection: PeerConnection<'a>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.open >= self.max_connections || state.peer_ids.contains(connection.peer_id()) {
+            return false;
+        }
+        state.peer_ids.insert(*connection.peer_id());
+        state.open += 1;
+        state.known.insert(peer.clone());
+        state.idle.push((peer, connection));
+        self.available.notify_all();
+        true
+    }
+
+    // Hands a connection that is still good back for someone else to use.
+    pub fn checkin(&self, peer: Peer, connection: PeerConnection<'a>) {
+        self.state.lock().unwrap().idle.push((peer, connection));
+        self.available.notify_all();
+    }
+
+    // Drops a dead connection, freeing its slot for a connection to another peer. The peer may
+    // be added again later.
+    pub fn discard(&self, peer: &Peer, connection: PeerConnection<'a>) {
+        let mut state = self.state.lock().unwrap();
+        state.peer_ids.remove(connection.peer_id());
+        drop(connection);
+        state.open -= 1;
+        state.known.remove(peer);
+        self.available.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        net::{SocketAddr, TcpListener, TcpStream},
+    };
+
+    use super::*;
+
+    fn peer(port: u16) -> Peer {
+        Peer::from(SocketAddr::from(([127, 0, 0, 1], port)))
+    }
+
+    // The receiving end of a connection from a client with `peer_id`.
+    fn incoming<'a>(info: &'a Info, peer_id: [u8; 20]) -> (Peer, PeerConnection<'a>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut handshake = b"\x13BitTorrent protocol".to_vec();
+        handshake.extend_from_slice(&[0; 8]);
+        handshake.extend_from_slice(info.get_hash().as_bytes());
+        handshake.extend_from_slice(&peer_id);
+        client.write_all(&handshake).unwrap();
+        let (stream, addr) = listener.accept().unwrap();
+        let connection = PeerConnection::accept(stream, &|_| Some(info)).unwrap();
+        (Peer::from(addr), connection)
+    }
+
+    #[test]
+    fn the_same_peer_is_only_added_once() {
+        let info = Info::for_data(b"data", 16);
+        let pool = PeerPool::new(&info, vec![peer(1), peer(2), peer(1)], 5);
+        pool.add_peers(&[peer(2), peer(3)]);
+        let state = pool.state.lock().unwrap();
+        let candidates: Vec<_> = state.candidates.iter().map(|(peer, ..)| peer).collect();
+        assert_eq!(candidates, [&peer(1), &peer(2), &peer(3)]);
+    }
+
+    #[test]
+    fn incoming_connections_from_the_same_client_are_dropped() {
+        let info = Info::for_data(b"data", 16);
+        let pool = PeerPool::new(&info, Vec::new(), 5);
+        let (first, connection) = incoming(&info, [1; 20]);
+        assert!(pool.add_connection(first, connection));
+        // Same client from another port.
+        let (second, connection) = incoming(&info, [1; 20]);
+        assert!(!pool.add_connection(second, connection));
+        let (third, connection) = incoming(&info, [2; 20]);
+        assert!(pool.add_connection(third, connection));
+
+        // Once the first connection is gone the client may connect again.
+        let (peer, connection) = pool.checkout().unwrap();
+        let id = *connection.peer_id();
+        pool.discard(&peer, connection);
+        let (again, connection) = incoming(&info, id);
+        assert!(pool.add_connection(again, connection));
+    }
+}
@@ -0,0 +1,21 @@
+// Randomness for things that only need to differ between calls, like peer ids, transaction ids
+// and picking a peer to unchoke. Every RandomState is seeded differently, which saves pulling in
+// an RNG crate. Not for anything that has to be unpredictable.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+};
+
+pub(crate) fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+pub(crate) fn random_u32() -> u32 {
+    random_u64() as u32
+}
+
+// Somewhere in `0..len`, `len` must not be 0.
+pub(crate) fn random_index(len: usize) -> usize {
+    (random_u64() % len as u64) as usize
+}
@@ -0,0 +1,612 @@
+use std::{
+    fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Component, Path, PathBuf},
+    sync::{mpsc, Mutex},
+    thread,
+};
+
+use bit_vec::BitVec;
+use sha1_smol::Sha1;
+
+use crate::{
+    bitfield::Bitfield,
+    peer::PieceData,
+    torrent::{FileType, Info},
+};
+
+// Writes the ordered pieces of a torrent to disk under `root`.
+// In the single file case, the file is written to `root/name`, in the multiple file case, every
+// file is written to `root/name/path...`, pieces are allowed to straddle file boundaries.
+pub fn write_pieces(info: &Info, pieces: &[PieceData], root: &Path) -> Result<(), StorageError> {
+    let mut stream = pieces.iter().map(|piece| piece.piece.as_slice());
+    let mut current: &[u8] = &[];
+    for (path, length) in file_layout(info, root)? {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = fs::File::create(&path)?;
+        let mut remaining = length;
+        while remaining > 0 {
+            if current.is_empty() {
+                current = stream.next().ok_or(StorageError::MissingData)?;
+                continue;
+            }
+            let n = remaining.min(current.len());
+            file.write_all(&current[..n])?;
+            current = &current[n..];
+            remaining -= n;
+        }
+    }
+    Ok(())
+}
+
+// Resolves every file of the torrent to where it lives on disk and its length, in the order the
+// files appear in the piece stream.
+pub fn file_layout(info: &Info, root: &Path) -> Result<Vec<(PathBuf, usize)>, StorageError> {
+    layout_at(info, safe_join(root, &[info.name()])?)
+}
+
+// Same as file_layout, with `base` standing in for `root/name`.
+fn layout_at(info: &Info, base: PathBuf) -> Result<Vec<(PathBuf, usize)>, StorageError> {
+    match &info.file_type() {
+        FileType::SingleFile { length, .. } => Ok(vec![(base, *length)]),
+        FileType::MultiFile { files } => files
+            .iter()
+            .map(|file| Ok((safe_join(&base, &file.path)?, file.length)))
+            .collect(),
+    }
+}
+
+// Joins the components onto root, refusing anything that could escape it.
+fn safe_join(root: &Path, components: &[impl AsRef<str>]) -> Result<PathBuf, StorageError> {
+    if components.is_empty() {
+        return Err(StorageError::EmptyPath);
+    }
+    let mut path = root.to_path_buf();
+    for component in components {
+        let component = component.as_ref();
+        let mut parsed = Path::new(component).components();
+        match (parsed.next(), parsed.next()) {
+            (Some(Component::Normal(_)), None) if !component.contains(['/', '\\', '\0']) => {
+                path.push(component)
+            }
+            _ => return Err(StorageError::UnsafePath(component.to_string())),
+        }
+    }
+    Ok(path)
+}
+
+// Where pieces we already have are read from when peers request them.
+pub trait PieceStore: Sync {
+    fn has_piece(&self, index: u32) -> bool;
+    // None when we don't have the piece or the block is out of its bounds.
+    fn read_block(&self, index: u32, begin: u32, length: u32) -> Option<Vec<u8>>;
+}
+
+// Keeps completed pieces in memory, which is where downloads end up anyway.
+pub struct MemoryStore {
+    pieces: Vec<Option<Vec<u8>>>,
+}
+
+impl MemoryStore {
+    pub fn new(piece_count: usize) -> Self {
+        Self {
+            pieces: vec![None; piece_count],
+        }
+    }
+
+    pub fn insert(&mut self, piece: PieceData) {
+        if let Some(slot) = self.pieces.get_mut(piece.index as usize) {
+            *slot = Some(piece.piece);
+        }
+    }
+}
+
+impl PieceStore for MemoryStore {
+    fn has_piece(&self, index: u32) -> bool {
+        matches!(self.pieces.get(index as usize), Some(Some(_)))
+    }
+
+    fn read_block(&self, index: u32, begin: u32, length: u32) -> Option<Vec<u8>> {
+        let piece = self.pieces.get(index as usize)?.as_ref()?;
+        let begin = begin as usize;
+        let end = begin.checked_add(length as usize)?;
+        piece.get(begin..end).map(|block| block.to_vec())
+    }
+}
+
+// Keeps pieces in the torrent's files as they complete, so an interrupted download can pick up
+// where it stopped. Which pieces are complete is recorded in a `<name>.bt-resume` sidecar next to
+// the download, until it's finished.
+pub struct DiskStore<'a> {
+    info: &'a Info,
+    layout: Vec<(PathBuf, usize)>,
+    resume_path: PathBuf,
+    completed: Mutex<Bitfield>,
+}
+
+impl<'a> DiskStore<'a> {
+    // Pieces listed in the sidecar are checked against their hashes again. If the sidecar is
+    // missing, unreadable or any of them fail (the files were changed or truncated since),
+    // every piece on disk is verified instead.
+    pub fn open(info: &'a Info, root: &Path) -> Result<Self, StorageError> {
+        Self::open_at(info, &safe_join(root, &[info.name()])?)
+    }
+
+    // Same as open, with `base` standing in for `root/name`.
+    pub fn open_at(info: &'a Info, base: &Path) -> Result<Self, StorageError> {
+        let piece_count = info.pieces().len();
+        let mut resume_path = base.as_os_str().to_owned();
+        resume_path.push(".bt-resume");
+        let mut store = Self {
+            info,
+            layout: layout_at(info, base.to_path_buf())?,
+            resume_path: resume_path.into(),
+            completed: Mutex::new(Bitfield::new(piece_count)),
+        };
+        // Every file is there from the start, empty ones would otherwise never be written at all.
+        for (path, _) in &store.layout {
+            open_for_writing(path)?;
+        }
+        let recorded = match fs::read(&store.resume_path) {
+            Ok(bytes) if bytes.len() == piece_count.div_ceil(8) => {
+                Some(Bitfield::from_bytes(&bytes, piece_count))
+            }
+            _ => None,
+        };
+        let completed = match recorded {
+            Some(recorded)
+                if (0..piece_count)
+                    .filter(|&i| recorded.has_piece(i))
+                    .all(|i| verify_piece(info, &store.layout, i as u32)) =>
+            {
+                recorded
+            }
+            _ => {
+                let mut completed = Bitfield::new(piece_count);
+                let verified =
+                    (0..piece_count).filter(|&i| verify_piece(info, &store.layout, i as u32));
+                for i in verified {
+                    completed.set_piece(i);
+                }
+                completed
+            }
+        };
+        store.completed = Mutex::new(completed);
+        Ok(store)
+    }
+
+    pub fn missing_pieces(&self) -> Vec<u32> {
+        let completed = self.completed.lock().unwrap();
+        (0..self.info.pieces().len() as u32)
+            .filter(|&i| !completed.has_piece(i as usize))
+            .collect()
+    }
+
+    // Writes a verified piece in place and records it as complete.
+    pub fn write_piece(&self, piece: &PieceData) -> Result<(), StorageError> {
+        let span = piece_span(self.info, piece.index).ok_or(StorageError::MissingData)?;
+        if piece.piece.len() != span.len() {
+            return Err(StorageError::MissingData);
+        }
+        for (path, file_offset, range) in segments(&self.layout, span) {
+            let mut file = open_for_writing(path)?;
+            file.seek(SeekFrom::Start(file_offset as u64))?;
+            file.write_all(&piece.piece[range])?;
+        }
+        let mut completed = self.completed.lock().unwrap();
+        completed.set_piece(piece.index as usize);
+        // Written aside and renamed over, quitting halfway through never leaves a torn sidecar.
+        let mut staged = self.resume_path.clone().into_os_string();
+        staged.push(".tmp");
+        fs::write(&staged, completed.to_bytes())?;
+        fs::rename(&staged, &self.resume_path)?;
+        Ok(())
+    }
+
+    // Makes sure every piece written so far, and the sidecar listing them, is on disk and not
+    // just in the OS's cache.
+    pub fn flush(&self) -> Result<(), StorageError> {
+        let _completed = self.completed.lock().unwrap();
+        for (path, _) in &self.layout {
+            open_for_writing(path)?.sync_data()?;
+        }
+        match fs::File::open(&self.resume_path) {
+            Ok(file) => file.sync_all()?,
+            // Nothing was written yet.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        Ok(())
+    }
+
+    // Removes the sidecar once nothing is missing, the files alone say the download is done.
+    pub fn finish(&self) -> Result<(), StorageError> {
+        if !self.missing_pieces().is_empty() {
+            return Ok(());
+        }
+        match fs::remove_file(&self.resume_path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl PieceStore for DiskStore<'_> {
+    fn has_piece(&self, index: u32) -> bool {
+        self.completed.lock().unwrap().has_piece(index as usize)
+    }
+
+    fn read_block(&self, index: u32, begin: u32, length: u32) -> Option<Vec<u8>> {
+        if !self.has_piece(index) {
+            return None;
+        }
+        // Only the block is read, not the whole piece around it.
+        let piece = piece_span(self.info, index)?;
+        let start = piece.start.checked_add(begin as usize)?;
+        let end = start.checked_add(length as usize)?;
+        if end > piece.end {
+            return None;
+        }
+        read_span(&self.layout, start..end)
+    }
+}
+
+// Creates the file and its directories if they don't exist yet, keeping whatever is in it.
+fn open_for_writing(path: &Path) -> io::Result<fs::File> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+}
+
+// Where the download goes for an `--output` path. An existing directory (or a path ending with a
+// separator) gets the torrent's file or directory inside it, anything else is taken as the name
+// of the file itself, which only makes sense for single file torrents.
+pub fn output_base(info: &Info, output: &Path) -> Result<PathBuf, StorageError> {
+    let is_dir = output.is_dir() || output.as_os_str().to_string_lossy().ends_with(['/', '\\']);
+    match &info.file_type() {
+        _ if is_dir => safe_join(output, &[info.name()]),
+        FileType::SingleFile { .. } => Ok(output.to_path_buf()),
+        FileType::MultiFile { .. } => Err(StorageError::OutputNotADirectory(
+            output.display().to_string(),
+        )),
+    }
+}
+
+// Checks the data at `path` against every piece hash, `path` being the file itself for single
+// file torrents or the torrent's directory otherwise.
+pub fn verify_pieces(info: &Info, path: &Path) -> Result<Vec<bool>, StorageError> {
+    let layout = layout_at(info, path.to_path_buf())?;
+    Ok((0..info.pieces().len() as u32)
+        .map(|index| verify_piece(info, &layout, index))
+        .collect())
+}
+
+// Which pieces of the torrent are already under `root`, reading every file once from start to
+// end. Missing files and anything past the end of a short file count as absent, so the pieces
+// they touch come out unset.
+pub fn scan_pieces(info: &Info, root: &Path) -> Result<BitVec, StorageError> {
+    let mut present = BitVec::from_elem(info.pieces().len(), false);
+    stream_pieces(
+        &file_layout(info, root)?,
+        info.piece_length(),
+        |index, piece, complete| {
+            if let Some(hash) = info.pieces().get(index) {
+                present.set(index, complete && hash_piece(piece) == *hash);
+            }
+        },
+    )?;
+    Ok(present)
+}
+
+// The piece hashes of files about to become a torrent. They have to stay as they were when they
+// were listed, otherwise this fails with `MissingData`. The files are still read once in order,
+// the pieces are hashed on `threads` threads and every hash goes in its piece's place, so the
+// result is the same whatever the thread count.
+pub(crate) fn hash_files(
+    layout: &[(PathBuf, usize)],
+    piece_length: usize,
+    threads: usize,
+) -> Result<Vec<[u8; 20]>, StorageError> {
+    let total_length: usize = layout.iter().map(|(_, length)| length).sum();
+    let pieces = Mutex::new(vec![[0u8; 20]; total_length.div_ceil(piece_length)]);
+    let mut all_there = true;
+    // Bounded, so reading doesn't run away from hashing with the whole torrent in memory.
+    let (sender, receiver) = mpsc::sync_channel::<(usize, Vec<u8>)>(threads * 2);
+    let receiver = Mutex::new(receiver);
+    thread::scope(|scope| {
+        for _ in 0..threads.max(1) {
+            scope.spawn(|| loop {
+                let Ok((index, piece)) = receiver.lock().unwrap().recv() else {
+                    break;
+                };
+                let hash = hash_piece(&piece);
+                pieces.lock().unwrap()[index] = hash;
+            });
+        }
+        let streamed = stream_pieces(layout, piece_length, |index, piece, complete| {
+            all_there &= complete;
+            sender
+                .send((index, piece.to_vec()))
+                .expect("Hashing threads only stop once the sender is gone");
+        });
+        // Lets the hashing threads finish.
+        drop(sender);
+        streamed
+    })?;
+    if !all_there {
+        return Err(StorageError::MissingData);
+    }
+    Ok(pieces.into_inner().unwrap())
+}
+
+// Every file under `path` with its path relative to it and its length, ordered by path so the
+// same directory always makes the same torrent. A plain file is listed with an empty path.
+pub(crate) fn list_files(path: &Path) -> Result<Vec<(Vec<String>, PathBuf, usize)>, StorageError> {
+    let metadata = fs::metadata(path)?;
+    if !metadata.is_dir() {
+        return Ok(vec![(
+            Vec::new(),
+            path.to_path_buf(),
+            metadata.len() as usize,
+        )]);
+    }
+    let mut entries: Vec<_> = fs::read_dir(path)?.collect::<Result<_, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    let mut files = Vec::new();
+    for entry in entries {
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(|name| StorageError::NonUtf8Path(name.to_string_lossy().to_string()))?;
+        for (mut relative, full, length) in list_files(&entry.path())? {
+            relative.insert(0, name.clone());
+            files.push((relative, full, length));
+        }
+    }
+    Ok(files)
+}
+
+// Feeds the files joined together to `f` one piece at a time, with the piece's index and whether
+// all of it was there, reading every file once from start to end. Missing files and anything past
+// the end of a short file read as zeroes so the pieces after them keep their place.
+fn stream_pieces(
+    layout: &[(PathBuf, usize)],
+    piece_length: usize,
+    mut f: impl FnMut(usize, &[u8], bool),
+) -> Result<(), StorageError> {
+    let mut piece = Vec::with_capacity(piece_length);
+    let mut complete = true;
+    let mut index = 0;
+    for (path, length) in layout {
+        let mut file = fs::File::open(path)
+            .ok()
+            .map(|file| file.take(*length as u64));
+        let mut remaining = *length;
+        while remaining > 0 {
+            let want = remaining.min(piece_length - piece.len());
+            let start = piece.len();
+            if let Some(reader) = &mut file {
+                reader.take(want as u64).read_to_end(&mut piece)?;
+            }
+            if piece.len() - start < want {
+                complete = false;
+                file = None;
+                piece.resize(start + want, 0);
+            }
+            remaining -= want;
+            if piece.len() == piece_length {
+                f(index, &piece, complete);
+                piece.clear();
+                complete = true;
+                index += 1;
+            }
+        }
+    }
+    // The last piece is usually short.
+    if !piece.is_empty() {
+        f(index, &piece, complete);
+    }
+    Ok(())
+}
+
+fn hash_piece(piece: &[u8]) -> [u8; 20] {
+    let mut sha1 = Sha1::new();
+    sha1.update(piece);
+    sha1.digest().bytes()
+}
+
+// Checks every file that has an `md5sum` against it, files without one are skipped. Piece hashes
+// already cover everything, this is only for older torrents that also carry MD5 digests.
+pub fn verify_md5(info: &Info, path: &Path) -> Result<(), StorageError> {
+    let md5sums: Vec<Option<&String>> = match &info.file_type() {
+        FileType::SingleFile { md5sum, .. } => vec![md5sum.as_ref()],
+        FileType::MultiFile { files } => files.iter().map(|file| file.md5sum.as_ref()).collect(),
+    };
+    for ((file, _), md5sum) in layout_at(info, path.to_path_buf())?.iter().zip(md5sums) {
+        let Some(md5sum) = md5sum else {
+            continue;
+        };
+        let mut context = md5::Context::new();
+        io::copy(&mut fs::File::open(file)?, &mut context)?;
+        if !format!("{:x}", context.compute()).eq_ignore_ascii_case(md5sum) {
+            return Err(StorageError::Md5Mismatch(file.display().to_string()));
+        }
+    }
+    Ok(())
+}
+
+fn verify_piece(info: &Info, layout: &[(PathBuf, usize)], index: u32) -> bool {
+    let Some(piece) = read_piece(info, layout, index) else {
+        return false;
+    };
+    hash_piece(&piece) == info.pieces()[index as usize]
+}
+
+fn read_piece(info: &Info, layout: &[(PathBuf, usize)], index: u32) -> Option<Vec<u8>> {
+    read_span(layout, piece_span(info, index)?)
+}
+
+// Any range of the piece stream, from whichever files it covers.
+fn read_span(layout: &[(PathBuf, usize)], span: Range<usize>) -> Option<Vec<u8>> {
+    let mut bytes = vec![0u8; span.len()];
+    for (path, file_offset, range) in segments(layout, span) {
+        let mut file = fs::File::open(path).ok()?;
+        file.seek(SeekFrom::Start(file_offset as u64)).ok()?;
+        file.read_exact(&mut bytes[range]).ok()?;
+    }
+    Some(bytes)
+}
+
+// Splits a range of the piece stream into the files it covers, as the file, the offset in that
+// file and the matching range relative to the start of the span.
+fn segments(layout: &[(PathBuf, usize)], span: Range<usize>) -> Vec<(&Path, usize, Range<usize>)> {
+    let mut segments = Vec::new();
+    let mut file_start = 0;
+    for (path, length) in layout {
+        let file_end = file_start + length;
+        let start = span.start.max(file_start);
+        let end = span.end.min(file_end);
+        if start < end {
+            segments.push((
+                path.as_path(),
+                start - file_start,
+                start - span.start..end - span.start,
+            ));
+        }
+        file_start = file_end;
+    }
+    segments
+}
+
+// Where a piece lies in the stream of all the files joined together.
+fn piece_span(info: &Info, index: u32) -> Option<Range<usize>> {
+    if index as usize >= info.pieces().len() {
+        return None;
+    }
+    let start = info.piece_offset(index);
+    Some(start..start + info.piece_len(index))
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum StorageError {
+    #[error("File has an empty path.")]
+    EmptyPath,
+    #[error("Path component escapes the download directory. (`{0}`)")]
+    UnsafePath(String),
+    #[error("Multi file torrents need a directory to download to. (`{0}`)")]
+    OutputNotADirectory(String),
+    #[error("File does not match its md5sum. (`{0}`)")]
+    Md5Mismatch(String),
+    #[error("File name is not valid UTF-8. (`{0}`)")]
+    NonUtf8Path(String),
+    #[error("Not enough piece data to fill every file.")]
+    MissingData,
+    #[error("Writing to disk failed.")]
+    Io(#[from] std::io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::File;
+
+    // A fresh directory for one test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("bit-torrust-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // `data` split into files of these lengths, in pieces of 16 bytes.
+    fn multi_file_info(data: &[u8], lengths: &[usize]) -> Info {
+        let mut info = Info::for_data(data, 16);
+        let files = lengths.iter().enumerate().map(|(i, &length)| File {
+            length,
+            path: vec![format!("file{i}")],
+            md5sum: None,
+        });
+        info.set_file_type(FileType::MultiFile {
+            files: files.collect(),
+        });
+        info
+    }
+
+    fn write_all(store: &DiskStore, data: &[u8], piece_length: usize) {
+        for (index, piece) in data.chunks(piece_length).enumerate() {
+            let piece = PieceData {
+                index: index as u32,
+                piece: piece.to_vec(),
+            };
+            store.write_piece(&piece).unwrap();
+        }
+    }
+
+    #[test]
+    fn empty_files_are_created() {
+        let data = b"0123456789abcdefghij";
+        let info = multi_file_info(data, &[10, 0, 10]);
+        let base = temp_dir("empty-files").join("test");
+        let store = DiskStore::open_at(&info, &base).unwrap();
+        assert_eq!(fs::metadata(base.join("file1")).unwrap().len(), 0);
+        write_all(&store, data, 16);
+        assert_eq!(fs::read(base.join("file0")).unwrap(), &data[..10]);
+        assert_eq!(fs::read(base.join("file1")).unwrap(), b"");
+        assert_eq!(fs::read(base.join("file2")).unwrap(), &data[10..]);
+        assert!(store.missing_pieces().is_empty());
+        fs::remove_dir_all(base.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn sidecar_is_kept_until_the_download_is_done() {
+        let data = b"0123456789abcdefghij";
+        let info = multi_file_info(data, &[10, 0, 10]);
+        let base = temp_dir("sidecar").join("test");
+        let resume_path = base.with_file_name("test.bt-resume");
+        let store = DiskStore::open_at(&info, &base).unwrap();
+        store.flush().unwrap();
+        let first = PieceData {
+            index: 0,
+            piece: data[..16].to_vec(),
+        };
+        store.write_piece(&first).unwrap();
+        store.flush().unwrap();
+        store.finish().unwrap();
+        assert!(resume_path.exists());
+        // Picked up again by the next run.
+        let store = DiskStore::open_at(&info, &base).unwrap();
+        assert_eq!(store.missing_pieces(), [1]);
+        write_all(&store, data, 16);
+        store.finish().unwrap();
+        assert!(!resume_path.exists());
+        assert!(!base.with_file_name("test.bt-resume.tmp").exists());
+        // Without the sidecar the files are verified instead.
+        let store = DiskStore::open_at(&info, &base).unwrap();
+        assert!(store.missing_pieces().is_empty());
+        fs::remove_dir_all(base.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn blocks_are_read_across_files() {
+        let data = b"0123456789abcdefghij";
+        let info = multi_file_info(data, &[10, 0, 10]);
+        let base = temp_dir("read-block").join("test");
+        let store = DiskStore::open_at(&info, &base).unwrap();
+        assert_eq!(store.read_block(0, 0, 4), None);
+        write_all(&store, data, 16);
+        assert_eq!(store.read_block(0, 8, 4).unwrap(), b"89ab");
+        assert_eq!(store.read_block(1, 0, 4).unwrap(), b"ghij");
+        // Past the end of the piece.
+        assert_eq!(store.read_block(1, 2, 4), None);
+        assert_eq!(store.read_block(0, u32::MAX, 4), None);
+        fs::remove_dir_all(base.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,71 @@
+// Token bucket rate limiting, shared by every connection going the same direction.
+// Tokens are bytes and come in at the limit's rate. Transfers take what they need up front, going
+// into debt if they have to, and then sleep until the bucket is back at zero. Waiting callers
+// each add to the debt, so together they never go faster than the limit.
+
+use std::{
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+// How much an idle bucket saves up, short so a burst barely shows in the rate.
+const BURST: Duration = Duration::from_millis(100);
+
+pub struct RateLimiter {
+    // Bytes per second, 0 is unlimited.
+    rate: u64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    // Negative while transfers are waiting their turn.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self {
+            rate: bytes_per_second,
+            bucket: Mutex::new(Bucket {
+                tokens: 0.0,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(0)
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    // Blocks until `bytes` more fit under the limit.
+    pub fn consume(&self, bytes: usize) {
+        if self.rate == 0 {
+            return;
+        }
+        let rate = self.rate as f64;
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let refill = (now - bucket.last_refill).as_secs_f64() * rate;
+            bucket.tokens = (bucket.tokens + refill).min(BURST.as_secs_f64() * rate);
+            bucket.last_refill = now;
+            bucket.tokens -= bytes as f64;
+            (-bucket.tokens).max(0.0) / rate
+        };
+        if wait > 0.0 {
+            thread::sleep(Duration::from_secs_f64(wait));
+        }
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::unlimited()
+    }
+}
@@ -24,36 +24,105 @@
 
 // path - A list of UTF-8 encoded strings corresponding to subdirectory names, the last of which is the actual file name (a zero length list is an error case).
 
-use std::{error::Error, io::Read, path::Path};
+use std::{
+    error::Error,
+    io::Read,
+    path::Path,
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use bencode::{Bencode, BencodeDictValues};
-use sha1_smol::{Digest, Sha1};
+use crate::{
+    download::{self, DownloadOptions, DownloadSummary},
+    info_hash::InfoHash,
+    listener,
+    storage::{self, StorageError},
+    tracker::TrackerService,
+};
+
+use bencode::{Bencode, BencodeDictValues, BencodeRef, IndexMap};
+use bit_vec::BitVec;
+use sha1_smol::Sha1;
 
 // In the single file case, the name key is the name of a file, in the muliple file case, it's the name of a directory.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Torrent {
     pub announce: Option<String>,
+    // Tiers of backup trackers (BEP 12), empty when the torrent only has `announce`.
+    pub announce_list: Vec<Vec<String>>,
     pub info: Info,
+    // Optional, informational keys.
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    // Seconds since the UNIX epoch.
+    pub creation_date: Option<i64>,
+    // Encoding of the strings in the info dictionary, for old torrents that are not UTF-8.
+    pub encoding: Option<String>,
+    // Encoded values of the keys we don't model (`url-list`, `nodes`, ...), so writing the torrent
+    // back out doesn't lose them.
+    extra: Vec<(String, Vec<u8>)>,
 }
 
-#[derive(Debug)]
+// The keys Torrent has fields for, everything else ends up in `extra`.
+const TORRENT_KEYS: [&str; 7] = [
+    "announce",
+    "announce-list",
+    "info",
+    "comment",
+    "created by",
+    "creation date",
+    "encoding",
+];
+
+// The optional parts of a torrent made with `Torrent::create`.
+#[derive(Debug, Clone, Default)]
+pub struct CreateOptions {
+    // Backup trackers, see `Torrent::announce_list`.
+    pub announce_list: Vec<Vec<String>>,
+    pub comment: Option<String>,
+    pub private: bool,
+    // See `Info::source`.
+    pub source: Option<String>,
+    // Threads hashing pieces, 0 uses one per core.
+    pub hash_threads: usize,
+}
+
+#[derive(Debug, Clone)]
+// Fields are behind accessors so nothing can change them without `raw_bytes` going with it,
+// otherwise the info-hash would stay that of the dictionary the info was parsed from.
 pub struct Info {
-    pub file_type: FileType,
-    pub name: String,
-    pub piece_length: usize,
-    pub pieces: Vec<[u8; 20]>,
+    file_type: FileType,
+    name: String,
+    piece_length: usize,
+    pieces: Vec<[u8; 20]>,
+    // Private torrents (BEP 27) only get peers from their trackers, so DHT, PEX and other peer
+    // discovery must stay off for them.
+    private: bool,
+    // Set by private trackers so the same files uploaded to several of them still hash
+    // differently. Part of the info dictionary, so it changes the info-hash.
+    source: Option<String>,
+    // The exact bencoded info dictionary this was parsed from, so the info-hash also covers
+    // keys we do not model (private, source, ...). Dropped by every setter.
+    raw_bytes: Option<Vec<u8>>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum FileType {
-    MultiFile { files: Vec<File> },
-    SingleFile { length: usize },
+    MultiFile {
+        files: Vec<File>,
+    },
+    // `md5sum` is an optional hex digest of the whole file, only older torrents have it.
+    SingleFile {
+        length: usize,
+        md5sum: Option<String>,
+    },
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct File {
     pub length: usize,
     pub path: Vec<String>,
+    pub md5sum: Option<String>,
 }
 
 impl TryFrom<Bencode> for Torrent {
@@ -62,10 +131,32 @@ impl TryFrom<Bencode> for Torrent {
     fn try_from(value: Bencode) -> Result<Self, Self::Error> {
         match value {
             Bencode::Dict(torrent_table) => {
-                let announce = torrent_table.get("announce").and_then(|val| match val {
-                    BencodeDictValues::Bencode(Bencode::String(s)) => Some(s.clone()),
+                let get_string = |key| match torrent_table.get(key) {
+                    Some(BencodeDictValues::Bencode(Bencode::String(s))) => Some(s.clone()),
                     _ => None,
-                });
+                };
+                let announce = get_string("announce");
+
+                let announce_list = match torrent_table.get("announce-list") {
+                    Some(BencodeDictValues::Bencode(Bencode::List(tiers))) => tiers
+                        .iter()
+                        .map(|tier| match tier {
+                            Bencode::List(urls) => urls
+                                .iter()
+                                .map(|url| match url {
+                                    Bencode::String(s) => Ok(s.clone()),
+                                    _ => Err(TorrentError::InvalidTorrentFile(
+                                        "Invalid tracker url in announce-list".to_string(),
+                                    )),
+                                })
+                                .collect::<Result<Vec<String>, TorrentError>>(),
+                            _ => Err(TorrentError::InvalidTorrentFile(
+                                "Invalid announce-list tier".to_string(),
+                            )),
+                        })
+                        .collect::<Result<Vec<_>, TorrentError>>()?,
+                    _ => Vec::new(),
+                };
 
                 let info = match torrent_table.get("info") {
                     Some(BencodeDictValues::Bencode(info_table)) => Info::parse_info(info_table),
@@ -74,7 +165,34 @@ impl TryFrom<Bencode> for Torrent {
                     )),
                 }?;
 
-                Ok(Self { announce, info })
+                Ok(Self {
+                    announce,
+                    announce_list,
+                    info,
+                    comment: get_string("comment"),
+                    created_by: get_string("created by"),
+                    creation_date: match torrent_table.get("creation date") {
+                        Some(BencodeDictValues::Bencode(Bencode::Number(date))) => Some(*date),
+                        _ => None,
+                    },
+                    encoding: get_string("encoding"),
+                    extra: torrent_table
+                        .iter()
+                        .filter(|(key, _)| !TORRENT_KEYS.contains(&key.as_str()))
+                        .filter_map(|(key, value)| match value {
+                            BencodeDictValues::Bencode(value) => Some((key, value)),
+                            BencodeDictValues::Bytes(_) => None,
+                        })
+                        .map(|(key, value)| {
+                            let encoded = value.to_bytes().map_err(|_| {
+                                TorrentError::InvalidTorrentFile(format!(
+                                    "`{key}` could not be encoded."
+                                ))
+                            })?;
+                            Ok((key.clone(), encoded))
+                        })
+                        .collect::<Result<_, TorrentError>>()?,
+                })
             }
             _ => Err(TorrentError::InvalidTorrentFile(
                 "Torrent metainfo file should have a bencoded dictionary.".to_string(),
@@ -85,96 +203,370 @@ impl TryFrom<Bencode> for Torrent {
 
 impl Torrent {
     pub fn from_file(file_path: &Path) -> Result<Self, Box<dyn Error>> {
-        // TODO: Buffered reads?
-        let mut f = std::fs::File::open(file_path)?;
+        Self::from_reader(std::fs::File::open(file_path)?)
+    }
+
+    // Reads until the end, e.g. stdin or a response body.
+    pub fn from_reader<R: Read>(mut reader: R) -> Result<Self, Box<dyn Error>> {
         let mut buffer = Vec::new();
+        reader.read_to_end(&mut buffer)?;
+        Self::from_bytes(&buffer)
+    }
+
+    // Nothing but whitespace may follow the torrent, which catches files that were concatenated
+    // or cut short into something that still decodes.
+    pub fn from_bytes(encoded_bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let (torrent, rest) = Self::decode(encoded_bytes)?;
+        if !rest.iter().all(u8::is_ascii_whitespace) {
+            return Err(Box::new(TorrentError::TrailingData {
+                offset: encoded_bytes.len() - rest.len(),
+            }));
+        }
+        Ok(torrent)
+    }
+
+    // Same as from_bytes, but whatever follows the torrent is ignored.
+    pub fn from_bytes_lenient(encoded_bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        Ok(Self::decode(encoded_bytes)?.0)
+    }
 
-        // read the whole file
-        f.read_to_end(&mut buffer)?;
-        let (values, _) = Bencode::from_bytes(&buffer, |s| match s {
-            "pieces" => Some(20),
+    fn decode(encoded_bytes: &[u8]) -> Result<(Self, &[u8]), Box<dyn Error>> {
+        let (values, rest) = Bencode::from_bytes_strict(encoded_bytes, |path| match path {
+            ["info", "pieces"] => Some(20),
             _ => None,
         })?;
-        Ok(Torrent::try_from(values)?)
+        let mut torrent = Torrent::try_from(values)?;
+        // Hashed as it is in the file, however the encoder would have written it.
+        torrent.info.raw_bytes = raw_dict_value(encoded_bytes, "info").map(<[u8]>::to_vec);
+        Ok((torrent, rest))
     }
 
+    // Makes a new torrent of the file or directory at `path`, hashing all of it. Without a
+    // `piece_length`, one is picked from the total size, see `default_piece_length`. Directories
+    // are walked in path order, so the same files always give the same info hash.
+    pub fn create(
+        path: &Path,
+        piece_length: Option<usize>,
+        announce: Option<String>,
+        options: &CreateOptions,
+    ) -> Result<Self, Box<dyn Error>> {
+        let path = path.canonicalize()?;
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| StorageError::NonUtf8Path(path.display().to_string()))?
+            .to_string();
+        let listed = storage::list_files(&path)?;
+        let total_length: usize = listed.iter().map(|(_, _, length)| length).sum();
+        let piece_length = piece_length.unwrap_or_else(|| default_piece_length(total_length));
+        if piece_length == 0 || total_length == 0 {
+            return Err(Box::new(TorrentError::InvalidTorrentFile(
+                "Torrents need some data and a piece length above 0.".to_string(),
+            )));
+        }
+        let layout: Vec<_> = listed
+            .iter()
+            .map(|(_, full, length)| (full.clone(), *length))
+            .collect();
+        let threads = match options.hash_threads {
+            0 => thread::available_parallelism().map_or(1, |threads| threads.get()),
+            threads => threads,
+        };
+        let pieces = storage::hash_files(&layout, piece_length, threads)?;
+        let file_type = if path.is_dir() {
+            let files = listed
+                .into_iter()
+                .map(|(relative, _, length)| File {
+                    length,
+                    path: relative,
+                    md5sum: None,
+                })
+                .collect();
+            FileType::MultiFile { files }
+        } else {
+            FileType::SingleFile {
+                length: total_length,
+                md5sum: None,
+            }
+        };
+        let creation_date = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs() as i64)
+            .ok();
+        Ok(Self {
+            announce,
+            announce_list: options.announce_list.clone(),
+            info: Info {
+                file_type,
+                name,
+                piece_length,
+                pieces,
+                private: options.private,
+                source: options.source.clone(),
+                raw_bytes: None,
+            },
+            comment: options.comment.clone(),
+            created_by: Some(format!("bit-torrust {}", env!("CARGO_PKG_VERSION"))),
+            creation_date,
+            encoding: None,
+            extra: Vec::new(),
+        })
+    }
+
+    // The metainfo file, with the info dictionary exactly as `Info::to_bytes` has it and keys
+    // sorted as canonical bencode wants. Keys read from a torrent that have no field here are
+    // written back as they were.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut entries = Vec::new();
+        if let Some(announce) = &self.announce {
+            entries.push(("announce".to_string(), Bencode::from(announce.as_str())));
+        }
+        if !self.announce_list.is_empty() {
+            let tiers: Vec<Bencode> = self
+                .announce_list
+                .iter()
+                .map(|tier| {
+                    let urls = tier.iter().map(|url| Bencode::from(url.as_str()));
+                    Bencode::from(urls.collect::<Vec<_>>())
+                })
+                .collect();
+            entries.push(("announce-list".to_string(), Bencode::from(tiers)));
+        }
+        let strings = [
+            ("comment", &self.comment),
+            ("created by", &self.created_by),
+            ("encoding", &self.encoding),
+        ];
+        for (key, value) in strings {
+            if let Some(value) = value {
+                entries.push((key.to_string(), Bencode::from(value.as_str())));
+            }
+        }
+        if let Some(creation_date) = self.creation_date {
+            entries.push(("creation date".to_string(), Bencode::from(creation_date)));
+        }
+        let mut encoded: Vec<(String, Vec<u8>)> = entries
+            .into_iter()
+            .map(|(key, value)| {
+                let value = value
+                    .to_bytes()
+                    .expect("Encoding built values should not fail");
+                (key, value)
+            })
+            .collect();
+        // Copied in as bytes rather than decoded again, which could lose what the info-hash
+        // covers, e.g. keys a lenient read let through.
+        encoded.push(("info".to_string(), self.info.to_bytes()));
+        encoded.extend(self.extra.iter().cloned());
+        encoded.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let mut bytes = vec![b'd'];
+        for (key, value) in encoded {
+            bytes.extend(format!("{}:{}", key.len(), key).as_bytes());
+            bytes.extend(value);
+        }
+        bytes.push(b'e');
+        bytes
+    }
+
+    // Everything from asking the trackers for peers to having the files on disk.
+    pub fn download(
+        &self,
+        output: &Path,
+        options: DownloadOptions,
+    ) -> Result<DownloadSummary, Box<dyn Error>> {
+        // Held for the whole download so the port we announce stays ours.
+        let listener = listener::bind(options.ports.clone())?;
+        let port = listener.local_addr()?.port();
+        let mut tracker_service = options
+            .peers
+            .is_empty()
+            .then(|| TrackerService::new(port, self));
+        download::download_to(
+            &self.info,
+            &listener,
+            tracker_service.as_mut(),
+            output,
+            &options,
+        )
+    }
+}
+
+impl Info {
+    pub fn file_type(&self) -> &FileType {
+        &self.file_type
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn piece_length(&self) -> usize {
+        self.piece_length
+    }
+
+    pub fn pieces(&self) -> &[[u8; 20]] {
+        &self.pieces
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.private
+    }
+
+    pub fn source(&self) -> Option<&str> {
+        self.source.as_deref()
+    }
+
+    // The setters change what the info dictionary encodes to, and so the info-hash. Keys we
+    // don't model are lost along with the bytes the info was parsed from.
+    pub fn set_file_type(&mut self, file_type: FileType) {
+        self.file_type = file_type;
+        self.raw_bytes = None;
+    }
+
+    pub fn set_name(&mut self, name: String) {
+        self.name = name;
+        self.raw_bytes = None;
+    }
+
+    pub fn set_private(&mut self, private: bool) {
+        self.private = private;
+        self.raw_bytes = None;
+    }
+
+    pub fn set_source(&mut self, source: Option<String>) {
+        self.source = source;
+        self.raw_bytes = None;
+    }
+
+    // Decodes a bare bencoded info dictionary, as fetched from peers for magnet links.
     pub fn from_bytes(encoded_bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
-        let (values, _) = Bencode::from_bytes(encoded_bytes, |s| match s {
-            "pieces" => Some(20),
+        let (values, rest) = Bencode::from_bytes_strict(encoded_bytes, |path| match path {
+            // A bare info dictionary, `pieces` is at the top.
+            ["pieces"] => Some(20),
             _ => None,
         })?;
-        Ok(Torrent::try_from(values)?)
+        let mut info = Info::parse_info(&values)?;
+        info.raw_bytes = Some(encoded_bytes[..encoded_bytes.len() - rest.len()].to_vec());
+        Ok(info)
     }
-}
 
-impl Info {
     pub fn to_bytes(&self) -> Vec<u8> {
-        let file_type_bytes = self.file_type.to_bytes();
-        let name_bytes = format!("{}:{}", self.name.len(), self.name)
-            .as_bytes()
-            .to_owned();
-        let piece_length_bytes = format!("i{}e", self.piece_length).as_bytes().to_owned();
-
-        let mut res: Vec<u8> = Vec::new();
-        res.push(b'd');
-        res.extend(file_type_bytes);
-
-        res.extend("4:name".as_bytes());
-        res.extend(name_bytes);
-        res.extend("12:piece length".as_bytes());
-        res.extend(piece_length_bytes);
-        res.extend("6:pieces".as_bytes());
-        res.extend(format!("{}:", self.pieces.len() * 20).as_bytes().to_owned());
-        res.extend(self.pieces.iter().flatten());
-        res.push(b'e');
-        res
-    }
-    pub fn get_hash(&self) -> Digest {
+        if let Some(raw_bytes) = &self.raw_bytes {
+            return raw_bytes.clone();
+        }
+        let (file_key, file_value) = self.file_type.to_bencode();
+        let pieces: Vec<u8> = self.pieces.iter().flatten().copied().collect();
+        let mut entries = vec![
+            (file_key.to_string(), file_value),
+            ("name".to_string(), Bencode::from(self.name.as_str())),
+            (
+                "piece length".to_string(),
+                Bencode::from(self.piece_length as i64),
+            ),
+            ("pieces".to_string(), Bencode::from(pieces)),
+        ];
+        // Only written when set, public torrents don't carry the key at all.
+        if self.private {
+            entries.push(("private".to_string(), Bencode::from(1)));
+        }
+        if let Some(source) = &self.source {
+            entries.push(("source".to_string(), Bencode::from(source.as_str())));
+        }
+        if let FileType::SingleFile {
+            md5sum: Some(md5sum),
+            ..
+        } = &self.file_type
+        {
+            entries.push(("md5sum".to_string(), Bencode::from(md5sum.as_str())));
+        }
+        Bencode::dict(entries)
+            .to_bytes()
+            .expect("Encoding built values should not fail")
+    }
+    pub fn get_hash(&self) -> InfoHash {
         let mut sha1 = Sha1::new();
         sha1.update(&self.to_bytes());
-        sha1.digest()
-    }
-
-    pub fn get_url_encoded_hash(&self) -> String {
-        let hash = hex::encode(self.get_hash().bytes());
-        hash.chars()
-            .enumerate()
-            .fold(String::with_capacity(hash.len()), |mut acc, (i, chr)| {
-                if i % 2 == 0 {
-                    acc.push('%');
-                }
-                acc.push(chr);
-                acc
-            })
+        InfoHash::from(sha1.digest().bytes())
     }
 
-    pub fn get_file_length(&self) -> usize {
+    // Length of all the files joined together.
+    pub fn total_length(&self) -> usize {
         match &self.file_type {
             FileType::MultiFile { files } => files.iter().map(|f| f.length).sum(),
-            FileType::SingleFile { length } => *length,
+            FileType::SingleFile { length, .. } => *length,
+        }
+    }
+
+    pub fn get_file_length(&self) -> usize {
+        self.total_length()
+    }
+
+    // Where the piece starts in the stream of all the files joined together.
+    pub fn piece_offset(&self, index: u32) -> usize {
+        self.piece_length * index as usize
+    }
+
+    // Which pieces are already on disk under `root` (at `root/name`, like a download), for
+    // seeding or resuming from files that came from elsewhere. Files that can't be laid out
+    // safely count as missing.
+    pub fn scan_existing(&self, root: &Path) -> BitVec {
+        storage::scan_pieces(self, root)
+            .unwrap_or_else(|_| BitVec::from_elem(self.pieces.len(), false))
+    }
+
+    // Every piece is full sized except possibly the last one, pieces past the end are empty.
+    pub fn piece_len(&self, index: u32) -> usize {
+        self.total_length()
+            .saturating_sub(self.piece_offset(index))
+            .min(self.piece_length)
+    }
+}
+#[cfg(test)]
+impl Info {
+    // A single file torrent over `data`, for tests.
+    pub(crate) fn for_data(data: &[u8], piece_length: usize) -> Self {
+        Info {
+            file_type: FileType::SingleFile {
+                length: data.len(),
+                md5sum: None,
+            },
+            name: "test".to_string(),
+            piece_length,
+            pieces: data
+                .chunks(piece_length)
+                .map(|piece| Sha1::from(piece).digest().bytes())
+                .collect(),
+            private: false,
+            source: None,
+            raw_bytes: None,
         }
     }
 }
 impl FileType {
-    fn to_bytes(&self) -> Vec<u8> {
+    // The key and value this takes up in the info dictionary.
+    fn to_bencode(&self) -> (&'static str, Bencode) {
         match self {
-            FileType::MultiFile { files } => [b'l']
-                .iter()
-                .copied()
-                .chain(files.iter().flat_map(|file| {
-                    let mut file_vec = format!("6:lengthi{}e", file.length).as_bytes().to_owned();
-                    file_vec.extend(
-                        file.path
-                            .iter()
-                            .flat_map(|s| format!("4:path{}:{}", s.len(), s).as_bytes().to_owned()),
-                    );
-                    file_vec
-                }))
-                .chain([b'e'].iter().copied())
-                .collect(),
-            FileType::SingleFile { length } => {
-                format!("6:lengthi{}e", length).as_bytes().to_owned()
-            }
+            FileType::MultiFile { files } => (
+                "files",
+                Bencode::from(
+                    files
+                        .iter()
+                        .map(|file| {
+                            let path = file.path.iter().map(|s| Bencode::from(s.as_str()));
+                            let mut entries = vec![
+                                ("length".to_string(), Bencode::from(file.length as i64)),
+                                ("path".to_string(), Bencode::from(path.collect::<Vec<_>>())),
+                            ];
+                            if let Some(md5sum) = &file.md5sum {
+                                let md5sum = Bencode::from(md5sum.as_str());
+                                entries.push(("md5sum".to_string(), md5sum));
+                            }
+                            Bencode::dict(entries)
+                        })
+                        .collect::<Vec<Bencode>>(),
+                ),
+            ),
+            FileType::SingleFile { length, .. } => ("length", Bencode::from(*length as i64)),
         }
     }
 }
@@ -200,20 +592,27 @@ impl Info {
                 "Should have advisory name".to_string(),
             ))?;
 
-        let piece_length = info_table
-            .get("piece length")
-            .and_then(|val| match val {
-                BencodeDictValues::Bencode(Bencode::Number(i)) => Some(*i as usize),
-                _ => None,
-            })
-            .ok_or(TorrentError::InvalidTorrentFile(
-                "Should have piece length information.".to_string(),
-            ))?;
+        let piece_length = match info_table.get("piece length") {
+            // Pieces of no bytes would never get anywhere.
+            Some(BencodeDictValues::Bencode(Bencode::Number(i))) => usize::try_from(*i)
+                .ok()
+                .filter(|&length| length > 0)
+                .ok_or_else(|| {
+                    TorrentError::InvalidTorrentFile(format!(
+                        "Piece length should be positive, not {i}."
+                    ))
+                })?,
+            _ => {
+                return Err(TorrentError::InvalidTorrentFile(
+                    "Should have piece length information.".to_string(),
+                ))
+            }
+        };
 
         let pieces = match info_table.get("pieces") {
             Some(BencodeDictValues::Bytes(bytez)) => {
                 let mut result: Vec<[u8; 20]> = Vec::new();
-                bytez.into_iter().try_for_each(|vec_of_bytes| {
+                bytez.iter().try_for_each(|vec_of_bytes| {
                     if vec_of_bytes.len() != 20 {
                         return Err(TorrentError::InvalidTorrentFile(
                             "Invalid file hash.".to_string(),
@@ -230,12 +629,41 @@ impl Info {
             )),
         }?;
 
-        Ok(Self {
+        // Only a stand-in for when there are no original bytes, re-encoding normalises things like
+        // string lengths with leading zeroes and would change the info-hash.
+        let raw_bytes = value.to_bytes().map_err(|_| {
+            TorrentError::InvalidTorrentFile("Info dictionary could not be encoded.".to_string())
+        })?;
+
+        let private = matches!(
+            info_table.get("private"),
+            Some(BencodeDictValues::Bencode(Bencode::Number(1)))
+        );
+        let source = match info_table.get("source") {
+            Some(BencodeDictValues::Bencode(Bencode::String(s))) => Some(s.clone()),
+            _ => None,
+        };
+
+        let info = Self {
             file_type,
             name,
             piece_length,
             pieces,
-        })
+            private,
+            source,
+            raw_bytes: Some(raw_bytes),
+        };
+        // One hash for every piece the files make up, the last one possibly short.
+        let expected = info.total_length().div_ceil(info.piece_length);
+        if info.pieces.len() != expected {
+            return Err(TorrentError::InvalidTorrentFile(format!(
+                "Expected {expected} piece hashes for {} bytes in pieces of {}, found {}.",
+                info.total_length(),
+                info.piece_length,
+                info.pieces.len()
+            )));
+        }
+        Ok(info)
     }
     fn resolve_file_type(value: &Bencode) -> Result<FileType, TorrentError> {
         let info_table = match value {
@@ -246,24 +674,33 @@ impl Info {
                 ))
             }
         };
+        // The spec allows exactly one of the two, not both or neither.
+        if info_table.contains_key("length") && info_table.contains_key("files") {
+            return Err(TorrentError::InvalidTorrentFile(
+                "Info has both `length` and `files`, only one of them is allowed.".to_string(),
+            ));
+        }
         let file_type;
         // Check file mode
         if let Some(BencodeDictValues::Bencode(Bencode::Number(x))) = info_table.get("length") {
             file_type = FileType::SingleFile {
-                length: *x as usize,
+                length: file_length(*x)?,
+                md5sum: md5sum(info_table),
             };
         } else if let Some(BencodeDictValues::Bencode(Bencode::List(files_list))) =
             info_table.get("files")
         {
             let files = files_list
-                .into_iter()
+                .iter()
                 .map(|bencode| {
                     // WARNING: PREPARE FOR SOME CODE ABOMINATION
                     // File list contains dictionary representing a File
                     match bencode {
                         Bencode::Dict(file_table) => {
                             let length = match file_table.get("length") {
-                                Some(BencodeDictValues::Bencode(Bencode::Number(x))) => *x as usize,
+                                Some(BencodeDictValues::Bencode(Bencode::Number(x))) => {
+                                    file_length(*x)?
+                                }
                                 _ => {
                                     return Err(TorrentError::InvalidTorrentFile(
                                         "File does not have valid file length.".to_string(),
@@ -289,7 +726,12 @@ impl Info {
                                     ))
                                 }
                             }?;
-                            Ok(File { length, path })
+                            validate_path(&path)?;
+                            Ok(File {
+                                length,
+                                path,
+                                md5sum: md5sum(file_table),
+                            })
                         }
                         _ => Err(TorrentError::InvalidTorrentFile(
                             "Invalid files list.".to_string(),
@@ -297,10 +739,18 @@ impl Info {
                     }
                 })
                 .collect::<Result<Vec<_>, TorrentError>>()?;
+            // Everything else goes by the total, which has to fit.
+            files
+                .iter()
+                .try_fold(0usize, |total, file| total.checked_add(file.length))
+                .ok_or_else(|| {
+                    TorrentError::InvalidTorrentFile("Files are too large in total.".to_string())
+                })?;
             file_type = FileType::MultiFile { files }
         } else {
             return Err(TorrentError::InvalidTorrentFile(
-                "Could not determine file type".to_string(),
+                "Info needs either a `length` (single file) or a `files` list (multiple files)."
+                    .to_string(),
             ));
         }
 
@@ -308,10 +758,81 @@ impl Info {
     }
 }
 
+// Aims for around 1500 pieces, in powers of two from 16 KiB (one block) to 16 MiB.
+pub fn default_piece_length(total_length: usize) -> usize {
+    (total_length / 1500)
+        .next_power_of_two()
+        .clamp(1 << 14, 1 << 24)
+}
+
+// Anything but a string is ignored, the key is optional and nothing depends on it.
+fn md5sum(table: &IndexMap<String, BencodeDictValues>) -> Option<String> {
+    match table.get("md5sum") {
+        Some(BencodeDictValues::Bencode(Bencode::String(md5sum))) => Some(md5sum.clone()),
+        _ => None,
+    }
+}
+
+// A file's path has to name something and must not climb out of the torrent's directory once
+// joined together.
+fn validate_path(path: &[String]) -> Result<(), TorrentError> {
+    if path.is_empty() {
+        return Err(TorrentError::InvalidTorrentFile(
+            "File has an empty path.".to_string(),
+        ));
+    }
+    let unsafe_component = path.iter().find(|component| {
+        matches!(component.as_str(), "" | "." | "..") || component.contains(['/', '\\', '\0'])
+    });
+    match unsafe_component {
+        Some(component) => Err(TorrentError::InvalidTorrentFile(format!(
+            "File path has an unsafe component `{}`.",
+            component.escape_debug()
+        ))),
+        None => Ok(()),
+    }
+}
+
+// Percent-encodes everything except the unreserved characters of RFC 3986.
+pub(crate) fn url_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len() * 3);
+    for &byte in bytes {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'.' | b'-' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+// File lengths can't be negative, and have to fit in memory offsets.
+fn file_length(length: i64) -> Result<usize, TorrentError> {
+    usize::try_from(length)
+        .map_err(|_| TorrentError::InvalidTorrentFile(format!("Invalid file length {length}.")))
+}
+
+// The value under `key` in the bencoded dictionary `encoded`, exactly as it appears there.
+fn raw_dict_value<'a>(encoded: &'a [u8], key: &str) -> Option<&'a [u8]> {
+    let mut rest = encoded.strip_prefix(b"d")?;
+    while !rest.starts_with(b"e") {
+        let (entry_key, value) = BencodeRef::from_bytes(rest).ok()?;
+        let (_, after) = BencodeRef::from_bytes(value).ok()?;
+        if entry_key.as_bytes()? == key.as_bytes() {
+            return Some(&value[..value.len() - after.len()]);
+        }
+        rest = after;
+    }
+    None
+}
+
 #[derive(Debug)]
 pub enum TorrentError {
     InvalidAnnounceUrl,
     InvalidTorrentFile(String),
+    // Bytes other than whitespace after the torrent, at `offset`.
+    TrailingData { offset: usize },
 }
 impl std::error::Error for TorrentError {}
 
@@ -319,9 +840,248 @@ impl std::fmt::Display for TorrentError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             TorrentError::InvalidTorrentFile(s) => {
-                write!(f, "Not a valid torrent file, missing {}", s)
+                write!(f, "Not a valid torrent file : {}", s)
+            }
+            TorrentError::TrailingData { offset } => {
+                write!(f, "Unexpected data after the torrent at byte {}", offset)
             }
             TorrentError::InvalidAnnounceUrl => todo!(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_reserved_bytes_are_url_encoded() {
+        assert_eq!(url_encode(b"aZ09.-_~"), "aZ09.-_~");
+        assert_eq!(
+            url_encode(&[0x00, b' ', b'%', 0xde, 0xad]),
+            "%00%20%25%DE%AD"
+        );
+        let info_hash = hex::decode("2732883b560d39220f4cf903723296151e5765b3").unwrap();
+        assert_eq!(
+            url_encode(&info_hash),
+            "%272%88%3BV%0D9%22%0FL%F9%03r2%96%15%1EWe%B3"
+        );
+    }
+
+    #[test]
+    fn pieces_have_offsets_and_lengths() {
+        let info = Info::for_data(&[1; 40], 16);
+        assert_eq!(info.pieces.len(), 3);
+        assert_eq!(info.total_length(), 40);
+        assert_eq!(info.piece_offset(2), 32);
+        assert_eq!(info.piece_len(0), 16);
+        // The last piece is cut short, there is nothing past it.
+        assert_eq!(info.piece_len(2), 8);
+        assert_eq!(info.piece_len(3), 0);
+    }
+
+    // The rest of a one byte info, after whichever of `length` and `files` is being tested.
+    fn info_with(file_keys: &[u8]) -> Vec<u8> {
+        let mut info = [
+            b"d",
+            file_keys,
+            b"4:name4:test12:piece lengthi16384e6:pieces20:",
+        ]
+        .concat();
+        info.extend_from_slice(&[7; 20]);
+        info.push(b'e');
+        info
+    }
+
+    fn invalid_torrent_message(info: &[u8]) -> String {
+        match Info::from_bytes(info)
+            .unwrap_err()
+            .downcast::<TorrentError>()
+        {
+            Ok(error) => match *error {
+                TorrentError::InvalidTorrentFile(message) => message,
+                other => panic!("unexpected error {other}"),
+            },
+            Err(error) => panic!("unexpected error {error}"),
+        }
+    }
+
+    #[test]
+    fn length_and_files_together_are_refused() {
+        let info = info_with(b"5:filesld6:lengthi1e4:pathl1:aeee6:lengthi1e");
+        assert_eq!(
+            invalid_torrent_message(&info),
+            "Info has both `length` and `files`, only one of them is allowed."
+        );
+    }
+
+    #[test]
+    fn neither_length_nor_files_is_refused() {
+        assert_eq!(
+            invalid_torrent_message(&info_with(b"")),
+            "Info needs either a `length` (single file) or a `files` list (multiple files)."
+        );
+    }
+
+    #[test]
+    fn clones_keep_the_pieces_and_the_hash() {
+        let info = Info::for_data(&[1; 40], 16);
+        let clone = info.clone();
+        assert_eq!(clone.pieces, info.pieces);
+        assert_eq!(clone.get_hash(), info.get_hash());
+        // Parsed ones carry their encoded form along, unknown keys and all.
+        let encoded = info_with(b"5:a-keyi1e6:lengthi1e");
+        let parsed = Info::from_bytes(&encoded).unwrap();
+        assert!(parsed.raw_bytes.is_some());
+        let clone = parsed.clone();
+        assert_eq!(clone.raw_bytes, parsed.raw_bytes);
+        assert_eq!(clone.pieces, parsed.pieces);
+        assert_eq!(
+            *clone.get_hash().as_bytes(),
+            Sha1::from(&encoded).digest().bytes()
+        );
+    }
+
+    #[test]
+    fn torrents_write_back_what_they_read() {
+        let mut encoded = b"d8:announce15:http://tracker/13:announce-listll15:http://tracker/el14:http://backup/ee7:comment2:hi4:info".to_vec();
+        encoded.extend_from_slice(&info_with(b"6:lengthi1e"));
+        encoded.extend_from_slice(b"8:url-listl12:http://seed/ee");
+        let torrent = Torrent::from_bytes(&encoded).unwrap();
+        assert_eq!(torrent.announce_list.len(), 2);
+        assert_eq!(torrent.to_bytes(), encoded);
+
+        let made = Info::for_data(b"some data", 16);
+        let mut torrent = torrent;
+        torrent.info = made.clone();
+        let reread = Torrent::from_bytes(&torrent.to_bytes()).unwrap();
+        assert_eq!(reread.info.get_hash(), made.get_hash());
+    }
+
+    #[test]
+    fn rewritten_torrents_keep_their_info_hash() {
+        let info = info_with(b"5:a-keyli1ed1:ai2eee6:lengthi1e");
+        let mut encoded = b"d8:announce15:http://tracker/4:info".to_vec();
+        encoded.extend_from_slice(&info);
+        encoded.extend_from_slice(b"e\ntrailing");
+        let torrent = Torrent::from_bytes_lenient(&encoded).unwrap();
+        let reread = Torrent::from_bytes(&torrent.to_bytes()).unwrap();
+        assert_eq!(reread.info.to_bytes(), info);
+        assert_eq!(reread.info.get_hash(), torrent.info.get_hash());
+    }
+
+    #[test]
+    fn source_changes_the_info_hash() {
+        let plain = Info::for_data(b"some data", 16);
+        let mut sourced = plain.clone();
+        sourced.set_source(Some("tracker".to_string()));
+        assert_ne!(plain.get_hash(), sourced.get_hash());
+        let reread = Info::from_bytes(&sourced.to_bytes()).unwrap();
+        assert_eq!(reread.source(), Some("tracker"));
+        assert_eq!(reread.get_hash(), sourced.get_hash());
+    }
+
+    #[test]
+    fn private_torrents_hash_to_a_known_value() {
+        let mut bytes = b"d6:lengthi5e4:name4:test12:piece lengthi16384e6:pieces20:".to_vec();
+        bytes.extend_from_slice(&[7; 20]);
+        bytes.extend_from_slice(b"7:privatei1ee");
+        let info = Info::from_bytes(&bytes).unwrap();
+        assert!(info.is_private());
+        assert_eq!(
+            info.get_hash().to_hex(),
+            "8adffa6f702020e258651fb53745d8ff6d02da36"
+        );
+    }
+
+    #[test]
+    fn setters_drop_the_bytes_that_were_read() {
+        let mut bytes = b"d6:lengthi5e4:name4:test12:piece lengthi16384e6:pieces20:".to_vec();
+        bytes.extend_from_slice(&[7; 20]);
+        bytes.extend_from_slice(b"7:privatei1ee");
+        let mut info = Info::from_bytes(&bytes).unwrap();
+        info.set_source(Some("tracker".to_string()));
+        assert_ne!(info.to_bytes(), bytes);
+        assert_eq!(
+            info.get_hash().to_hex(),
+            "1539f75ed31eb3faafb38bcfbb2a9d9b3108e868"
+        );
+        info.set_private(false);
+        assert!(!Info::from_bytes(&info.to_bytes()).unwrap().is_private());
+    }
+
+    #[test]
+    fn pieces_are_the_same_on_any_number_of_threads() {
+        let dir = std::env::temp_dir().join(format!("bit-torrust-hashing-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        // Pieces that cross file boundaries and a short one at the end.
+        let mut data = Vec::new();
+        for (file, len) in [("a", 70_000), ("b", 1), ("sub/c", 100_003)] {
+            let contents: Vec<u8> = (0..len).map(|i| (i * 7 % 251) as u8).collect();
+            std::fs::write(dir.join(file), &contents).unwrap();
+            data.extend(contents);
+        }
+        let expected = Info::for_data(&data, 16384).pieces;
+        for hash_threads in [1, 4, 16] {
+            let options = CreateOptions {
+                hash_threads,
+                ..CreateOptions::default()
+            };
+            let torrent = Torrent::create(&dir, Some(16384), None, &options).unwrap();
+            assert_eq!(torrent.info.pieces, expected, "{hash_threads} threads");
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn info_hash_covers_the_original_bytes() {
+        // A string length with a leading zero, which re-encoding would drop.
+        let info = info_with(b"06:lengthi5e");
+        let mut torrent = b"d4:info".to_vec();
+        torrent.extend_from_slice(&info);
+        torrent.push(b'e');
+        let torrent = Torrent::from_bytes(&torrent).unwrap();
+        assert_eq!(torrent.info.name, "test");
+        assert_eq!(
+            *torrent.info.get_hash().as_bytes(),
+            Sha1::from(&info).digest().bytes()
+        );
+    }
+
+    #[test]
+    fn bare_info_hash_covers_the_original_bytes() {
+        let info = info_with(b"06:lengthi5e");
+        let parsed = Info::from_bytes(&info).unwrap();
+        assert_eq!(
+            *parsed.get_hash().as_bytes(),
+            Sha1::from(&info).digest().bytes()
+        );
+    }
+
+    #[test]
+    fn negative_file_lengths_are_refused() {
+        let mut info = b"d6:lengthi-1e4:name4:test12:piece lengthi16384e6:pieces20:".to_vec();
+        info.extend_from_slice(&[7; 20]);
+        info.push(b'e');
+        assert!(Info::from_bytes(&info).is_err());
+    }
+
+    #[test]
+    fn files_too_large_in_total_are_refused() {
+        let file = format!("d6:lengthi{}e4:pathl1:aee", i64::MAX);
+        let mut info = format!("d5:filesl{file}{file}{file}e4:name4:test").into_bytes();
+        info.extend_from_slice(b"12:piece lengthi16384e6:pieces20:");
+        info.extend_from_slice(&[7; 20]);
+        info.push(b'e');
+        assert!(Info::from_bytes(&info).is_err());
+    }
+
+    #[test]
+    fn raw_dict_value_finds_the_exact_slice() {
+        let encoded = b"d1:ai1e4:infod1:xli1ei2eee1:zi3ee";
+        assert_eq!(raw_dict_value(encoded, "info"), Some(&b"d1:xli1ei2eee"[..]));
+        assert_eq!(raw_dict_value(encoded, "z"), Some(&b"i3e"[..]));
+        assert_eq!(raw_dict_value(encoded, "missing"), None);
+        assert_eq!(raw_dict_value(b"li1ee", "info"), None);
+    }
+}
@@ -1,89 +1,647 @@
 use crate::{
-    peer::{Peer, PEER_ID},
-    torrent::Torrent,
+    info_hash::InfoHash,
+    magnet::Magnet,
+    peer::{Peer, PeerError, PeerId},
+    random::random_u32,
+    torrent::{url_encode, Torrent},
 };
 use std::{
     error::Error,
+    sync::OnceLock,
     time::{Duration, Instant},
 };
 
 use bencode::{Bencode, BencodeDictValues};
+use log::debug;
+
+#[cfg(feature = "async")]
+mod asynchronous;
+mod udp;
+
+#[cfg(feature = "async")]
+pub use asynchronous::AsyncTrackerService;
+
+// Lower bound on the time between two announces, whatever the tracker's interval says.
+const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+// Peers asked for on every announce unless told otherwise.
+pub const DEFAULT_NUM_WANT: u32 = 50;
+// Requests made with our own HTTP client give up after this, a hung tracker would block forever.
+pub const DEFAULT_HTTP_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Debug)]
-pub struct TrackerService<'a> {
-    client: reqwest::blocking::Client,
+pub struct TrackerService {
+    // Only made for the first HTTP announce unless one was given, the async service never
+    // needs it.
+    client: OnceLock<reqwest::blocking::Client>,
     interval: Duration,
+    // The tracker's own floor on announces, kept apart from the interval it would like.
+    min_interval: Duration,
+    // Echoed back on every announce once a tracker has handed us one.
+    tracker_id: Option<Vec<u8>>,
     last_updated: Instant,
     port: u16,
-    torrent: &'a Torrent,
+    info_hash: InfoHash,
+    tiers: Vec<Vec<String>>,
+    peers: Vec<Peer>,
+    announced: bool,
+    warning_message: Option<String>,
+    // Seeders and leechers as of the last announce, if the tracker said.
+    complete: Option<u64>,
+    incomplete: Option<u64>,
+    strict: bool,
+    num_want: u32,
+    // How long to wait on a tracker's reply, the HTTP client's and BEP 15's own otherwise.
+    timeout: Option<Duration>,
+    // Sent with every UDP announce so the tracker can tell it's still us if our address changes,
+    // which only works if it stays the same for the session.
+    key: u32,
 }
 
-impl<'a> TrackerService<'a> {
-    pub fn new(port: u16, torrent: &'a Torrent) -> Self {
+impl TrackerService {
+    pub fn new(port: u16, torrent: &Torrent) -> Self {
+        // Per BEP 12, `announce` is only used when there is no `announce-list`.
+        let tiers = if torrent.announce_list.is_empty() {
+            torrent
+                .announce
+                .iter()
+                .map(|url| vec![url.clone()])
+                .collect()
+        } else {
+            torrent.announce_list.clone()
+        };
+        Self::with_trackers(port, torrent.info.get_hash(), tiers)
+    }
+
+    // Magnet links carry no tiers, every tracker is tried in the order given.
+    pub fn from_magnet(port: u16, magnet: &Magnet) -> Self {
+        let tiers = magnet
+            .trackers
+            .iter()
+            .map(|url| vec![url.clone()])
+            .collect();
+        Self::with_trackers(port, magnet.info_hash, tiers)
+    }
+
+    // A single tracker, for when all we have is its url and the info hash.
+    pub fn from_url(port: u16, tracker_url: &str, info_hash: InfoHash) -> Self {
+        Self::with_trackers(port, info_hash, vec![vec![tracker_url.to_string()]])
+    }
+
+    fn with_trackers(port: u16, info_hash: InfoHash, tiers: Vec<Vec<String>>) -> Self {
         Self {
-            client: reqwest::blocking::Client::new(),
+            client: OnceLock::new(),
             interval: Duration::default(),
+            min_interval: Duration::default(),
+            tracker_id: None,
             last_updated: Instant::now(),
             port,
-            torrent,
+            info_hash,
+            tiers,
+            peers: Vec::new(),
+            announced: false,
+            warning_message: None,
+            complete: None,
+            incomplete: None,
+            strict: false,
+            num_want: DEFAULT_NUM_WANT,
+            timeout: None,
+            key: random_u32(),
         }
     }
 
+    // By default malformed peer entries are skipped, strict mode rejects the whole response.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    // How many peers to ask for on announces, 0 only reports our stats.
+    pub fn set_num_want(&mut self, num_want: u32) {
+        self.num_want = num_want;
+    }
+
+    // HTTP(S) requests go through this client instead of our own, for proxies, a different user
+    // agent or trusting the self-signed certificate of a local tracker. Its timeout is used,
+    // unless `set_timeout` says otherwise.
+    pub fn set_client(&mut self, client: reqwest::blocking::Client) {
+        self.client = OnceLock::from(client);
+    }
+
+    // Applies to every tracker request from now on, over UDP to each try of one.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+    }
+
+    // The port we announce as listening on.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn last_updated(&self) -> Instant {
+        self.last_updated
+    }
+
+    // Announces again once the tracker's interval has elapsed since the last announce, returning
+    // only the peers we did not know about yet. The interval is clamped to at least
+    // `MIN_ANNOUNCE_INTERVAL`, so a tracker sending a tiny (or no) interval does not get hammered.
+    pub fn maybe_reannounce(
+        &mut self,
+        uploaded: u64,
+        downloaded: u64,
+        left: u64,
+    ) -> Result<Option<Vec<Peer>>, Box<dyn Error>> {
+        if !self.reannounce_due() {
+            return Ok(None);
+        }
+        // Newly discovered peers are appended to the ones we already know.
+        let known = self.peers.len();
+        self.get_peers(uploaded, downloaded, left)?;
+        Ok(Some(self.peers[known..].to_vec()))
+    }
+
+    // Both the regular interval and the tracker's `min interval` have to have passed, the latter
+    // is a hard limit some trackers ban for.
+    fn reannounce_due(&self) -> bool {
+        let elapsed = self.last_updated.elapsed();
+        elapsed >= self.min_interval && elapsed >= self.interval.max(MIN_ANNOUNCE_INTERVAL)
+    }
+
+    // Warning sent along with the last announce response, if any.
+    pub fn warning_message(&self) -> Option<&str> {
+        self.warning_message.as_deref()
+    }
+
+    pub fn peers(&self) -> &[Peer] {
+        &self.peers
+    }
+
+    // Seeders in the swarm according to the last announce.
+    pub fn complete(&self) -> Option<u64> {
+        self.complete
+    }
+
+    // Leechers in the swarm according to the last announce.
+    pub fn incomplete(&self) -> Option<u64> {
+        self.incomplete
+    }
+
+    // Announces with `started` the first time and as a regular update afterwards.
     pub fn get_peers(
         &mut self,
         uploaded: u64,
         downloaded: u64,
-        left:u64
+        left: u64,
     ) -> Result<Vec<Peer>, Box<dyn Error>> {
-        let query_params = [
-            ("peer_id", PEER_ID),
-            ("port", &self.port.to_string()),
-            ("uploaded", &uploaded.to_string()),
-            ("downloaded", &downloaded.to_string()),
-            ("left", &left.to_string()),
-            ("compact", "1"),
+        let event = if self.announced {
+            TrackerEvent::None
+        } else {
+            TrackerEvent::Started
+        };
+        self.announce(event, uploaded, downloaded, left)
+    }
+
+    // Tries every tracker tier by tier until one responds. As BEP 12 asks, the tracker that
+    // responded is moved to the front of its tier so it gets tried first next time.
+    pub fn announce(
+        &mut self,
+        event: TrackerEvent,
+        uploaded: u64,
+        downloaded: u64,
+        left: u64,
+    ) -> Result<Vec<Peer>, Box<dyn Error>> {
+        let mut last_error: Box<dyn Error> = Box::new(TrackerError::NoTrackers);
+        for tier in 0..self.tiers.len() {
+            for i in 0..self.tiers[tier].len() {
+                let tracker_url = self.tiers[tier][i].clone();
+                match self.announce_to(&tracker_url, event, uploaded, downloaded, left) {
+                    Ok(peers) => {
+                        let url = self.tiers[tier].remove(i);
+                        self.tiers[tier].insert(0, url);
+                        return Ok(peers);
+                    }
+                    Err(e) => {
+                        debug!("Announce to {tracker_url} failed : {e}");
+                        last_error = e;
+                    }
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    // Announces over UDP (BEP 15) for `udp://` trackers and over HTTP otherwise.
+    fn announce_to(
+        &mut self,
+        tracker_url: &str,
+        event: TrackerEvent,
+        uploaded: u64,
+        downloaded: u64,
+        left: u64,
+    ) -> Result<Vec<Peer>, Box<dyn Error>> {
+        debug!("Announcing {event:?} to {tracker_url}");
+        let response = if tracker_url.starts_with("udp://") {
+            udp::announce(self, tracker_url, event, uploaded, downloaded, left)?
+        } else {
+            self.http_announce(tracker_url, event, uploaded, downloaded, left)?
+        };
+        let peers = self.finish_announce(event, response);
+        debug!("{tracker_url} sent {} peers", peers.len());
+        Ok(peers)
+    }
+
+    // Keeps what a successful announce told us, returning the peers it sent.
+    fn finish_announce(&mut self, event: TrackerEvent, response: AnnounceResponse) -> Vec<Peer> {
+        self.announced = true;
+        // We are leaving the swarm, whatever peers the tracker sends back are of no use.
+        if event == TrackerEvent::Stopped {
+            return Vec::new();
+        }
+        if let Some(interval) = response.interval {
+            self.interval = interval;
+        }
+        if let Some(min_interval) = response.min_interval {
+            self.min_interval = min_interval;
+        }
+        // Trackers only send the id once, it is kept until one sends another.
+        if response.tracker_id.is_some() {
+            self.tracker_id = response.tracker_id;
+        }
+        self.complete = response.complete;
+        self.incomplete = response.incomplete;
+        self.last_updated = Instant::now();
+        self.merge_peers(&response.peers);
+        response.peers
+    }
+
+    fn http_announce(
+        &mut self,
+        tracker_url: &str,
+        event: TrackerEvent,
+        uploaded: u64,
+        downloaded: u64,
+        left: u64,
+    ) -> Result<AnnounceResponse, Box<dyn Error>> {
+        let url = self.announce_url(tracker_url, event, uploaded, downloaded, left)?;
+        let response = self.http_get(url).send()?.bytes()?;
+        if event == TrackerEvent::Stopped {
+            return Ok(AnnounceResponse::default());
+        }
+        Ok(self.parse_http_response(&response)?)
+    }
+
+    fn http_get(&self, url: reqwest::Url) -> reqwest::blocking::RequestBuilder {
+        let client = self.client.get_or_init(|| {
+            reqwest::blocking::Client::builder()
+                .timeout(DEFAULT_HTTP_TIMEOUT)
+                .build()
+                .expect("Default HTTP client should build")
+        });
+        let request = client.get(url);
+        match self.timeout {
+            Some(timeout) => request.timeout(timeout),
+            None => request,
+        }
+    }
+
+    fn announce_url(
+        &self,
+        tracker_url: &str,
+        event: TrackerEvent,
+        uploaded: u64,
+        downloaded: u64,
+        left: u64,
+    ) -> Result<reqwest::Url, TrackerError> {
+        let port = self.port.to_string();
+        let uploaded = uploaded.to_string();
+        let downloaded = downloaded.to_string();
+        let left = left.to_string();
+        let num_want = self.num_want.to_string();
+        let mut query_params: Vec<(&str, &[u8])> = vec![
+            ("info_hash", self.info_hash.as_bytes()),
+            ("peer_id", PeerId::local().as_bytes()),
+            ("port", port.as_bytes()),
+            ("uploaded", uploaded.as_bytes()),
+            ("downloaded", downloaded.as_bytes()),
+            ("left", left.as_bytes()),
+            ("compact", b"1"),
+            ("numwant", num_want.as_bytes()),
         ];
+        if let Some(event) = event.as_str() {
+            query_params.push(("event", event.as_bytes()));
+        }
+        if let Some(tracker_id) = &self.tracker_id {
+            query_params.push(("trackerid", tracker_id));
+        }
 
-        let tracker_url = self
-            .torrent
-            .announce
-            .as_ref()
-            .expect("Annouce should be present");
-        let encoded_info_hash = self.torrent.info.get_url_encoded_hash();
-
-        let request = self
-            .client
-            // NOTE: Just take the url encoded hash AS IS, don't do anything smart like
-            // treating valid characters as not needing to be escaped.
-            .get(format!("{}?info_hash={}", tracker_url, encoded_info_hash))
-            .query(&query_params);
-
-        let response = request.send()?.bytes()?;
-        let (bencoded_response, _) = Bencode::from_bytes(&response, |s| match s {
-            "peers" => Some(6),
-            _ => None,
-        })?;
+        // The info hash is raw bytes, which the client's query builder only takes as strings, so
+        // the query is encoded here. Any query already in the url (passkeys and such) is kept.
+        let mut url = reqwest::Url::parse(tracker_url)
+            .map_err(|_| TrackerError::InvalidTrackerUrl(tracker_url.to_string()))?;
+        let query = url
+            .query()
+            .filter(|query| !query.is_empty())
+            .into_iter()
+            .map(str::to_string)
+            .chain(
+                query_params
+                    .iter()
+                    .map(|(key, value)| format!("{}={}", key, url_encode(value))),
+            )
+            .collect::<Vec<String>>()
+            .join("&");
+        url.set_query(Some(&query));
+        Ok(url)
+    }
+
+    fn parse_http_response(&mut self, response: &[u8]) -> Result<AnnounceResponse, TrackerError> {
+        // No byte mode keys, `peers` may come either way and a byte mode key would misparse the
+        // dictionary form. Compact peers are split up here instead, the decoder would also reject
+        // the whole response over a truncated entry.
+        let (bencoded_response, _) = Bencode::from_bytes(response, |_| None)
+            .map_err(|e| TrackerError::InvalidBencode(e.to_string()))?;
         if let Bencode::Dict(table) = bencoded_response {
-            if let Some(BencodeDictValues::Bencode(Bencode::Number(n))) = table.get("interval") {
-                self.interval = Duration::from_secs(*n as u64);
+            // The tracker rejected us, nothing else in the response is meaningful.
+            if let Some(BencodeDictValues::Bencode(Bencode::String(reason))) =
+                table.get("failure reason")
+            {
+                return Err(TrackerError::AnnounceFailed(reason.clone()));
             }
-            match table.get("peers") {
-                Some(BencodeDictValues::Bytes(peer_table)) => {
-                    return Ok(peer_table
-                        .iter()
-                        .map(|peer| TryInto::<Peer>::try_into(peer.as_slice()).unwrap())
-                        .collect())
+            self.warning_message = match table.get("warning message") {
+                Some(BencodeDictValues::Bencode(Bencode::String(warning))) => Some(warning.clone()),
+                _ => None,
+            };
+            // Negative intervals are ignored like missing ones, we keep the default.
+            let seconds = |key| match table.get(key) {
+                Some(BencodeDictValues::Bencode(Bencode::Number(n))) => {
+                    u64::try_from(*n).ok().map(Duration::from_secs)
                 }
-                _ => return Err(Box::new(TrackerError::MalformedTrackerResponse)),
+                _ => None,
+            };
+            let interval = seconds("interval");
+            let min_interval = seconds("min interval");
+            let count = |key| match table.get(key) {
+                Some(BencodeDictValues::Bencode(Bencode::Number(n))) => u64::try_from(*n).ok(),
+                _ => None,
+            };
+            let tracker_id = match table.get("tracker id") {
+                Some(BencodeDictValues::Bencode(value)) => byte_string(value).map(<[u8]>::to_vec),
+                _ => None,
+            };
+            // Trackers are free to pick compact or not on every response, whatever we asked for,
+            // so the format is worked out from the value each time.
+            let mut peers = match table.get("peers") {
+                Some(BencodeDictValues::Bencode(value)) => match PeerListFormat::of(value) {
+                    Some(format) => format.parse(value, 6, self.strict)?,
+                    None => return Err(TrackerError::MalformedTrackerResponse),
+                },
+                // An IPv6 only tracker may leave `peers` out entirely.
+                None if table.contains_key("peers6") => Vec::new(),
+                _ => return Err(TrackerError::MalformedTrackerResponse),
+            };
+            // IPv6 peers (BEP 7), always compact.
+            match table.get("peers6") {
+                Some(BencodeDictValues::Bencode(value))
+                    if PeerListFormat::of(value) == Some(PeerListFormat::Compact) =>
+                {
+                    peers.extend(PeerListFormat::Compact.parse(value, 18, self.strict)?)
+                }
+                None => {}
+                _ => return Err(TrackerError::MalformedTrackerResponse),
+            }
+            return Ok(AnnounceResponse {
+                interval,
+                min_interval,
+                tracker_id,
+                complete: count("complete"),
+                incomplete: count("incomplete"),
+                peers,
+            });
+        }
+        Err(TrackerError::MalformedTrackerResponse)
+    }
+}
+
+impl TrackerService {
+    // Swarm stats for our torrent without announcing, from the first tracker that answers.
+    pub fn scrape(&self) -> Result<ScrapeStats, Box<dyn Error>> {
+        let mut last_error: Box<dyn Error> = Box::new(TrackerError::NoTrackers);
+        for tracker_url in self.tiers.iter().flatten() {
+            let stats = if tracker_url.starts_with("udp://") {
+                udp::scrape(self, tracker_url)
+            } else {
+                self.http_scrape(tracker_url)
+            };
+            match stats {
+                Ok(stats) => return Ok(stats),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    fn http_scrape(&self, tracker_url: &str) -> Result<ScrapeStats, Box<dyn Error>> {
+        let mut url = scrape_url(tracker_url)?;
+        let query = url
+            .query()
+            .filter(|query| !query.is_empty())
+            .into_iter()
+            .map(str::to_string)
+            .chain([format!("info_hash={}", self.info_hash.to_url_encoded())])
+            .collect::<Vec<String>>()
+            .join("&");
+        url.set_query(Some(&query));
+        let response = self.http_get(url).send()?.bytes()?;
+        Ok(self.parse_scrape_response(&response)?)
+    }
+
+    fn parse_scrape_response(&self, response: &[u8]) -> Result<ScrapeStats, TrackerError> {
+        // `files` is keyed by the raw info hashes, which are rarely valid UTF-8 and so can't be
+        // decoded as keys. Our entry is found by its encoded key and only its value is decoded.
+        let key = [b"20:".as_slice(), self.info_hash.as_bytes()].concat();
+        let Some(start) = response.windows(key.len()).position(|window| window == key) else {
+            // Either the tracker refused or it doesn't know the torrent, which is an empty swarm.
+            let (bencoded_response, _) = Bencode::from_bytes(response, |_| None)
+                .map_err(|e| TrackerError::InvalidBencode(e.to_string()))?;
+            let Bencode::Dict(table) = bencoded_response else {
+                return Err(TrackerError::MalformedTrackerResponse);
+            };
+            if let Some(BencodeDictValues::Bencode(Bencode::String(reason))) =
+                table.get("failure reason")
+            {
+                return Err(TrackerError::AnnounceFailed(reason.clone()));
+            }
+            return Ok(ScrapeStats::default());
+        };
+        let (stats, _) = Bencode::from_bytes(&response[start + key.len()..], |_| None)
+            .map_err(|e| TrackerError::InvalidBencode(e.to_string()))?;
+        let Bencode::Dict(table) = stats else {
+            return Err(TrackerError::MalformedTrackerResponse);
+        };
+        let count = |key: &str| match table.get(key) {
+            Some(BencodeDictValues::Bencode(Bencode::Number(n))) if *n >= 0 => Ok(*n as u64),
+            _ => Err(TrackerError::MalformedTrackerResponse),
+        };
+        Ok(ScrapeStats {
+            complete: count("complete")?,
+            incomplete: count("incomplete")?,
+            downloaded: count("downloaded")?,
+        })
+    }
+}
+
+// By convention the scrape url is the announce url with the `announce` at the start of its last
+// path segment replaced by `scrape`, trackers whose url doesn't look like that can't be scraped.
+fn scrape_url(tracker_url: &str) -> Result<reqwest::Url, TrackerError> {
+    let mut url = reqwest::Url::parse(tracker_url)
+        .map_err(|_| TrackerError::InvalidTrackerUrl(tracker_url.to_string()))?;
+    let path = url.path();
+    let (directory, last) = path.rsplit_once('/').unwrap_or(("", path));
+    let Some(rest) = last.strip_prefix("announce") else {
+        return Err(TrackerError::ScrapeUnsupported(tracker_url.to_string()));
+    };
+    let path = format!("{directory}/scrape{rest}");
+    url.set_path(&path);
+    Ok(url)
+}
+
+// How many peers a tracker knows about for a torrent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ScrapeStats {
+    // Seeders.
+    pub complete: u64,
+    // Leechers.
+    pub incomplete: u64,
+    // Peers that finished downloading, ever.
+    pub downloaded: u64,
+}
+
+fn byte_string(value: &Bencode) -> Option<&[u8]> {
+    match value {
+        Bencode::String(s) => Some(s.as_bytes()),
+        Bencode::ByteString(bytes) => Some(bytes),
+        _ => None,
+    }
+}
+
+// The two ways a tracker can send its peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PeerListFormat {
+    // BEP 23, one string of packed addresses.
+    Compact,
+    // The original form, a list of dictionaries.
+    Dictionary,
+}
+
+impl PeerListFormat {
+    fn of(value: &Bencode) -> Option<Self> {
+        match value {
+            Bencode::List(_) => Some(Self::Dictionary),
+            value => byte_string(value).map(|_| Self::Compact),
+        }
+    }
+
+    // `entry_len` is only used for compact peers, 6 for IPv4 and 18 for IPv6.
+    fn parse(
+        self,
+        value: &Bencode,
+        entry_len: usize,
+        strict: bool,
+    ) -> Result<Vec<Peer>, TrackerError> {
+        match (self, value) {
+            (Self::Dictionary, Bencode::List(peer_list)) => {
+                collect_peers(peer_list.iter().map(Peer::try_from), strict)
+            }
+            (Self::Compact, value) => match byte_string(value) {
+                Some(bytes) => compact_peers(bytes, entry_len, strict),
+                None => Err(TrackerError::MalformedTrackerResponse),
+            },
+            _ => Err(TrackerError::MalformedTrackerResponse),
+        }
+    }
+}
+
+// Compact peers are `entry_len` bytes each, a short entry at the end counts as malformed.
+fn compact_peers(bytes: &[u8], entry_len: usize, strict: bool) -> Result<Vec<Peer>, TrackerError> {
+    collect_peers(bytes.chunks(entry_len).map(Peer::try_from), strict)
+}
+
+// Malformed entries are skipped, unless `strict` where the first one fails the announce.
+fn collect_peers(
+    peers: impl Iterator<Item = Result<Peer, PeerError>>,
+    strict: bool,
+) -> Result<Vec<Peer>, TrackerError> {
+    let mut collected = Vec::new();
+    for peer in peers {
+        match peer {
+            Ok(peer) => collected.push(peer),
+            Err(e) if strict => return Err(TrackerError::InvalidPeer(e)),
+            Err(_) => {}
+        }
+    }
+    Ok(collected)
+}
+
+// What an announce told us, regardless of the tracker protocol.
+#[derive(Default)]
+struct AnnounceResponse {
+    interval: Option<Duration>,
+    // Only HTTP trackers send these two.
+    min_interval: Option<Duration>,
+    tracker_id: Option<Vec<u8>>,
+    // Seeders and leechers, optional for HTTP trackers.
+    complete: Option<u64>,
+    incomplete: Option<u64>,
+    peers: Vec<Peer>,
+}
+
+impl TrackerService {
+    fn merge_peers(&mut self, peers: &[Peer]) {
+        for peer in peers {
+            if !self.peers.contains(peer) {
+                self.peers.push(peer.clone());
             }
         }
-        Err(Box::new(TrackerError::MalformedTrackerResponse))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackerEvent {
+    Started,
+    Stopped,
+    Completed,
+    // Regular announce at the tracker's interval.
+    None,
+}
+
+impl TrackerEvent {
+    fn as_str(&self) -> Option<&'static str> {
+        match self {
+            TrackerEvent::Started => Some("started"),
+            TrackerEvent::Stopped => Some("stopped"),
+            TrackerEvent::Completed => Some("completed"),
+            TrackerEvent::None => None,
+        }
+    }
+
+    fn udp_id(&self) -> u32 {
+        match self {
+            TrackerEvent::None => 0,
+            TrackerEvent::Completed => 1,
+            TrackerEvent::Started => 2,
+            TrackerEvent::Stopped => 3,
+        }
     }
 }
 
 #[derive(Debug)]
 pub enum TrackerError {
     MalformedTrackerResponse,
+    AnnounceFailed(String),
+    InvalidTrackerUrl(String),
+    TrackerTimeout,
+    NoTrackers,
+    InvalidPeer(PeerError),
+    InvalidBencode(String),
+    ScrapeUnsupported(String),
 }
 
 impl std::error::Error for TrackerError {}
@@ -91,6 +649,134 @@ impl std::fmt::Display for TrackerError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             TrackerError::MalformedTrackerResponse => write!(f, "Malformed Tracker Response!"),
+            TrackerError::AnnounceFailed(reason) => {
+                write!(f, "Tracker refused announce : {}", reason)
+            }
+            TrackerError::InvalidTrackerUrl(url) => write!(f, "Invalid tracker url : {}", url),
+            TrackerError::TrackerTimeout => write!(f, "Tracker did not respond."),
+            TrackerError::NoTrackers => write!(f, "Torrent does not have any trackers."),
+            TrackerError::InvalidPeer(e) => write!(f, "Tracker sent an invalid peer : {}", e),
+            TrackerError::InvalidBencode(e) => write!(f, "Tracker response is not bencode : {}", e),
+            TrackerError::ScrapeUnsupported(url) => {
+                write!(f, "Tracker does not support scraping : {}", url)
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    // Answers the first announce made to the returned url with `body`.
+    fn tracker(body: &[u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let mut response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        )
+        .into_bytes();
+        response.extend_from_slice(body);
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.read(&mut [0; 4096]);
+            stream.write_all(&response).unwrap();
+        });
+        url
+    }
+
+    // A torrent of a single byte announcing to `url`.
+    fn torrent(url: &str) -> Torrent {
+        let mut encoded = format!("d8:announce{}:{url}4:info", url.len()).into_bytes();
+        encoded.extend_from_slice(b"d6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:");
+        encoded.extend_from_slice(&[0; 20]);
+        encoded.extend_from_slice(b"ee");
+        Torrent::from_bytes(&encoded).unwrap()
+    }
+
+    fn announce(body: &[u8]) -> Result<Vec<Peer>, Box<dyn Error>> {
+        announce_strictly(body, false)
+    }
+
+    fn announce_strictly(body: &[u8], strict: bool) -> Result<Vec<Peer>, Box<dyn Error>> {
+        let torrent = torrent(&tracker(body));
+        let mut service = TrackerService::new(6881, &torrent);
+        service.set_strict(strict);
+        service.get_peers(0, 0, 1)
+    }
+
+    fn service() -> TrackerService {
+        TrackerService::from_url(6881, "http://tracker/announce", InfoHash::from([0; 20]))
+    }
+
+    #[test]
+    fn dictionary_peers_are_read() {
+        let peers = announce(b"d8:intervali60e5:peersld2:ip9:127.0.0.24:porti80eeee").unwrap();
+        assert_eq!(peers, [Peer::new([127, 0, 0, 2].into(), 80)]);
+    }
+
+    #[test]
+    fn failure_reasons_are_errors() {
+        let error = announce(b"d14:failure reason7:go awaye").unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<TrackerError>(),
+            Some(TrackerError::AnnounceFailed(reason)) if reason == "go away"
+        ));
+    }
+
+    #[test]
+    fn ipv6_peers_are_read() {
+        let mut response = b"d8:intervali60e5:peers6:".to_vec();
+        response.extend_from_slice(&[127, 0, 0, 1, 0, 80]);
+        response.extend_from_slice(b"6:peers618:");
+        response.extend_from_slice(&[0; 15]);
+        response.extend_from_slice(&[1, 0, 81]);
+        response.push(b'e');
+        assert_eq!(
+            announce(&response).unwrap(),
+            [
+                Peer::new([127, 0, 0, 1].into(), 80),
+                Peer::new(std::net::Ipv6Addr::LOCALHOST.into(), 81),
+            ]
+        );
+    }
+
+    // One whole entry and a cut off one.
+    const TRUNCATED_PEERS: &[u8] =
+        b"d8:intervali900e5:peers9:\x7f\x00\x00\x01\x1a\xe1\x0a\x00\x00e";
+
+    #[test]
+    fn truncated_compact_peers_are_skipped() {
+        assert_eq!(
+            announce(TRUNCATED_PEERS).unwrap(),
+            [Peer::new([127, 0, 0, 1].into(), 6881)]
+        );
+    }
+
+    #[test]
+    fn strict_mode_fails_on_truncated_peers() {
+        let error = announce_strictly(TRUNCATED_PEERS, true).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<TrackerError>(),
+            Some(TrackerError::InvalidPeer(PeerError::UnknownBytesListFormat))
+        ));
+    }
+
+    #[test]
+    fn negative_intervals_keep_the_default() {
+        let mut service = service();
+        let default = service.interval;
+        let response = b"d8:intervali-1e12:min intervali-30e5:peers0:e";
+        let parsed = service.parse_http_response(response).unwrap();
+        assert_eq!(parsed.interval, None);
+        assert_eq!(parsed.min_interval, None);
+        service.finish_announce(TrackerEvent::Started, parsed);
+        assert_eq!(service.interval, default);
+    }
+}
@@ -0,0 +1,130 @@
+// TrackerService on tokio. Announces are built and responses parsed by the wrapped service, which
+// also keeps the tracker state, only the requests themselves are async.
+
+use std::error::Error;
+
+use super::{
+    udp, AnnounceResponse, TrackerError, TrackerEvent, TrackerService, DEFAULT_HTTP_TIMEOUT,
+};
+use crate::peer::Peer;
+
+pub struct AsyncTrackerService {
+    service: TrackerService,
+    client: reqwest::Client,
+}
+
+impl AsyncTrackerService {
+    // Any TrackerService works, whichever constructor made it.
+    pub fn new(service: TrackerService) -> Self {
+        Self {
+            service,
+            client: reqwest::Client::builder()
+                .timeout(DEFAULT_HTTP_TIMEOUT)
+                .build()
+                .expect("Default HTTP client should build"),
+        }
+    }
+
+    // Same as TrackerService::set_client.
+    pub fn set_client(&mut self, client: reqwest::Client) {
+        self.client = client;
+    }
+
+    pub fn service(&self) -> &TrackerService {
+        &self.service
+    }
+
+    pub fn service_mut(&mut self) -> &mut TrackerService {
+        &mut self.service
+    }
+
+    // Same as TrackerService::maybe_reannounce.
+    pub async fn maybe_reannounce(
+        &mut self,
+        uploaded: u64,
+        downloaded: u64,
+        left: u64,
+    ) -> Result<Option<Vec<Peer>>, Box<dyn Error + Send + Sync>> {
+        if !self.service.reannounce_due() {
+            return Ok(None);
+        }
+        let known = self.service.peers.len();
+        self.get_peers(uploaded, downloaded, left).await?;
+        Ok(Some(self.service.peers[known..].to_vec()))
+    }
+
+    pub async fn get_peers(
+        &mut self,
+        uploaded: u64,
+        downloaded: u64,
+        left: u64,
+    ) -> Result<Vec<Peer>, Box<dyn Error + Send + Sync>> {
+        let event = if self.service.announced {
+            TrackerEvent::None
+        } else {
+            TrackerEvent::Started
+        };
+        self.announce(event, uploaded, downloaded, left).await
+    }
+
+    // Same tier order as TrackerService::announce.
+    pub async fn announce(
+        &mut self,
+        event: TrackerEvent,
+        uploaded: u64,
+        downloaded: u64,
+        left: u64,
+    ) -> Result<Vec<Peer>, Box<dyn Error + Send + Sync>> {
+        let mut last_error: Box<dyn Error + Send + Sync> = Box::new(TrackerError::NoTrackers);
+        for tier in 0..self.service.tiers.len() {
+            for i in 0..self.service.tiers[tier].len() {
+                let tracker_url = self.service.tiers[tier][i].clone();
+                let response = if tracker_url.starts_with("udp://") {
+                    udp::announce_async(
+                        &self.service,
+                        &tracker_url,
+                        event,
+                        uploaded,
+                        downloaded,
+                        left,
+                    )
+                    .await
+                } else {
+                    self.http_announce(&tracker_url, event, uploaded, downloaded, left)
+                        .await
+                };
+                match response {
+                    Ok(response) => {
+                        let url = self.service.tiers[tier].remove(i);
+                        self.service.tiers[tier].insert(0, url);
+                        return Ok(self.service.finish_announce(event, response));
+                    }
+                    Err(e) => last_error = e,
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    async fn http_announce(
+        &mut self,
+        tracker_url: &str,
+        event: TrackerEvent,
+        uploaded: u64,
+        downloaded: u64,
+        left: u64,
+    ) -> Result<AnnounceResponse, Box<dyn Error + Send + Sync>> {
+        let url = self
+            .service
+            .announce_url(tracker_url, event, uploaded, downloaded, left)?;
+        let mut request = self.client.get(url);
+        if let Some(timeout) = self.service.timeout {
+            request = request.timeout(timeout);
+        }
+        let response = request.send().await?.bytes().await?;
+        if event == TrackerEvent::Stopped {
+            return Ok(AnnounceResponse::default());
+        }
+        Ok(self.service.parse_http_response(&response)?)
+    }
+}
@@ -0,0 +1,293 @@
+// UDP tracker protocol (BEP 15).
+// Every request starts by trading a magic constant for a connection id, which is then used to
+// announce. All integers are big endian.
+
+use std::{
+    error::Error,
+    net::UdpSocket,
+    time::{Duration, Instant},
+};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use super::{
+    compact_peers, AnnounceResponse, ScrapeStats, TrackerError, TrackerEvent, TrackerService,
+};
+use crate::{peer::PeerId, random::random_u32};
+
+const PROTOCOL_ID: u64 = 0x41727101980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+// BEP 15 asks for 15 * 2 ^ n seconds, we give up a lot sooner than the spec's 8 tries.
+const TIMEOUT: Duration = Duration::from_secs(15);
+const MAX_TRIES: u32 = 3;
+
+pub(super) fn announce(
+    service: &TrackerService,
+    tracker_url: &str,
+    event: TrackerEvent,
+    uploaded: u64,
+    downloaded: u64,
+    left: u64,
+) -> Result<AnnounceResponse, Box<dyn Error>> {
+    let (host, tracker_port) = tracker_addr(tracker_url)?;
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect((host.as_str(), tracker_port))?;
+
+    let timeout = service.timeout.unwrap_or(TIMEOUT);
+    let response = transact(&socket, &mut connect_request(), ACTION_CONNECT, timeout)?;
+    let connection_id = parse_connect(&response)?;
+    let mut request = announce_request(service, connection_id, event, uploaded, downloaded, left);
+    let response = transact(&socket, &mut request, ACTION_ANNOUNCE, timeout)?;
+    Ok(parse_announce(service, &response)?)
+}
+
+pub(super) fn scrape(
+    service: &TrackerService,
+    tracker_url: &str,
+) -> Result<ScrapeStats, Box<dyn Error>> {
+    let (host, tracker_port) = tracker_addr(tracker_url)?;
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect((host.as_str(), tracker_port))?;
+
+    let timeout = service.timeout.unwrap_or(TIMEOUT);
+    let response = transact(&socket, &mut connect_request(), ACTION_CONNECT, timeout)?;
+    let connection_id = parse_connect(&response)?;
+    let mut scrape_buf = [0u8; 36];
+    BigEndian::write_u64(&mut scrape_buf[..8], connection_id);
+    BigEndian::write_u32(&mut scrape_buf[8..12], ACTION_SCRAPE);
+    scrape_buf[16..36].clone_from_slice(service.info_hash.as_bytes());
+    let response = transact(&socket, &mut scrape_buf, ACTION_SCRAPE, timeout)?;
+    if response.len() < 20 {
+        return Err(Box::new(TrackerError::MalformedTrackerResponse));
+    }
+    // Seeders, completed and leechers for the one hash we asked about.
+    Ok(ScrapeStats {
+        complete: BigEndian::read_u32(&response[8..12]) as u64,
+        downloaded: BigEndian::read_u32(&response[12..16]) as u64,
+        incomplete: BigEndian::read_u32(&response[16..20]) as u64,
+    })
+}
+
+// Sends the request with a fresh transaction id and waits for the matching response, resending
+// when none comes within `timeout`. Datagrams for other transactions are skipped, they don't
+// restart the wait.
+fn transact(
+    socket: &UdpSocket,
+    request: &mut [u8],
+    action: u32,
+    timeout: Duration,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let transaction_id = random_u32();
+    BigEndian::write_u32(&mut request[12..16], transaction_id);
+    let mut response = vec![0u8; 2048];
+    for _ in 0..MAX_TRIES {
+        socket.send(request)?;
+        let deadline = Instant::now() + timeout;
+        while let Some(left) = time_left(deadline) {
+            socket.set_read_timeout(Some(left))?;
+            let len = match socket.recv(&mut response) {
+                Ok(len) => len,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    break
+                }
+                Err(e) => return Err(Box::new(e)),
+            };
+            if is_for(&response[..len], transaction_id) {
+                return Ok(check_response(&response[..len], action)?);
+            }
+        }
+    }
+    Err(Box::new(TrackerError::TrackerTimeout))
+}
+
+// None once `deadline` has passed.
+fn time_left(deadline: Instant) -> Option<Duration> {
+    deadline
+        .checked_duration_since(Instant::now())
+        .filter(|left| !left.is_zero())
+}
+
+#[cfg(feature = "async")]
+pub(super) async fn announce_async(
+    service: &TrackerService,
+    tracker_url: &str,
+    event: TrackerEvent,
+    uploaded: u64,
+    downloaded: u64,
+    left: u64,
+) -> Result<AnnounceResponse, Box<dyn Error + Send + Sync>> {
+    let (host, tracker_port) = tracker_addr(tracker_url)?;
+    let socket = tokio::net::UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect((host.as_str(), tracker_port)).await?;
+
+    let timeout = service.timeout.unwrap_or(TIMEOUT);
+    let response = transact_async(&socket, &mut connect_request(), ACTION_CONNECT, timeout).await?;
+    let connection_id = parse_connect(&response)?;
+    let mut request = announce_request(service, connection_id, event, uploaded, downloaded, left);
+    let response = transact_async(&socket, &mut request, ACTION_ANNOUNCE, timeout).await?;
+    Ok(parse_announce(service, &response)?)
+}
+
+#[cfg(feature = "async")]
+async fn transact_async(
+    socket: &tokio::net::UdpSocket,
+    request: &mut [u8],
+    action: u32,
+    timeout: Duration,
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let transaction_id = random_u32();
+    BigEndian::write_u32(&mut request[12..16], transaction_id);
+    let mut response = vec![0u8; 2048];
+    for _ in 0..MAX_TRIES {
+        socket.send(request).await?;
+        let deadline = Instant::now() + timeout;
+        while let Some(left) = time_left(deadline) {
+            let Ok(len) = tokio::time::timeout(left, socket.recv(&mut response)).await else {
+                break;
+            };
+            let len = len?;
+            if is_for(&response[..len], transaction_id) {
+                return Ok(check_response(&response[..len], action)?);
+            }
+        }
+    }
+    Err(Box::new(TrackerError::TrackerTimeout))
+}
+
+fn tracker_addr(tracker_url: &str) -> Result<(String, u16), TrackerError> {
+    let url = reqwest::Url::parse(tracker_url)
+        .map_err(|_| TrackerError::InvalidTrackerUrl(tracker_url.to_string()))?;
+    match (url.host_str(), url.port()) {
+        (Some(host), Some(tracker_port)) => Ok((host.to_string(), tracker_port)),
+        _ => Err(TrackerError::InvalidTrackerUrl(tracker_url.to_string())),
+    }
+}
+
+// The transaction id is filled in by transact.
+fn connect_request() -> [u8; 16] {
+    let mut connect_buf = [0u8; 16];
+    BigEndian::write_u64(&mut connect_buf[..8], PROTOCOL_ID);
+    BigEndian::write_u32(&mut connect_buf[8..12], ACTION_CONNECT);
+    connect_buf
+}
+
+fn parse_connect(response: &[u8]) -> Result<u64, TrackerError> {
+    if response.len() < 16 {
+        return Err(TrackerError::MalformedTrackerResponse);
+    }
+    Ok(BigEndian::read_u64(&response[8..16]))
+}
+
+fn announce_request(
+    service: &TrackerService,
+    connection_id: u64,
+    event: TrackerEvent,
+    uploaded: u64,
+    downloaded: u64,
+    left: u64,
+) -> [u8; 98] {
+    let mut announce_buf = [0u8; 98];
+    BigEndian::write_u64(&mut announce_buf[..8], connection_id);
+    BigEndian::write_u32(&mut announce_buf[8..12], ACTION_ANNOUNCE);
+    announce_buf[16..36].clone_from_slice(service.info_hash.as_bytes());
+    announce_buf[36..56].clone_from_slice(PeerId::local().as_bytes());
+    BigEndian::write_u64(&mut announce_buf[56..64], downloaded);
+    BigEndian::write_u64(&mut announce_buf[64..72], left);
+    BigEndian::write_u64(&mut announce_buf[72..80], uploaded);
+    BigEndian::write_u32(&mut announce_buf[80..84], event.udp_id());
+    // IP address is left as 0 so the tracker uses the sender's address, followed by our key.
+    BigEndian::write_u32(&mut announce_buf[88..92], service.key);
+    BigEndian::write_u32(&mut announce_buf[92..96], service.num_want);
+    BigEndian::write_u16(&mut announce_buf[96..98], service.port);
+    announce_buf
+}
+
+fn parse_announce(
+    service: &TrackerService,
+    response: &[u8],
+) -> Result<AnnounceResponse, TrackerError> {
+    if response.len() < 20 {
+        return Err(TrackerError::MalformedTrackerResponse);
+    }
+    let interval = Duration::from_secs(BigEndian::read_u32(&response[8..12]) as u64);
+    let leechers = BigEndian::read_u32(&response[12..16]) as u64;
+    let seeders = BigEndian::read_u32(&response[16..20]) as u64;
+    let peers = compact_peers(&response[20..], 6, service.strict)?;
+    Ok(AnnounceResponse {
+        interval: Some(interval),
+        complete: Some(seeders),
+        incomplete: Some(leechers),
+        peers,
+        ..Default::default()
+    })
+}
+
+// Whether the datagram answers the transaction, anything too short to say doesn't.
+fn is_for(response: &[u8], transaction_id: u32) -> bool {
+    response.len() >= 8 && BigEndian::read_u32(&response[4..8]) == transaction_id
+}
+
+// The response for our transaction, see is_for, or the error the tracker sent instead.
+fn check_response(response: &[u8], action: u32) -> Result<Vec<u8>, TrackerError> {
+    match BigEndian::read_u32(&response[..4]) {
+        x if x == action => Ok(response.to_vec()),
+        ACTION_ERROR => Err(TrackerError::AnnounceFailed(
+            String::from_utf8_lossy(&response[8..]).to_string(),
+        )),
+        _ => Err(TrackerError::MalformedTrackerResponse),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::info_hash::InfoHash;
+
+    #[test]
+    fn the_key_stays_the_same_across_announces() {
+        let service = TrackerService::from_url(6881, "udp://tracker:80", InfoHash::from([0; 20]));
+        let first = announce_request(&service, 1, TrackerEvent::Started, 0, 0, 10);
+        let second = announce_request(&service, 2, TrackerEvent::None, 0, 5, 5);
+        assert_eq!(first[88..92], second[88..92]);
+    }
+
+    #[test]
+    fn datagrams_for_other_transactions_are_skipped() {
+        let tracker = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.connect(tracker.local_addr().unwrap()).unwrap();
+        let remote = thread::spawn(move || {
+            let mut request = [0u8; 16];
+            let (_, from) = tracker.recv_from(&mut request).unwrap();
+            let transaction_id = BigEndian::read_u32(&request[12..16]);
+            let mut response = [0u8; 16];
+            BigEndian::write_u32(&mut response[..4], ACTION_CONNECT);
+            BigEndian::write_u64(&mut response[8..16], 42);
+            // Someone else's answer and a runt, then ours.
+            BigEndian::write_u32(&mut response[4..8], transaction_id.wrapping_add(1));
+            tracker.send_to(&response, from).unwrap();
+            tracker.send_to(&[0; 3], from).unwrap();
+            BigEndian::write_u32(&mut response[4..8], transaction_id);
+            tracker.send_to(&response, from).unwrap();
+        });
+        let response = transact(
+            &socket,
+            &mut connect_request(),
+            ACTION_CONNECT,
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        assert_eq!(parse_connect(&response).unwrap(), 42);
+        remote.join().unwrap();
+    }
+}